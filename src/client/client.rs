@@ -1,12 +1,26 @@
-use std::{collections::HashMap, io::Read, net::TcpStream, str::FromStr};
+use std::collections::HashMap;
+use std::sync::LazyLock;
+use std::{fmt, str::FromStr};
 
 use thiserror::Error;
 
+use crate::extensions::Extensions;
+use crate::header::HeaderMap;
+use crate::server::config::DEFAULT_MAX_BODY_SIZE;
+use crate::server::request_id::Id;
+use crate::server::router::PathParams;
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Method {
     GET,
+    HEAD,
     POST,
+    PUT,
+    PATCH,
     DELETE,
+    OPTIONS,
+    CONNECT,
+    TRACE,
 }
 
 #[derive(Error, Debug)]
@@ -21,48 +35,64 @@ impl FromStr for Method {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "GET" => Ok(Method::GET),
+            "HEAD" => Ok(Method::HEAD),
             "POST" => Ok(Method::POST),
+            "PUT" => Ok(Method::PUT),
+            "PATCH" => Ok(Method::PATCH),
             "DELETE" => Ok(Method::DELETE),
+            "OPTIONS" => Ok(Method::OPTIONS),
+            "CONNECT" => Ok(Method::CONNECT),
+            "TRACE" => Ok(Method::TRACE),
             other => Err(MethodParseError::Unknown(other.to_string())),
         }
     }
 }
 
-impl ToString for Method {
-    fn to_string(&self) -> String {
+impl Method {
+    pub fn as_str(&self) -> &'static str {
         match self {
-            Method::GET => "GET".to_string(),
-            Method::POST => "POST".to_string(),
-            Method::DELETE => "DELETE".to_string(),
+            Method::GET => "GET",
+            Method::HEAD => "HEAD",
+            Method::POST => "POST",
+            Method::PUT => "PUT",
+            Method::PATCH => "PATCH",
+            Method::DELETE => "DELETE",
+            Method::OPTIONS => "OPTIONS",
+            Method::CONNECT => "CONNECT",
+            Method::TRACE => "TRACE",
         }
     }
 }
 
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Request {
     method: Method,
     path: String,
     version: String,
-    pub headers: HashMap<String, String>,
+    pub headers: HeaderMap,
     pub body: Option<Vec<u8>>,
+    // most a body may grow to once decompressed, the server's `max_body_size`
+    body_limit: usize,
+    extensions: Extensions,
 }
 
 impl Request {
-    pub fn new(
-        method: Method,
-        path: &str,
-        version: &str,
-        headers: HashMap<String, String>,
-    ) -> Self {
-        let req = Request {
+    pub fn new(method: Method, path: &str, version: &str, headers: HeaderMap) -> Self {
+        Request {
             method,
             path: path.to_string(),
             version: version.to_string(),
-            headers: headers,
+            headers,
             body: None,
-        };
-
-        req
+            body_limit: DEFAULT_MAX_BODY_SIZE,
+            extensions: Extensions::new(),
+        }
     }
 
     pub fn get_path(&self) -> &str {
@@ -73,17 +103,63 @@ impl Request {
         self.body = Some(body)
     }
 
+    /// header names are case insensitive
     pub fn get_header(&self, key: &str) -> Option<&str> {
-        match self.headers.get(key) {
-            Some(val) => Some(val.trim()),
-            None => None,
-        }
+        self.headers.get(key)
+    }
+
+    pub fn get_version(&self) -> &str {
+        self.version.as_str()
     }
 
     pub fn get_method(&self) -> &Method {
         &self.method
     }
+
+    /// largest body the extractors accept, counted after decompression
+    pub fn body_limit(&self) -> usize {
+        self.body_limit
+    }
+
+    pub(crate) fn set_body_limit(&mut self, limit: usize) {
+        self.body_limit = limit;
+    }
+
+    /// path parameters the router extracted, `{name}` segments by name
+    pub fn params(&self) -> &HashMap<String, String> {
+        static NONE: LazyLock<HashMap<String, String>> = LazyLock::new(HashMap::new);
+        match self.extensions.get::<PathParams>() {
+            Some(params) => &params.0,
+            None => &NONE,
+        }
+    }
+
+    /// id the `RequestId` middleware gave the request, `None` without it
+    pub fn request_id(&self) -> Option<&str> {
+        self.extensions.get::<Id>().map(|id| id.0.as_str())
+    }
+
+    /// values middleware and the router attach to the request, see `Extensions`
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
 }
+#[cfg(test)]
+impl Request {
+    /// an HTTP/1.1 request with the given header lines, what most tests start from
+    pub(crate) fn with_headers(method: Method, path: &str, headers: &[(&str, &str)]) -> Self {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.insert(*name, *value);
+        }
+        Request::new(method, path, "HTTP/1.1", map)
+    }
+}
+
 pub trait IntoRequest {
     fn into_request(self) -> Request;
 }
@@ -94,8 +170,10 @@ impl IntoRequest for String {
             method: Method::GET,
             path: "/".to_string(),
             version: "HTTP/1.1".to_string(),
-            headers: HashMap::new(),
+            headers: HeaderMap::new(),
             body: None,
+            body_limit: DEFAULT_MAX_BODY_SIZE,
+            extensions: Extensions::new(),
         }
     }
 }
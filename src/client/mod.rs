@@ -1,2 +1,4 @@
+// `client::client` is what every import names, renaming it breaks them all
+#[allow(clippy::module_inception)]
 pub mod client;
 pub mod parse;
@@ -1,8 +1,12 @@
 use super::client::{Method, Request};
+use crate::header::HeaderMap;
+use crate::server::config::{MinDataRate, ServerConfig};
+use crate::status_code::StatusCode;
 use memchr::memmem::{self};
-use std::collections::HashMap;
-use std::io::{self, Read};
-use tokio::io::Interest;
+use std::io::Read;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+use tokio::time::{timeout, timeout_at};
 
 use std::net::TcpStream;
 use std::str::FromStr;
@@ -20,6 +24,46 @@ pub enum ParseError {
     AgentError,
     #[error("Closed connection")]
     CloseConn,
+    #[error("Request line exceeds the configured limit")]
+    RequestLineTooLong,
+    #[error("Request headers exceed the configured size limit")]
+    HeadersTooLarge,
+    #[error("Request has more headers than allowed")]
+    TooManyHeaders,
+    #[error("Timed out waiting for the request headers")]
+    Timeout,
+    #[error("Client sent data below the minimum data rate")]
+    TooSlow,
+    #[error("Kept alive connection was idle for too long")]
+    Idle,
+    #[error("Invalid header field name")]
+    InvalidHeaderName,
+    #[error("Invalid Content-Length header")]
+    InvalidContentLength,
+    #[error("Unsupported Transfer-Encoding")]
+    UnsupportedTransferEncoding,
+    #[error("Request body exceeds the configured limit")]
+    BodyTooLarge,
+    #[error("Reading from the connection failed: {0}")]
+    Io(std::io::Error),
+}
+
+impl ParseError {
+    /// status the client should be answered with, `None` when the connection is
+    /// gone or the request is so broken that answering makes no sense
+    pub fn status_code(&self) -> Option<StatusCode> {
+        match self {
+            ParseError::RequestLineTooLong => Some(StatusCode::URI_TOO_LONG),
+            ParseError::BodyTooLarge => Some(StatusCode::PAYLOAD_TOO_LARGE),
+            ParseError::HeadersTooLarge | ParseError::TooManyHeaders => {
+                Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+            }
+            ParseError::Timeout | ParseError::TooSlow => Some(StatusCode::REQUEST_TIMEOUT),
+            ParseError::UnsupportedTransferEncoding => Some(StatusCode::NOT_IMPLEMENTED),
+            ParseError::CloseConn | ParseError::Idle | ParseError::Io(_) => None,
+            _ => Some(StatusCode::BAD_REQUEST),
+        }
+    }
 }
 
 //one unique connection between two sockets
@@ -40,7 +84,7 @@ pub fn parse_http_blocking(stream: &mut TcpStream) -> Result<Request, ParseError
         }
 
         let mut finder = memmem::find_iter(&v, b"\r\n\r\n");
-        if let Some(pos) = finder.nth(0) {
+        if let Some(pos) = finder.next() {
             let header = parse_header(&v[..pos]);
             match header {
                 Ok(head) => return Ok(head),
@@ -51,74 +95,388 @@ pub fn parse_http_blocking(stream: &mut TcpStream) -> Result<Request, ParseError
 }
 
 fn parse_header(buf: &[u8]) -> Result<Request, ParseError> {
-    let header = String::from_utf8_lossy(&buf);
+    let header = String::from_utf8_lossy(buf);
 
-    let request_line = header.lines().nth(0).ok_or(ParseError::HearderError)?;
+    let request_line = header.lines().next().ok_or(ParseError::HearderError)?;
     let mut parts = request_line.split_whitespace();
 
     let method_str = parts.next().ok_or(ParseError::HearderError)?;
     let path = parts.next().ok_or(ParseError::PathError)?.to_string();
     let version = parts.next().ok_or(ParseError::HearderError)?.to_string();
-    let method = Method::from_str(&method_str).map_err(|_| ParseError::HearderError)?;
+    let method = Method::from_str(method_str).map_err(|_| ParseError::HearderError)?;
 
-    let mut hmap = HashMap::new();
+    let mut hmap = HeaderMap::new();
     for theader in header.lines().skip(1) {
         let (k, v) = theader.split_once(":").ok_or(ParseError::HearderError)?;
-        hmap.insert(k.to_string(), v.to_string());
+        // `Content-Length : 5` must not slip past the framing as another
+        // header, RFC 9112 5.1
+        if !is_token(k) {
+            return Err(ParseError::InvalidHeaderName);
+        }
+        hmap.append(k, v.trim());
     }
 
     Ok(Request::new(method, &path, &version, hmap))
 }
 
-pub async fn parse_http(stream: &mut tokio::net::TcpStream) -> Result<Request, ParseError> {
-    let mut v: Vec<u8> = Vec::with_capacity(1024);
-    loop {
-        let ready = stream
-            .ready(Interest::READABLE)
-            .await
-            .map_err(|_| ParseError::CloseConn)?;
-
-        if ready.is_readable() {
+/// Parses the head of the next request off the connection and returns it along
+/// with the length of its body, which is left for `read_body`. `buf` belongs to
+/// the connection and keeps whatever was read past the head so the next call can
+/// pick it up. `idle_timeout` is how long to wait for the first byte of a
+/// request on a reused connection.
+pub async fn parse_http(
+    stream: &mut tokio::net::TcpStream,
+    buf: &mut Vec<u8>,
+    config: &ServerConfig,
+    idle_timeout: Option<Duration>,
+) -> Result<(Request, usize), ParseError> {
+    // nothing arriving on a kept alive connection is not an error, the client
+    // is just done with us
+    if buf.is_empty() {
+        if let Some(idle) = idle_timeout {
             let mut local = [0; 2024];
-            match stream.try_read(&mut local) {
-                Ok(n) => {
-                    v.extend_from_slice(&local[..n]);
-
-                    if n == 0 {
-                        eprintln!("terminating socket con");
-                        return Err(ParseError::CloseConn);
-                    }
-                    if n > local.len() {
-                        eprintln!("packet sent was too big");
-                        return Err(ParseError::CloseConn);
-                    }
-
-                    let mut request;
-
-                    let mut finder = memmem::find_iter(&v, b"\r\n\r\n");
-                    if let Some(pos) = finder.nth(0) {
-                        let header = parse_header(&v[..pos]);
-                        match header {
-                            Ok(head) => request = head,
-                            Err(_) => return Err(ParseError::HearderError),
-                        };
-
-                        let body_headers = request.get_header("Content-Length");
-                        if let Some(_body) = body_headers {
-                            let body = &v[pos..];
-                            if !body.is_empty() {
-                                request.set_body(body.to_vec());
-                            }
-                        }
-
-                        return Ok(request);
-                    }
-                }
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    continue;
-                }
-                Err(_) => return Err(ParseError::CloseConn),
+            match timeout(idle, stream.read(&mut local)).await {
+                Ok(Ok(0)) | Ok(Err(_)) => return Err(ParseError::CloseConn),
+                Ok(Ok(n)) => buf.extend_from_slice(&local[..n]),
+                Err(_) => return Err(ParseError::Idle),
             }
         }
     }
+
+    let started = Instant::now();
+    let deadline = started + config.header_read_timeout;
+    let mut received = 0;
+    let head_end = loop {
+        if let Some(pos) = memmem::find(buf, b"\r\n\r\n") {
+            break pos + 4;
+        }
+        check_head_limits(buf, config)?;
+        received += read_more(stream, buf, config, started, received, Some(deadline)).await?;
+    };
+    check_head_limits(&buf[..head_end], config)?;
+    let mut request = parse_header(&buf[..head_end - 4])?;
+    request.set_body_limit(config.max_body_size);
+    let length = body_length(&request, config)?;
+    buf.drain(..head_end);
+
+    Ok((request, length))
+}
+
+/// Reads a body of `length` bytes, starting with whatever of it is already in `buf`.
+pub async fn read_body(
+    stream: &mut tokio::net::TcpStream,
+    buf: &mut Vec<u8>,
+    config: &ServerConfig,
+    length: usize,
+) -> Result<Vec<u8>, ParseError> {
+    let started = Instant::now();
+    let mut received = 0;
+    while buf.len() < length {
+        received += read_more(stream, buf, config, started, received, None).await?;
+    }
+    Ok(buf.drain(..length).collect())
+}
+
+/// Takes the next request out of `buf` when it has already arrived in full,
+/// without touching the socket. This is how pipelined requests get picked up.
+pub fn parse_buffered(
+    buf: &mut Vec<u8>,
+    config: &ServerConfig,
+) -> Result<Option<Request>, ParseError> {
+    let Some(pos) = memmem::find(buf, b"\r\n\r\n") else {
+        check_head_limits(buf, config)?;
+        return Ok(None);
+    };
+    let head_end = pos + 4;
+    check_head_limits(&buf[..head_end], config)?;
+    let mut request = parse_header(&buf[..pos])?;
+    request.set_body_limit(config.max_body_size);
+    let length = body_length(&request, config)?;
+    if buf.len() < head_end + length {
+        return Ok(None);
+    }
+
+    buf.drain(..head_end);
+    if length > 0 {
+        request.set_body(buf.drain(..length).collect());
+    }
+    Ok(Some(request))
+}
+
+fn body_length(request: &Request, config: &ServerConfig) -> Result<usize, ParseError> {
+    // without chunked decoding there is no way to tell where such a body ends
+    if request.get_header("Transfer-Encoding").is_some() {
+        return Err(ParseError::UnsupportedTransferEncoding);
+    }
+
+    let length = content_length(request)?.unwrap_or(0);
+    // refused from the header alone, before a single byte of it is read
+    if length > config.max_body_size {
+        return Err(ParseError::BodyTooLarge);
+    }
+    Ok(length)
+}
+
+// Every Content-Length value and list item has to be plain digits and all of
+// them the same number, anything else leaves the framing open to
+// interpretation, RFC 9112 6.3
+fn content_length(request: &Request) -> Result<Option<usize>, ParseError> {
+    let mut length = None;
+    for value in request.headers.get_all("Content-Length") {
+        for item in value.split(',').map(str::trim) {
+            if item.is_empty() || !item.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(ParseError::InvalidContentLength);
+            }
+            let item: usize = item.parse().map_err(|_| ParseError::InvalidContentLength)?;
+            if length.is_some_and(|length| length != item) {
+                return Err(ParseError::InvalidContentLength);
+            }
+            length = Some(item);
+        }
+    }
+    Ok(length)
+}
+
+// field names are tokens, RFC 9110 5.6.2
+fn is_token(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+// reads whatever the client sent next into `buf`, giving up at `deadline` or
+// as soon as the client falls below the minimum data rate counted from `started`
+async fn read_more(
+    stream: &mut tokio::net::TcpStream,
+    buf: &mut Vec<u8>,
+    config: &ServerConfig,
+    started: Instant,
+    received: usize,
+    deadline: Option<Instant>,
+) -> Result<usize, ParseError> {
+    let rate_deadline = config
+        .min_data_rate
+        .as_ref()
+        .map(|rate| rate_deadline(rate, started, received));
+    let (wake_at, on_timeout) = match (deadline, rate_deadline) {
+        (Some(deadline), Some(rate)) if rate < deadline => (Some(rate), ParseError::TooSlow),
+        (Some(deadline), _) => (Some(deadline), ParseError::Timeout),
+        (None, Some(rate)) => (Some(rate), ParseError::TooSlow),
+        (None, None) => (None, ParseError::Timeout),
+    };
+
+    let mut local = [0; 2024];
+    let read = match wake_at {
+        Some(at) => timeout_at(at.into(), stream.read(&mut local))
+            .await
+            .map_err(|_| on_timeout)?,
+        None => stream.read(&mut local).await,
+    };
+    match read {
+        Ok(0) => Err(ParseError::CloseConn),
+        Err(e) => Err(ParseError::Io(e)),
+        Ok(n) => {
+            buf.extend_from_slice(&local[..n]);
+            Ok(n)
+        }
+    }
+}
+
+// checks whatever part of the head has arrived so far, so that a client can't
+// make us buffer more than the limits allow
+fn check_head_limits(head: &[u8], config: &ServerConfig) -> Result<(), ParseError> {
+    match memchr::memchr(b'\n', head) {
+        Some(line_end) if line_end > config.max_request_line => {
+            return Err(ParseError::RequestLineTooLong)
+        }
+        None if head.len() > config.max_request_line => return Err(ParseError::RequestLineTooLong),
+        _ => {}
+    }
+
+    if head.len() > config.max_header_bytes {
+        return Err(ParseError::HeadersTooLarge);
+    }
+
+    // every header line ends with a newline, the request line and the blank
+    // line are not headers
+    let lines = memchr::memchr_iter(b'\n', head).count();
+    if lines.saturating_sub(2) > config.max_headers {
+        return Err(ParseError::TooManyHeaders);
+    }
+    Ok(())
+}
+
+// point in time at which `received` bytes stop satisfying the minimum rate
+fn rate_deadline(rate: &MinDataRate, started: Instant, received: usize) -> Instant {
+    let earned = if rate.bytes_per_second == 0 {
+        Duration::MAX
+    } else {
+        Duration::from_secs_f64(received as f64 / rate.bytes_per_second as f64)
+    };
+    started + earned.max(rate.grace_period)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &str) -> Result<Option<Request>, ParseError> {
+        let mut buf = raw.as_bytes().to_vec();
+        parse_buffered(&mut buf, &ServerConfig::new())
+    }
+
+    fn status(raw: &str) -> Option<u16> {
+        parse(raw).err()?.status_code().map(|s| s.as_u16())
+    }
+
+    #[test]
+    fn whitespace_before_colon_is_rejected() {
+        let raw = "POST / HTTP/1.1\r\nContent-Length : 24\r\n\r\nGET /secret HTTP/1.1\r\n\r\n";
+        assert!(matches!(parse(raw), Err(ParseError::InvalidHeaderName)));
+        assert_eq!(status(raw), Some(400));
+    }
+
+    #[test]
+    fn field_names_must_be_tokens() {
+        assert_eq!(status("GET / HTTP/1.1\r\nX Y: 1\r\n\r\n"), Some(400));
+        assert_eq!(status("GET / HTTP/1.1\r\n: 1\r\n\r\n"), Some(400));
+        assert_eq!(status("GET / HTTP/1.1\r\n Folded: 1\r\n\r\n"), Some(400));
+        assert!(parse("GET / HTTP/1.1\r\nX-Custom_Name.1: a b\r\n\r\n").is_ok());
+    }
+
+    #[test]
+    fn conflicting_content_lengths_are_rejected() {
+        let raw = "POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 30\r\n\r\nabc";
+        assert!(matches!(parse(raw), Err(ParseError::InvalidContentLength)));
+        assert_eq!(
+            status("POST / HTTP/1.1\r\nContent-Length: 3, 4\r\n\r\nabcd"),
+            Some(400)
+        );
+    }
+
+    #[test]
+    fn repeated_equal_content_lengths_are_fine() {
+        let raw = "POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 3\r\n\r\nabc";
+        let request = parse(raw).unwrap().unwrap();
+        assert_eq!(request.body.as_deref(), Some(&b"abc"[..]));
+    }
+
+    #[test]
+    fn content_length_must_be_digits() {
+        for value in ["+3", "-3", "3 4", "0x3", "", "３"] {
+            let raw = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\nabc", value);
+            assert_eq!(status(&raw), Some(400), "{:?}", value);
+        }
+        let raw = "POST / HTTP/1.1\r\nContent-Length: 99999999999999999999999\r\n\r\n";
+        assert_eq!(status(raw), Some(400));
+    }
+
+    #[test]
+    fn transfer_encoding_is_not_implemented() {
+        let raw = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+        assert_eq!(status(raw), Some(501));
+    }
+
+    fn limited() -> ServerConfig {
+        ServerConfig::new()
+            .max_request_line(32)
+            .max_header_bytes(128)
+            .max_headers(3)
+    }
+
+    fn limit_status(raw: &str) -> Option<u16> {
+        let mut buf = raw.as_bytes().to_vec();
+        let error = parse_buffered(&mut buf, &limited()).err()?;
+        error.status_code().map(|s| s.as_u16())
+    }
+
+    async fn connected() -> (tokio::net::TcpStream, tokio::net::TcpStream) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = tokio::net::TcpStream::connect(listener.local_addr().unwrap());
+        let (client, accepted) = tokio::join!(client, listener.accept());
+        (client.unwrap(), accepted.unwrap().0)
+    }
+
+    #[test]
+    fn long_request_lines_are_uri_too_long() {
+        let long = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(40));
+        assert_eq!(limit_status(&long), Some(414));
+        // refused before the line is even complete
+        assert_eq!(limit_status(&format!("GET /{}", "a".repeat(40))), Some(414));
+        assert_eq!(limit_status("GET /short HTTP/1.1\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn large_heads_are_header_fields_too_large() {
+        let big = format!("GET / HTTP/1.1\r\nX-Big: {}\r\n\r\n", "b".repeat(120));
+        assert_eq!(limit_status(&big), Some(431));
+        let partial = format!("GET / HTTP/1.1\r\nX-Big: {}", "b".repeat(120));
+        assert_eq!(limit_status(&partial), Some(431));
+    }
+
+    #[test]
+    fn too_many_headers_are_header_fields_too_large() {
+        let three = "GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n";
+        assert_eq!(limit_status(three), None);
+        let four = "GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\nD: 4\r\n\r\n";
+        assert_eq!(limit_status(four), Some(431));
+    }
+
+    #[test]
+    fn bodies_over_the_limit_are_refused_from_the_header() {
+        let config = ServerConfig::new().max_body_size(4);
+        let mut buf = b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\n".to_vec();
+        let error = parse_buffered(&mut buf, &config).unwrap_err();
+        assert_eq!(error.status_code(), Some(StatusCode::PAYLOAD_TOO_LARGE));
+    }
+
+    #[tokio::test]
+    async fn slow_heads_time_out() {
+        let (mut client, mut server) = connected().await;
+        let config = ServerConfig::new()
+            .header_read_timeout(Duration::from_millis(50))
+            .min_data_rate(None);
+        tokio::io::AsyncWriteExt::write_all(&mut client, b"GET / HTTP/1.1\r\n")
+            .await
+            .unwrap();
+        let mut buf = Vec::new();
+        let error = parse_http(&mut server, &mut buf, &config, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, ParseError::Timeout));
+        assert_eq!(error.status_code(), Some(StatusCode::REQUEST_TIMEOUT));
+    }
+
+    #[tokio::test]
+    async fn trickling_clients_are_too_slow() {
+        let (mut client, mut server) = connected().await;
+        let config = ServerConfig::new()
+            .header_read_timeout(Duration::from_secs(5))
+            .min_data_rate(Some(MinDataRate {
+                bytes_per_second: 1000,
+                grace_period: Duration::from_millis(50),
+            }));
+        tokio::io::AsyncWriteExt::write_all(&mut client, b"GET")
+            .await
+            .unwrap();
+        let mut buf = Vec::new();
+        let error = parse_http(&mut server, &mut buf, &config, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, ParseError::TooSlow));
+        assert_eq!(error.status_code(), Some(StatusCode::REQUEST_TIMEOUT));
+    }
+
+    #[tokio::test]
+    async fn idle_connections_are_closed_without_an_answer() {
+        let (_client, mut server) = connected().await;
+        let mut buf = Vec::new();
+        let idle = Some(Duration::from_millis(20));
+        let error = parse_http(&mut server, &mut buf, &ServerConfig::new(), idle)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, ParseError::Idle));
+        assert_eq!(error.status_code(), None);
+    }
 }
@@ -0,0 +1,188 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+
+// what the map stores, values have to be clonable so requests still are
+trait AnyClone: Any + Send + Sync {
+    fn clone_box(&self) -> Box<dyn AnyClone>;
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+}
+
+impl<T: Clone + Send + Sync + 'static> AnyClone for T {
+    fn clone_box(&self) -> Box<dyn AnyClone> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+impl Clone for Box<dyn AnyClone> {
+    fn clone(&self) -> Self {
+        (**self).clone_box()
+    }
+}
+
+/// Values keyed by their type, one per type, riding along with a request or a
+/// response. Middleware leaves things here for the handlers further down, the
+/// router puts the matched `MatchedPath` and `PathParams` in.
+///
+/// ```ignore
+/// #[derive(Clone)]
+/// struct User(String);
+///
+/// request.extensions_mut().insert(User("ferris".into()));
+/// let user = request.extensions().get::<User>();
+/// ```
+#[derive(Clone, Default)]
+pub struct Extensions {
+    // most requests never get any, so no table or hasher until the first insert
+    map: Option<HashMap<TypeId, Box<dyn AnyClone>>>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// stores `value`, handing back the one of the same type it replaced
+    pub fn insert<T: Clone + Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        self.map
+            .get_or_insert_with(HashMap::new)
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|old| old.into_any().downcast().ok().map(|old| *old))
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.map
+            .as_ref()?
+            .get(&TypeId::of::<T>())
+            .and_then(|value| (**value).as_any().downcast_ref())
+    }
+
+    pub fn get_mut<T: Send + Sync + 'static>(&mut self) -> Option<&mut T> {
+        self.map
+            .as_mut()?
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|value| (**value).as_any_mut().downcast_mut())
+    }
+
+    pub fn remove<T: Send + Sync + 'static>(&mut self) -> Option<T> {
+        self.map
+            .as_mut()?
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.into_any().downcast().ok().map(|value| *value))
+    }
+
+    pub fn contains<T: Send + Sync + 'static>(&self) -> bool {
+        self.get::<T>().is_some()
+    }
+
+    /// moves every value of `other` in here, replacing those of the same type
+    pub fn extend(&mut self, other: Extensions) {
+        if let Some(other) = other.map {
+            self.map.get_or_insert_with(HashMap::new).extend(other);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.map = None;
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.as_ref().map_or(0, |map| map.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// the values needn't be `Debug`, so only how many there are
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct User(String);
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Hits(u32);
+
+    #[test]
+    fn insert_hands_back_the_replaced_value() {
+        let mut extensions = Extensions::new();
+        assert!(extensions.is_empty());
+        assert_eq!(extensions.insert(User("ferris".into())), None);
+        assert_eq!(
+            extensions.insert(User("corro".into())),
+            Some(User("ferris".into()))
+        );
+        assert_eq!(extensions.insert(Hits(1)), None);
+        assert_eq!(extensions.len(), 2);
+        assert_eq!(extensions.get::<User>(), Some(&User("corro".into())));
+        assert_eq!(extensions.get::<String>(), None);
+    }
+
+    #[test]
+    fn values_change_in_place_and_come_out() {
+        let mut extensions = Extensions::new();
+        assert_eq!(extensions.get_mut::<Hits>(), None);
+        extensions.insert(Hits(1));
+        extensions.get_mut::<Hits>().unwrap().0 += 1;
+        assert_eq!(extensions.get::<Hits>(), Some(&Hits(2)));
+
+        assert_eq!(extensions.remove::<Hits>(), Some(Hits(2)));
+        assert_eq!(extensions.remove::<Hits>(), None);
+        assert!(!extensions.contains::<Hits>());
+        assert!(extensions.is_empty());
+    }
+
+    #[test]
+    fn extend_overwrites_values_of_the_same_type() {
+        let mut extensions = Extensions::new();
+        extensions.insert(User("ferris".into()));
+        extensions.insert(Hits(1));
+        let mut other = Extensions::new();
+        other.insert(Hits(7));
+        extensions.extend(other);
+        assert_eq!(extensions.get::<Hits>(), Some(&Hits(7)));
+        assert_eq!(extensions.get::<User>(), Some(&User("ferris".into())));
+
+        extensions.extend(Extensions::new());
+        assert_eq!(extensions.len(), 2);
+        extensions.clear();
+        assert!(extensions.is_empty());
+    }
+
+    #[test]
+    fn clones_are_independent() {
+        let mut original = Extensions::new();
+        original.insert(Hits(1));
+        let mut copy = original.clone();
+        copy.get_mut::<Hits>().unwrap().0 = 5;
+        copy.insert(User("ferris".into()));
+        assert_eq!(original.get::<Hits>(), Some(&Hits(1)));
+        assert!(!original.contains::<User>());
+        assert_eq!(copy.get::<Hits>(), Some(&Hits(5)));
+    }
+}
@@ -1,30 +1,47 @@
-use crate::server::handle_connection::StatusCode;
-use std::io::Write;
-use std::{collections::HashMap, io::Read};
-
-use thiserror::Error;
+use crate::body::{Body, BoxError};
+use crate::extensions::Extensions;
+use crate::header::HeaderMap;
+use crate::server::encoder::{encode_buffered, EncodeContext};
+use crate::status_code::StatusCode;
+use bytes::Bytes;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::future::Future;
+
+use serde::Serialize;
 
 use crate::client::client::Request;
+use crate::server::extractors::{ExtractError, ACCEPTED_ENCODINGS};
 
 pub struct Response {
     status_code: StatusCode,
-    headers: HashMap<String, String>,
-    body: Option<Vec<u8>>,
+    // replaces the canonical reason phrase in the status line
+    reason: Option<String>,
+    headers: HeaderMap,
+    body: Body,
+    extensions: Extensions,
+    // the handler error this response was rendered from, picked up by the
+    // connection handling to log it and apply the configured error format
+    error: Option<Box<HandlerError>>,
 }
 
 #[derive(Default)]
 pub struct ResponseBuilder {
     status_code: Option<StatusCode>,
-    headers: Option<HashMap<String, String>>,
-    body: Option<Vec<u8>>,
+    reason: Option<String>,
+    headers: Option<HeaderMap>,
+    body: Body,
+    extensions: Extensions,
 }
 
 impl ResponseBuilder {
     pub fn new() -> Self {
         ResponseBuilder {
             status_code: None,
-            headers: Some(HashMap::new()),
-            body: None,
+            reason: None,
+            headers: Some(HeaderMap::new()),
+            body: Body::Empty,
+            extensions: Extensions::new(),
         }
     }
 
@@ -33,55 +50,86 @@ impl ResponseBuilder {
         self
     }
 
+    /// custom reason phrase, mostly useful for codes without a registered one
+    pub fn reason(mut self, reason: &str) -> Self {
+        self.reason = Some(reason.to_string());
+        self
+    }
+
     pub fn header(mut self, header: (&str, &str)) -> Self {
         //header is always some
-        self.headers
-            .as_mut()
-            .unwrap()
-            .insert(header.0.to_string(), header.1.to_string());
+        self.headers.as_mut().unwrap().append(header.0, header.1);
         self
     }
 
     pub fn body(mut self, body: &[u8]) -> Self {
-        self.body = Some(body.to_vec());
+        self.body = body.to_vec().into();
+        self
+    }
+
+    /// any kind of body, `Body::from_stream` or `Body::from_reader` for ones
+    /// that are written out as they are produced
+    pub fn with_body(mut self, body: impl Into<Body>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// value for the response's extensions, for the layers it passes through
+    pub fn extension<T: Clone + Send + Sync + 'static>(mut self, value: T) -> Self {
+        self.extensions.insert(value);
         self
     }
 
     pub fn build(self) -> Response {
         Response {
             status_code: self.status_code.expect("status_code is never none"),
+            reason: self.reason,
             headers: self.headers.expect("headers is at least empty map"),
             body: self.body,
+            extensions: self.extensions,
+            error: None,
         }
     }
 }
 
 impl Response {
-    fn new_with_body(body: String) -> Self {
+    fn new_with_file(body: impl Into<Body>) -> Self {
         Self {
-            // statuscode needs default and here we set the default to OK
-            status_code: StatusCode::ALL_OK,
-            body: Some(body.as_bytes().to_vec()),
-            headers: HashMap::new(),
+            status_code: StatusCode::OK,
+            reason: None,
+            body: body.into(),
+            headers: HeaderMap::new(),
+            extensions: Extensions::new(),
+            error: None,
         }
     }
-    fn new_with_file(body: Vec<u8>) -> Self {
-        Self {
-            status_code: StatusCode::ALL_OK,
-            body: Some(body),
-            headers: HashMap::new(),
-        }
-    }
-    fn add_core_header(&mut self, k: String, v: String) {
+    pub(crate) fn add_core_header(&mut self, k: String, v: String) {
         self.headers.insert(k, v);
     }
 
+    fn with_content_type(mut self, content_type: &str) -> Self {
+        self.headers.insert("Content-Type", content_type);
+        self
+    }
+
+    // every name in `headers` replaces what the response had under it, a name
+    // given twice ends up with both values
+    fn replace_headers(&mut self, headers: HeaderMap) {
+        for name in headers.keys() {
+            self.headers.remove(name);
+        }
+        self.headers.extend(headers);
+    }
+
     fn new() -> Self {
         Self {
             // statuscode needs default and here we set the default to OK
-            status_code: StatusCode::ALL_OK,
-            body: None,
-            headers: HashMap::new(),
+            status_code: StatusCode::OK,
+            reason: None,
+            body: Body::Empty,
+            headers: HeaderMap::new(),
+            extensions: Extensions::new(),
+            error: None,
         }
     }
 
@@ -89,43 +137,103 @@ impl Response {
         Self {
             // statuscode needs default and here we set the default to OK
             status_code: StatusCode::NOT_FOUND,
-            body: None,
-            headers: HashMap::new(),
+            reason: None,
+            body: Body::Empty,
+            headers: HeaderMap::new(),
+            extensions: Extensions::new(),
+            error: None,
         }
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut response: Vec<u8> = Vec::new();
-
-        response.extend_from_slice(self.status_code.as_str().as_bytes());
-        response.extend_from_slice(b"\r\n");
+    pub fn status_code(&self) -> StatusCode {
+        self.status_code
+    }
 
-        response.extend_from_slice(b"Connection: close\r\n");
-        for (k, v) in self.headers.iter() {
-            write_header(k, v, &mut response);
+    /// reason phrase for the status line, the canonical one unless overridden
+    pub fn reason(&self) -> &str {
+        match &self.reason {
+            Some(reason) => reason.as_str(),
+            None => self.status_code.canonical_reason().unwrap_or(""),
         }
+    }
 
-        if let Some(body) = &self.body {
-            let body_len = body.len();
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
 
-            write_header(
-                "Content-Length",
-                body_len.to_string().as_str(),
-                &mut response,
-            );
-            response.extend_from_slice(b"\r\n");
-            //write body
-            response.extend_from_slice(&body);
-        } else {
-            response.extend_from_slice(b"\r\n");
-        }
+    pub fn headers_mut(&mut self) -> &mut HeaderMap {
+        &mut self.headers
+    }
 
-        response
+    pub fn body(&self) -> &Body {
+        &self.body
+    }
+
+    pub fn body_mut(&mut self) -> &mut Body {
+        &mut self.body
+    }
+
+    /// values handlers and layers attach to the response for the layers
+    /// further up
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    pub fn into_body(self) -> Body {
+        self.body
+    }
+
+    pub(crate) fn take_error(&mut self) -> Option<Box<HandlerError>> {
+        self.error.take()
+    }
+
+    /// Serializes the response on its own, as the answer to a GET on a
+    /// persistent HTTP/1.1 connection. The connection handling goes through
+    /// `encoder::encode` with the details of the actual request instead.
+    /// A streamed body can't be turned into bytes here, only its head is.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        encode_buffered(self, &EncodeContext::default())
     }
 }
 
 pub trait IntoResponse {
     fn into_response(self) -> Response;
+
+    /// Erases the type, for handlers returning different kinds of responses
+    /// from different branches.
+    fn boxed(self) -> BoxedResponse
+    where
+        Self: Sized + Send + 'static,
+    {
+        Box::new(self)
+    }
+}
+
+/// Object safe side of `IntoResponse`, implemented for every `IntoResponse`.
+pub trait IntoResponseBoxed: Send {
+    fn into_response_boxed(self: Box<Self>) -> Response;
+}
+
+impl<T> IntoResponseBoxed for T
+where
+    T: IntoResponse + Send,
+{
+    fn into_response_boxed(self: Box<Self>) -> Response {
+        (*self).into_response()
+    }
+}
+
+/// Any response, type erased. Made with `IntoResponse::boxed`.
+pub type BoxedResponse = Box<dyn IntoResponseBoxed>;
+
+impl IntoResponse for BoxedResponse {
+    fn into_response(self) -> Response {
+        self.into_response_boxed()
+    }
 }
 
 impl IntoResponse for Response {
@@ -142,7 +250,7 @@ impl IntoResponse for () {
 
 impl IntoResponse for String {
     fn into_response(self) -> Response {
-        let mut resp = Response::new_with_file(self.to_string().into());
+        let mut resp = Response::new_with_file(self.to_string());
         resp.add_core_header("Content-Type".to_string(), "text/plain".to_string());
         resp
     }
@@ -150,12 +258,28 @@ impl IntoResponse for String {
 
 impl IntoResponse for &str {
     fn into_response(self) -> Response {
-        let mut resp = Response::new_with_file(self.to_string().into());
+        let mut resp = Response::new_with_file(self.to_string());
         resp.add_core_header("Content-Type".to_string(), "text/plain".to_string());
         resp
     }
 }
 
+impl IntoResponse for Cow<'static, str> {
+    fn into_response(self) -> Response {
+        match self {
+            Cow::Borrowed(text) => Response::new_with_file(text),
+            Cow::Owned(text) => Response::new_with_file(text),
+        }
+        .with_content_type("text/plain")
+    }
+}
+
+impl IntoResponse for Bytes {
+    fn into_response(self) -> Response {
+        Response::new_with_file(self).with_content_type("application/octet-stream")
+    }
+}
+
 impl<T> IntoResponse for (StatusCode, T)
 where
     T: IntoResponse,
@@ -163,22 +287,78 @@ where
     fn into_response(self) -> Response {
         let (status, body) = self;
         let mut response = body.into_response();
-        response.status_code = status.clone();
+        response.status_code = status;
         response
     }
 }
 
-impl IntoResponse for StatusCode {
+// the headers replace whatever `T` set under the same names
+impl<T> IntoResponse for (StatusCode, HeaderMap, T)
+where
+    T: IntoResponse,
+{
+    fn into_response(self) -> Response {
+        let (status, headers, body) = self;
+        let mut response = body.into_response();
+        response.status_code = status;
+        response.replace_headers(headers);
+        response
+    }
+}
+
+impl<K, V, T, const N: usize> IntoResponse for ([(K, V); N], T)
+where
+    K: AsRef<str>,
+    V: AsRef<str>,
+    T: IntoResponse,
+{
+    fn into_response(self) -> Response {
+        let (headers, body) = self;
+        let mut response = body.into_response();
+        response.replace_headers(
+            headers
+                .iter()
+                .map(|(k, v)| (k.as_ref(), v.as_ref()))
+                .collect(),
+        );
+        response
+    }
+}
+
+impl<K, V, T, const N: usize> IntoResponse for (StatusCode, [(K, V); N], T)
+where
+    K: AsRef<str>,
+    V: AsRef<str>,
+    T: IntoResponse,
+{
+    fn into_response(self) -> Response {
+        let (status, headers, body) = self;
+        let mut response = (headers, body).into_response();
+        response.status_code = status;
+        response
+    }
+}
+
+// nothing to show is a 404
+impl<T> IntoResponse for Option<T>
+where
+    T: IntoResponse,
+{
     fn into_response(self) -> Response {
         match self {
-            StatusCode::NOT_FOUND => "404 Not Found".into_response(),
-            StatusCode::CREATED => "201 Created".into_response(),
-            StatusCode::METHOD_NOT_ALLOWED => "405 Method Not Allowed".into_response(),
-            _ => "500 Internal Server Error".into_response(),
+            Some(found) => found.into_response(),
+            None => (StatusCode::NOT_FOUND, ()).into_response(),
         }
     }
 }
 
+// the status with its "404 Not Found" text as the body
+impl IntoResponse for StatusCode {
+    fn into_response(self) -> Response {
+        (self, self.to_string()).into_response()
+    }
+}
+
 impl IntoResponse for Vec<u8> {
     fn into_response(self) -> Response {
         let mut resp = Response::new_with_file(self);
@@ -190,15 +370,24 @@ impl IntoResponse for Vec<u8> {
     }
 }
 
-// this is shit
-impl IntoResponse for HandlerError {
+// streams and other bodies nobody knows the type of
+impl IntoResponse for Body {
     fn into_response(self) -> Response {
-        let response = Response::error();
-        response
+        let mut resp = Response::new_with_file(self);
+        resp.add_core_header(
+            "Content-Type".to_string(),
+            "application/octet-stream".to_string(),
+        );
+        resp
     }
 }
 
-impl IntoResponse for Result<Response, HandlerError> {
+// this is shit
+impl<T, E> IntoResponse for Result<T, E>
+where
+    T: IntoResponse,
+    E: IntoResponse,
+{
     fn into_response(self) -> Response {
         match self {
             Ok(fine) => fine.into_response(),
@@ -207,10 +396,309 @@ impl IntoResponse for Result<Response, HandlerError> {
     }
 }
 
-#[derive(Error, Debug)]
-pub enum HandlerError {
-    #[error("failed to serve on socket")]
-    MainHandlerError,
+/// HTML body, sent as `text/html; charset=utf-8`.
+pub struct Html<T>(pub T);
+
+impl<T> IntoResponse for Html<T>
+where
+    T: Into<Body>,
+{
+    fn into_response(self) -> Response {
+        Response::new_with_file(self.0).with_content_type("text/html; charset=utf-8")
+    }
+}
+
+/// Redirect to another location. `to` is the classic `302 Found`, `see_other`
+/// turns any request into a GET of the target, `temporary` and `permanent`
+/// keep the method and body. The location has to be percent encoded already,
+/// one with spaces, line breaks or non ASCII characters turns the redirect
+/// into a logged `500`.
+#[derive(Clone, Debug)]
+pub struct Redirect {
+    status_code: StatusCode,
+    location: String,
+}
+
+impl Redirect {
+    /// 302 Found
+    pub fn to(location: &str) -> Self {
+        Self::with_status(StatusCode::FOUND, location)
+    }
+
+    /// 303 See Other, what a POST handler answers with to send the client on to a page
+    pub fn see_other(location: &str) -> Self {
+        Self::with_status(StatusCode::SEE_OTHER, location)
+    }
+
+    /// 307 Temporary Redirect
+    pub fn temporary(location: &str) -> Self {
+        Self::with_status(StatusCode::TEMPORARY_REDIRECT, location)
+    }
+
+    /// 308 Permanent Redirect
+    pub fn permanent(location: &str) -> Self {
+        Self::with_status(StatusCode::PERMANENT_REDIRECT, location)
+    }
+
+    fn with_status(status_code: StatusCode, location: &str) -> Self {
+        Self {
+            status_code,
+            location: location.to_string(),
+        }
+    }
+
+    pub fn location(&self) -> &str {
+        &self.location
+    }
+
+    // a URI reference is printable ASCII without spaces, anything else has to
+    // be percent encoded by whoever built it. CR and LF would split the header
+    fn valid_location(&self) -> bool {
+        !self.location.is_empty() && self.location.bytes().all(|b| b.is_ascii_graphic())
+    }
+}
+
+impl IntoResponse for Redirect {
+    fn into_response(self) -> Response {
+        // a broken location is a bug in the handler, not something to send
+        // on, the error is logged with the location escaped
+        if !self.valid_location() {
+            let error = format!("redirect to invalid location {:?}", self.location);
+            return HandlerError::internal(error).into_response();
+        }
+        ResponseBuilder::new()
+            .status_code(self.status_code)
+            .header(("Location", &self.location))
+            .build()
+    }
+}
+
+/// An error a handler answers with. The status and the public message are what
+/// the client gets to see, the source chain stays on the server where it is
+/// logged together with the request it broke. Server errors default to the
+/// canonical reason phrase as their message so nothing internal slips out.
+///
+/// ```ignore
+/// fn load(request: Request) -> Result<String, HandlerError> {
+///     let config = std::fs::read_to_string("config.toml")?;
+///     let Json(update) = request.json::<Update>()?;
+///     if update.name.is_empty() {
+///         return Err(HandlerError::bad_request("name must not be empty").code("empty_name"));
+///     }
+///     Ok(apply(config, update))
+/// }
+/// ```
+#[derive(Debug)]
+pub struct HandlerError {
+    status_code: StatusCode,
+    message: Option<String>,
+    code: Option<String>,
+    headers: HeaderMap,
+    source: Option<BoxError>,
+}
+
+/// How handler errors are written out, set with `ServerConfig::error_format`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ErrorFormat {
+    /// the public message as `text/plain`
+    #[default]
+    PlainText,
+    /// an RFC 9457 `application/problem+json` document, the code goes into a
+    /// `code` extension member
+    ProblemJson,
+}
+
+#[derive(Serialize)]
+struct Problem<'a> {
+    #[serde(rename = "type")]
+    kind: &'a str,
+    title: &'a str,
+    status: u16,
+    detail: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<&'a str>,
+}
+
+impl HandlerError {
+    pub fn new(status_code: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status_code,
+            message: Some(message.into()),
+            code: None,
+            headers: HeaderMap::new(),
+            source: None,
+        }
+    }
+
+    /// just the status, the message is its reason phrase
+    pub fn status(status_code: StatusCode) -> Self {
+        Self {
+            status_code,
+            message: None,
+            code: None,
+            headers: HeaderMap::new(),
+            source: None,
+        }
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, message)
+    }
+
+    /// a 500 that only says so, `source` is logged
+    pub fn internal(source: impl Into<BoxError>) -> Self {
+        Self::status(StatusCode::INTERNAL_SERVER_ERROR).with_source(source)
+    }
+
+    /// machine readable code for clients to match on, e.g. `"quota_exceeded"`
+    pub fn code(mut self, code: impl Into<String>) -> Self {
+        self.code = Some(code.into());
+        self
+    }
+
+    /// extra header on the error response, `WWW-Authenticate`, `Retry-After`..
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.append(name, value);
+        self
+    }
+
+    /// the underlying error, logged but never sent
+    pub fn with_source(mut self, source: impl Into<BoxError>) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        self.status_code
+    }
+
+    pub fn message(&self) -> &str {
+        match &self.message {
+            Some(message) => message,
+            None => self.status_code.canonical_reason().unwrap_or("Error"),
+        }
+    }
+
+    pub fn error_code(&self) -> Option<&str> {
+        self.code.as_deref()
+    }
+
+    /// The public message followed by every source, `: ` separated. This is
+    /// what ends up in the log.
+    pub fn chain(&self) -> String {
+        let mut chain = self.message().to_string();
+        let mut next = std::error::Error::source(self);
+        while let Some(error) = next {
+            chain.push_str(": ");
+            chain.push_str(&error.to_string());
+            next = error.source();
+        }
+        chain
+    }
+
+    pub(crate) fn render(&self, format: ErrorFormat) -> (&'static str, Vec<u8>) {
+        match format {
+            ErrorFormat::PlainText => ("text/plain; charset=utf-8", self.message().into()),
+            ErrorFormat::ProblemJson => {
+                let problem = Problem {
+                    kind: "about:blank",
+                    title: self.status_code.canonical_reason().unwrap_or(""),
+                    status: self.status_code.as_u16(),
+                    detail: self.message(),
+                    code: self.code.as_deref(),
+                };
+                // a struct of strings and a number always serializes
+                let body = serde_json::to_vec(&problem).unwrap_or_default();
+                ("application/problem+json", body)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.status_code.as_u16(), self.message())
+    }
+}
+
+impl std::error::Error for HandlerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source
+            .as_deref()
+            .map(|e| e as &(dyn std::error::Error + 'static))
+    }
+}
+
+impl From<std::io::Error> for HandlerError {
+    fn from(error: std::io::Error) -> Self {
+        let status_code = match error.kind() {
+            std::io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            std::io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        Self::status(status_code).with_source(error)
+    }
+}
+
+impl From<serde_json::Error> for HandlerError {
+    fn from(error: serde_json::Error) -> Self {
+        // failing to write JSON is on us, failing to read it on the client
+        let status_code = if error.is_io() {
+            StatusCode::INTERNAL_SERVER_ERROR
+        } else if error.is_data() {
+            StatusCode::UNPROCESSABLE_ENTITY
+        } else {
+            StatusCode::BAD_REQUEST
+        };
+        Self::status(status_code).with_source(error)
+    }
+}
+
+impl From<anyhow::Error> for HandlerError {
+    fn from(error: anyhow::Error) -> Self {
+        Self::internal(error)
+    }
+}
+
+impl From<ExtractError> for HandlerError {
+    fn from(error: ExtractError) -> Self {
+        // these only ever describe what the client sent
+        let mut handler_error = Self::new(error.status_code(), error.to_string());
+        if let ExtractError::UnsupportedEncoding(_) = error {
+            handler_error = handler_error.header("Accept-Encoding", ACCEPTED_ENCODINGS);
+        }
+        handler_error.with_source(error)
+    }
+}
+
+tokio::task_local! {
+    // what `ServerConfig::error_format` says for the request being answered
+    static ERROR_FORMAT: ErrorFormat;
+}
+
+/// Runs `future` with handler errors turning into responses in `format`.
+pub(crate) async fn with_error_format<F: Future>(format: ErrorFormat, future: F) -> F::Output {
+    ERROR_FORMAT.scope(format, future).await
+}
+
+impl IntoResponse for HandlerError {
+    fn into_response(mut self) -> Response {
+        // plain text outside of a connection, in tests or tasks of their own
+        let format = ERROR_FORMAT.try_with(|format| *format).unwrap_or_default();
+        let (content_type, body) = self.render(format);
+        let mut response = ResponseBuilder::new()
+            .status_code(self.status_code)
+            .header(("Content-Type", content_type))
+            .with_body(body)
+            .build();
+        response.replace_headers(std::mem::take(&mut self.headers));
+        response.error = Some(Box::new(self));
+        response
+    }
 }
 
 pub trait Handler: Send + Sync + 'static {
@@ -269,6 +757,30 @@ where
     }
 }
 
-fn write_header<'a>(key: &'a str, value: &'a str, response: &'a mut Vec<u8>) {
-    write!(response, "{}: {}\r\n", key, value).unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redirects_carry_the_location() {
+        let response = Redirect::permanent("/new?q=a%20b").into_response();
+        assert_eq!(response.status_code(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(response.headers().get("Location"), Some("/new?q=a%20b"));
+    }
+
+    #[test]
+    fn invalid_locations_are_logged_errors() {
+        for location in ["/a\r\nSet-Cookie: x=1", "/a b", "/zażółć", ""] {
+            let mut response = Redirect::to(location).into_response();
+            assert_eq!(response.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+            assert_eq!(response.headers().get("Location"), None);
+            let error = response.take_error().expect("a handler error to log");
+            assert!(
+                error.chain().contains("invalid location"),
+                "{}",
+                error.chain()
+            );
+            assert!(!error.chain().contains('\r'));
+        }
+    }
 }
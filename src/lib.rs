@@ -4,13 +4,20 @@ use client::client::Request;
 use into_response::Handler0;
 use into_response::HandlerParams;
 use into_response::HandlerRequest;
-use server::router::MethodTypes;
 use std::io::{self, Write};
 use tokio::io::AsyncWriteExt;
+pub mod body;
+pub mod extensions;
+pub mod header;
 pub mod into_response;
 pub mod server;
+pub mod status_code;
+pub use body::Body;
+pub use extensions::Extensions;
+pub use header::HeaderMap;
 use into_response::Handler;
-pub use into_response::IntoResponse;
+pub use into_response::{ErrorFormat, HandlerError, Html, IntoResponse, Redirect};
+pub use status_code::StatusCode;
 use std::collections::HashMap;
 use std::net::TcpListener;
 
@@ -30,6 +37,11 @@ impl HandlerTypes {
             HandlerTypes::Params((_, method)) => method,
         }
     }
+
+    // only handlers getting the whole request ever see the body
+    fn reads_body(&self) -> bool {
+        matches!(self, HandlerTypes::Full(_) | HandlerTypes::Body(_))
+    }
 }
 
 impl HandlerTypes {
@@ -71,6 +83,9 @@ pub enum TcpListeners {
     Asynchronous(TcpListener),
 }
 
+// the blocking listener is switched off in `Server::serve`, its writer stays
+// for when it comes back
+#[allow(dead_code)]
 trait ResponseWriter {
     fn write_response<T: AsRef<[u8]>>(&mut self, response: T) -> io::Result<()>;
 }
@@ -97,6 +112,7 @@ impl AsyncResponseWriter for tokio::net::TcpStream {
     }
 }
 
+#[allow(dead_code)]
 fn write_blocking<T>(stream: &mut std::net::TcpStream, response: T) -> io::Result<()>
 where
     T: AsRef<[u8]>,
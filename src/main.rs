@@ -1,18 +1,49 @@
-use skibidi_http::server::handle_connection::StatusCode;
-use skibidi_http::server::router::Router;
+use serde::Deserialize;
+use skibidi_http::server::auth::{BasicAuth, BasicUser};
+use skibidi_http::server::compression::Compression;
+use skibidi_http::server::conditional::Conditional;
+use skibidi_http::server::config::ServerConfig;
+use skibidi_http::server::cors::{AllowOrigin, Cors};
+use skibidi_http::server::extractors::{Extension, ExtractError, Json};
+use skibidi_http::server::jwt::Jwt;
+use skibidi_http::server::logging::{LogFormat, Logging};
+use skibidi_http::server::middleware::{from_fn, Next};
+use skibidi_http::server::negotiate::{Negotiate, Variant};
+use skibidi_http::server::request_id::RequestId;
+use skibidi_http::server::router::{MatchedPath, Route, Router};
+use skibidi_http::server::serve_dir::ServeDir;
 use skibidi_http::server::server::Server;
+use skibidi_http::server::zip::ZipDownload;
 use std::collections::HashMap;
-use std::fs;
+use std::time::Duration;
 use tokio::net::TcpListener;
 
 use skibidi_http::client::client::{Method, Request};
 use skibidi_http::into_response::{HandlerError, Response, ResponseBuilder};
-use skibidi_http::{HandlerTypes, IntoResponse};
+use skibidi_http::{ErrorFormat, HandlerTypes, IntoResponse, Redirect, StatusCode};
 
 // shit without macros is pain
 #[tokio::main(flavor = "multi_thread", worker_threads = 8)]
 async fn main() {
     let listener = TcpListener::bind("127.0.0.1:4221").await.unwrap();
+    // routes of their own, mounted under /api further down
+    let api = Router::builder()
+        .route("/status", HandlerTypes::empty(|| "ok", Method::GET))
+        .route("/items/{id}", HandlerTypes::params(item, Method::GET))
+        // bearer token signed with the shared dev secret
+        .route(
+            "/me",
+            Route::new(HandlerTypes::full(me, Method::GET))
+                .layer(Jwt::<Claims>::new().hs256(b"dev secret").issuer("skibidi")),
+        )
+        .layer(from_fn(api_version))
+        .build();
+    // everything below /admin wants a password
+    let admin = Router::builder()
+        .route("/whoami", HandlerTypes::full(whoami, Method::GET))
+        .layer(BasicAuth::user("admin", "admin", "hunter2"))
+        .build();
+
     // building the router
     let router = Router::builder()
         //why do i have to suffer through lack of specialization in stable rust
@@ -27,21 +58,61 @@ async fn main() {
             HandlerTypes::params(respond_with_body_handler, Method::POST),
         )
         .route("/empty", HandlerTypes::empty(test_hander, Method::POST))
+        // static files, streamed from disk
+        .serve_dir("/files", ServeDir::new("./").directory_listing(true))
+        // gzip, deflate, br or zstd compressed JSON is decoded on the way in
+        .route("/json", HandlerTypes::body(count_keys, Method::POST))
+        // the served directory as one zip, built while it downloads
+        .route("/files.zip", HandlerTypes::empty(download_all, Method::GET))
+        // JSON, HTML or plain text, whatever the Accept header prefers
+        .route("/greeting", HandlerTypes::full(greeting, Method::GET))
         .route(
-            "/files/{filename}",
-            HandlerTypes::params(respond_with_file, Method::POST),
+            "/old",
+            HandlerTypes::empty(|| Redirect::permanent("/"), Method::GET),
         )
         .route("/ill", HandlerTypes::empty(complicated, Method::GET))
+        // big uploads get turned away before the client sends the body
+        .route(
+            "/upload",
+            Route::new(HandlerTypes::body(upload, Method::POST)).expect_continue(|request| {
+                match request.get_header("Content-Type") {
+                    Some("application/octet-stream") => Ok(()),
+                    _ => Err(StatusCode::EXPECTATION_FAILED),
+                }
+            }),
+        )
+        .nest("/api", api)
+        .nest("/admin", admin)
+        // wraps every route, nested ones included
+        .layer(from_fn(response_time))
+        // the frontend dev server may call us, preflights included
+        .layer(
+            Cors::new()
+                .allow_origin(AllowOrigin::exact("http://localhost:3000"))
+                .allow_methods([Method::GET, Method::POST, Method::PUT])
+                .allow_headers(["Content-Type"])
+                .expose_headers(["X-Request-Id"])
+                .max_age(Duration::from_secs(600)),
+        )
+        // 304 and 412 for conditional requests to any route
+        .conditional(Conditional::new())
+        // gzip, deflate, brotli or zstd, whatever the client likes best
+        .compression(Compression::new())
+        // X-Request-Id on every response and log line
+        .request_id(RequestId::new())
         .build();
 
     let service = router.into_service();
-    let mut server = Server::new(listener, service);
+    let config = ServerConfig::new()
+        .error_format(ErrorFormat::ProblemJson)
+        .logging(Logging::new().format(LogFormat::Combined));
+    let mut server = Server::new(listener, service).config(config);
     server.serve().await.unwrap();
 }
 
 fn simple_handler(request: Request) -> Result<Response, HandlerError> {
     if let Some(param) = request.headers.values().next() {
-        return Ok(param.clone().into_response());
+        return Ok(param.to_string().into_response());
     }
     Ok(().into_response())
 }
@@ -58,7 +129,7 @@ fn user_agent(request: Request) -> Result<Response, HandlerError> {
 
 // responding with a type implementing IntoResponse trait
 fn test_hander() -> impl IntoResponse {
-    HandlerError::MainHandlerError
+    HandlerError::new(StatusCode::SERVICE_UNAVAILABLE, "try again later").code("maintenance")
 }
 
 // acessing path parameter
@@ -66,22 +137,78 @@ fn respond_with_body_handler(map: HashMap<String, String>) -> Result<Response, H
     let body = map.get("str");
     match body {
         Some(bod) => Ok(bod.clone().into_response()),
-        None => Err(HandlerError::MainHandlerError),
+        None => Err(HandlerError::not_found("nothing to echo")),
+    }
+}
+
+#[derive(Clone, Deserialize)]
+struct Claims {
+    sub: String,
+}
+
+// the claims of the verified token
+fn me(request: Request) -> Result<String, ExtractError> {
+    let Extension(claims) = request.extension::<Claims>()?;
+    Ok(format!("token for {}", claims.sub))
+}
+
+// the auth layer left the user in the extensions
+fn whoami(request: Request) -> Result<String, ExtractError> {
+    let Extension(BasicUser(name)) = request.extension::<BasicUser>()?;
+    Ok(format!("logged in as {}", name))
+}
+
+fn item(params: HashMap<String, String>) -> String {
+    format!("item {}", params.get("id").map_or("?", String::as_str))
+}
+
+async fn api_version(request: Request, next: Next) -> Response {
+    // the router left the pattern that matched in the extensions
+    let route = request.extensions().get::<MatchedPath>().cloned();
+    let mut response = next.run(request).await;
+    response.headers_mut().insert("X-Api-Version", "1");
+    if let Some(MatchedPath(route)) = route {
+        response.headers_mut().insert("X-Route", route);
     }
+    response
 }
 
-// converting to concrete Response to allow different types under the hood
-fn respond_with_file(map: HashMap<String, String>) -> Response {
-    let file_name = map.get("filename").unwrap();
-    let path = "./";
+async fn response_time(request: Request, next: Next) -> Response {
+    let start = std::time::Instant::now();
+    let mut response = next.run(request).await;
+    let elapsed = format!("{}us", start.elapsed().as_micros());
+    response.headers_mut().insert("X-Response-Time", elapsed);
+    response
+}
 
-    let file_path = format!("{}{}", path, file_name);
-    match fs::read(&file_path) {
-        Ok(contents) => (StatusCode::ALL_OK, contents).into_response(), // (StatusCode, Vec<u8>)
-        Err(_) => (StatusCode::NOT_FOUND, "pozdro nie ma tu wstepu").into_response(), // (StatusCode, &str)
+// the body is only read once this handler is picked
+fn upload(request: Request) -> String {
+    let size = request.body.map(|body| body.len()).unwrap_or(0);
+    format!("received {} bytes", size)
+}
+
+// the extractors decompress the body and answer 400, 413 or 415 on their own
+fn count_keys(request: Request) -> Response {
+    match request.json::<HashMap<String, serde_json::Value>>() {
+        Ok(Json(object)) => format!("{} keys", object.len()).into_response(),
+        Err(e) => e.into_response(),
     }
 }
 
+// io errors turn into 404, 403 or 500, the details only go to the log
+fn download_all() -> Result<ZipDownload, HandlerError> {
+    Ok(ZipDownload::new("files.zip").directory("files", "./")?)
+}
+
+fn greeting(request: Request) -> Response {
+    Negotiate::new()
+        .offer("application/json", || r#"{"greeting":"hello"}"#)
+        .offer("text/html", || "<p>hello</p>")
+        .variant(Variant::new("text/plain", || "hello").language("en"))
+        .variant(Variant::new("text/plain", || "czesc").language("pl"))
+        .respond(&request)
+}
+
 // using builder
 fn complicated() -> Response {
     ResponseBuilder::new()
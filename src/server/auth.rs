@@ -0,0 +1,378 @@
+use std::sync::Arc;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+
+use super::middleware::{from_fn, BoxService, Layer, Next};
+use crate::client::client::Request;
+use crate::extensions::Extensions;
+use crate::into_response::{HandlerError, Response};
+use crate::status_code::StatusCode;
+use crate::IntoResponse;
+
+// checks the credentials and puts whatever the verifier made of them into the
+// request's extensions
+type Verify<C> = Arc<dyn Fn(&C, &mut Extensions) -> bool + Send + Sync + 'static>;
+
+/// Username of a request `BasicAuth` let through, in its extensions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BasicUser(pub String);
+
+/// Token of a request `BearerAuth` let through, in its extensions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BearerToken(pub String);
+
+/// Why a request wasn't let through.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    #[error("authentication required")]
+    Missing,
+    #[error("invalid credentials")]
+    Invalid,
+}
+
+/// What a `Basic` authorization header carries.
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// Compares two secrets in time depending only on their lengths, so an
+/// attacker can't find a password byte by byte. For verifiers to use.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
+/// `Authorization: Basic` authentication. Requests with credentials the
+/// verifier accepts go on with a `BasicUser` and the verifier's principal in
+/// their extensions, everything else gets a `401` with a `Basic` challenge.
+/// Add `Cors` before it, preflights never carry credentials.
+///
+/// ```ignore
+/// let admin = Router::builder()
+///     .route("/stats", HandlerTypes::full(stats, Method::GET))
+///     .layer(BasicAuth::new("admin", |credentials| {
+///         let user = users.get(&credentials.username)?;
+///         constant_time_eq(user.password.as_bytes(), credentials.password.as_bytes())
+///             .then(|| user.clone())
+///     }))
+///     .build();
+///
+/// fn stats(request: Request) -> Result<String, ExtractError> {
+///     let Extension(user) = request.extension::<User>()?;
+///     ...
+/// }
+/// ```
+#[derive(Clone)]
+pub struct BasicAuth {
+    realm: String,
+    verify: Verify<Credentials>,
+}
+
+impl BasicAuth {
+    /// `verify` returns the principal for credentials it accepts
+    pub fn new<F, P>(realm: &str, verify: F) -> Self
+    where
+        F: Fn(&Credentials) -> Option<P> + Send + Sync + 'static,
+        P: Clone + Send + Sync + 'static,
+    {
+        Self {
+            realm: realm.to_string(),
+            verify: principal(verify),
+        }
+    }
+
+    /// a single fixed user, the principal is its `BasicUser`
+    pub fn user(realm: &str, username: &str, password: &str) -> Self {
+        let (username, password) = (username.to_string(), password.to_string());
+        Self::new(realm, move |credentials| {
+            // both compared either way, a wrong name takes as long as a wrong password
+            let name = constant_time_eq(credentials.username.as_bytes(), username.as_bytes());
+            let pass = constant_time_eq(credentials.password.as_bytes(), password.as_bytes());
+            (name & pass).then(|| BasicUser(username.clone()))
+        })
+    }
+
+    /// Checks the credentials of `request`, an undecodable header counts as
+    /// missing.
+    pub fn authenticate(&self, request: &mut Request) -> Result<(), AuthError> {
+        let credentials = authorization(request, "Basic").and_then(decode_basic);
+        let credentials = credentials.ok_or(AuthError::Missing)?;
+        if !(self.verify)(&credentials, request.extensions_mut()) {
+            return Err(AuthError::Invalid);
+        }
+        request
+            .extensions_mut()
+            .insert(BasicUser(credentials.username));
+        Ok(())
+    }
+
+    /// the `401` for a request that wasn't let through
+    pub fn challenge(&self, error: AuthError) -> Response {
+        let challenge = format!("Basic realm={}, charset=\"UTF-8\"", quote(&self.realm));
+        HandlerError::new(StatusCode::UNAUTHORIZED, error.to_string())
+            .header("WWW-Authenticate", &challenge)
+            .into_response()
+    }
+}
+
+/// `Authorization: Bearer` authentication, RFC 6750. Requests with a token the
+/// verifier accepts go on with a `BearerToken` and the verifier's principal in
+/// their extensions, everything else gets a `401` with a `Bearer` challenge.
+///
+/// ```ignore
+/// let api = Router::builder()
+///     .route("/me", HandlerTypes::full(me, Method::GET))
+///     .layer(BearerAuth::new("api", |token| sessions.lookup(token)))
+///     .build();
+/// ```
+#[derive(Clone)]
+pub struct BearerAuth {
+    realm: String,
+    verify: Verify<str>,
+}
+
+impl BearerAuth {
+    /// `verify` returns the principal for tokens it accepts
+    pub fn new<F, P>(realm: &str, verify: F) -> Self
+    where
+        F: Fn(&str) -> Option<P> + Send + Sync + 'static,
+        P: Clone + Send + Sync + 'static,
+    {
+        Self {
+            realm: realm.to_string(),
+            verify: principal(verify),
+        }
+    }
+
+    /// a single fixed token, the principal is its `BearerToken`
+    pub fn token(realm: &str, token: &str) -> Self {
+        let token = token.to_string();
+        Self::new(realm, move |given| {
+            constant_time_eq(given.as_bytes(), token.as_bytes()).then(|| BearerToken(token.clone()))
+        })
+    }
+
+    pub fn authenticate(&self, request: &mut Request) -> Result<(), AuthError> {
+        let token = authorization(request, "Bearer").map(str::to_string);
+        let token = token.ok_or(AuthError::Missing)?;
+        if token.is_empty() || !(self.verify)(&token, request.extensions_mut()) {
+            return Err(AuthError::Invalid);
+        }
+        request.extensions_mut().insert(BearerToken(token));
+        Ok(())
+    }
+
+    /// the `401` for a request that wasn't let through
+    pub fn challenge(&self, error: AuthError) -> Response {
+        let mut challenge = format!("Bearer realm={}", quote(&self.realm));
+        // no error code when there were no credentials at all, RFC 6750 3.1
+        if error == AuthError::Invalid {
+            challenge.push_str(", error=\"invalid_token\"");
+        }
+        HandlerError::new(StatusCode::UNAUTHORIZED, error.to_string())
+            .header("WWW-Authenticate", &challenge)
+            .into_response()
+    }
+}
+
+impl Layer for BasicAuth {
+    fn layer(&self, inner: BoxService) -> BoxService {
+        let auth = self.clone();
+        from_fn(move |mut request: Request, next: Next| {
+            let checked = auth
+                .authenticate(&mut request)
+                .map_err(|error| auth.challenge(error));
+            async move {
+                match checked {
+                    Ok(()) => next.run(request).await,
+                    Err(challenge) => challenge,
+                }
+            }
+        })
+        .layer(inner)
+    }
+}
+
+impl Layer for BearerAuth {
+    fn layer(&self, inner: BoxService) -> BoxService {
+        let auth = self.clone();
+        from_fn(move |mut request: Request, next: Next| {
+            let checked = auth
+                .authenticate(&mut request)
+                .map_err(|error| auth.challenge(error));
+            async move {
+                match checked {
+                    Ok(()) => next.run(request).await,
+                    Err(challenge) => challenge,
+                }
+            }
+        })
+        .layer(inner)
+    }
+}
+
+// the principal of accepted credentials goes into the extensions
+fn principal<C, P, F>(verify: F) -> Verify<C>
+where
+    C: ?Sized,
+    F: Fn(&C) -> Option<P> + Send + Sync + 'static,
+    P: Clone + Send + Sync + 'static,
+{
+    Arc::new(move |credentials, extensions| match verify(credentials) {
+        Some(principal) => {
+            extensions.insert(principal);
+            true
+        }
+        None => false,
+    })
+}
+
+// the credentials after `scheme`, which is matched case insensitively
+pub(crate) fn authorization<'a>(request: &'a Request, scheme: &str) -> Option<&'a str> {
+    let value = request.get_header("Authorization")?.trim();
+    let (given, credentials) = value.split_once(' ')?;
+    given
+        .eq_ignore_ascii_case(scheme)
+        .then(|| credentials.trim())
+}
+
+fn decode_basic(encoded: &str) -> Option<Credentials> {
+    let decoded = STANDARD.decode(encoded).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    // passwords may contain colons, user ids can't
+    let (username, password) = decoded.split_once(':')?;
+    Some(Credentials {
+        username: username.to_string(),
+        password: password.to_string(),
+    })
+}
+
+// quoted-string for challenge parameters
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::client::Method;
+
+    fn request(authorization: Option<&str>) -> Request {
+        let headers: Vec<_> = authorization
+            .map(|value| ("Authorization", value))
+            .into_iter()
+            .collect();
+        Request::with_headers(Method::GET, "/", &headers)
+    }
+
+    fn basic(credentials: &str) -> Request {
+        request(Some(&format!("Basic {}", STANDARD.encode(credentials))))
+    }
+
+    fn challenge(response: &Response) -> &str {
+        assert_eq!(response.status_code(), StatusCode::UNAUTHORIZED);
+        response.headers().get("WWW-Authenticate").unwrap()
+    }
+
+    #[test]
+    fn basic_accepts_the_right_user() {
+        let auth = BasicAuth::user("admin", "root", "pa:ss");
+        let mut request = basic("root:pa:ss");
+        assert_eq!(auth.authenticate(&mut request), Ok(()));
+        let user = request.extensions().get::<BasicUser>();
+        assert_eq!(user, Some(&BasicUser("root".to_string())));
+    }
+
+    #[test]
+    fn basic_rejects_wrong_or_missing_credentials() {
+        let auth = BasicAuth::user("admin", "root", "secret");
+        let wrong = auth.authenticate(&mut basic("root:guess"));
+        assert_eq!(wrong, Err(AuthError::Invalid));
+        assert_eq!(
+            auth.authenticate(&mut basic("admin:secret")),
+            Err(AuthError::Invalid)
+        );
+        assert_eq!(
+            auth.authenticate(&mut request(None)),
+            Err(AuthError::Missing)
+        );
+        // not base64, no colon, another scheme
+        for header in ["Basic !!!", "Basic cm9vdA==", "Bearer cm9vdDpzZWNyZXQ="] {
+            let error = auth.authenticate(&mut request(Some(header)));
+            assert_eq!(error, Err(AuthError::Missing), "{}", header);
+        }
+        // the scheme is case insensitive
+        let lower = format!("basic {}", STANDARD.encode("root:secret"));
+        assert_eq!(auth.authenticate(&mut request(Some(&lower))), Ok(()));
+    }
+
+    #[test]
+    fn basic_verifiers_provide_the_principal() {
+        #[derive(Clone, Debug, PartialEq)]
+        struct Admin(u32);
+        let auth = BasicAuth::new("admin", |credentials| {
+            (credentials.username == "ferris").then_some(Admin(7))
+        });
+        let mut request = basic("ferris:");
+        assert_eq!(auth.authenticate(&mut request), Ok(()));
+        assert_eq!(request.extensions().get::<Admin>(), Some(&Admin(7)));
+    }
+
+    #[test]
+    fn basic_challenge() {
+        let auth = BasicAuth::user("the \"admin\" area", "a", "b");
+        let response = auth.challenge(AuthError::Missing);
+        assert_eq!(
+            challenge(&response),
+            "Basic realm=\"the \\\"admin\\\" area\", charset=\"UTF-8\""
+        );
+    }
+
+    #[test]
+    fn bearer_accepts_the_right_token() {
+        let auth = BearerAuth::token("api", "s3cret");
+        let mut request = request(Some("Bearer  s3cret "));
+        assert_eq!(auth.authenticate(&mut request), Ok(()));
+        let token = request.extensions().get::<BearerToken>();
+        assert_eq!(token, Some(&BearerToken("s3cret".to_string())));
+    }
+
+    #[test]
+    fn bearer_rejects_wrong_empty_or_missing_tokens() {
+        let auth = BearerAuth::token("api", "s3cret");
+        let wrong = auth.authenticate(&mut request(Some("Bearer guess")));
+        assert_eq!(wrong, Err(AuthError::Invalid));
+        // nothing after the scheme is no credentials at all
+        let empty = auth.authenticate(&mut request(Some("Bearer ")));
+        assert_eq!(empty, Err(AuthError::Missing));
+        assert_eq!(
+            auth.authenticate(&mut request(None)),
+            Err(AuthError::Missing)
+        );
+        let basic = auth.authenticate(&mut request(Some("Basic s3cret")));
+        assert_eq!(basic, Err(AuthError::Missing));
+    }
+
+    #[test]
+    fn bearer_challenges() {
+        let auth = BearerAuth::token("api", "s3cret");
+        let missing = auth.challenge(AuthError::Missing);
+        assert_eq!(challenge(&missing), "Bearer realm=\"api\"");
+        let invalid = auth.challenge(AuthError::Invalid);
+        assert_eq!(
+            challenge(&invalid),
+            "Bearer realm=\"api\", error=\"invalid_token\""
+        );
+    }
+
+    #[test]
+    fn constant_time_comparison() {
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"abcd"));
+    }
+}
@@ -0,0 +1,507 @@
+use std::io::{self, Write};
+use std::mem;
+
+use bytes::Bytes;
+use futures_util::stream::{self, StreamExt};
+
+use super::conditional::NotModified;
+use super::middleware::{from_fn, BoxService, Layer, Next};
+use crate::body::{Body, BodyStream, BoxError};
+use crate::client::client::Request;
+use crate::header::{add_vary, HeaderMap};
+use crate::into_response::Response;
+use crate::status_code::StatusCode;
+
+// content types that are compressed already, running them through a codec
+// again only burns cpu
+const COMPRESSED_TYPES: [&str; 14] = [
+    "image/",
+    "video/",
+    "audio/",
+    "font/woff",
+    "application/zip",
+    "application/gzip",
+    "application/x-gzip",
+    "application/zstd",
+    "application/x-bzip2",
+    "application/x-xz",
+    "application/x-7z-compressed",
+    "application/x-rar-compressed",
+    "application/vnd.rar",
+    "application/octet-stream",
+];
+
+/// Content codings we can produce, in the order we prefer them when the client
+/// likes several equally.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    /// the coding named by a `Content-Encoding` or `Accept-Encoding` token
+    pub fn from_token(token: &str) -> Option<Self> {
+        let token = token.trim();
+        [
+            Encoding::Brotli,
+            Encoding::Zstd,
+            Encoding::Gzip,
+            Encoding::Deflate,
+        ]
+        .into_iter()
+        .find(|e| e.as_str().eq_ignore_ascii_case(token))
+        // x-gzip is the same coding under its old name
+        .or_else(|| {
+            token
+                .eq_ignore_ascii_case("x-gzip")
+                .then_some(Encoding::Gzip)
+        })
+    }
+}
+
+/// Compresses response bodies with the best coding the client accepts, judging
+/// by the q-values of its `Accept-Encoding`. Buffered bodies are compressed in
+/// one go, streams chunk by chunk as they are written. Bodies below
+/// `min_size`, content types that are compressed already, partial content and
+/// responses marked `no-transform` go out as they are. Opt in with
+/// `RouterBuilder::compression`, or use it as a `Layer`.
+#[derive(Clone, Debug)]
+pub struct Compression {
+    encodings: Vec<Encoding>,
+    min_size: usize,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            encodings: vec![
+                Encoding::Brotli,
+                Encoding::Zstd,
+                Encoding::Gzip,
+                Encoding::Deflate,
+            ],
+            min_size: 1024,
+        }
+    }
+}
+
+impl Compression {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// codings to offer, earlier ones win when the client has no preference
+    pub fn encodings(mut self, encodings: &[Encoding]) -> Self {
+        self.encodings = encodings.to_vec();
+        self
+    }
+
+    /// bodies smaller than this are not worth the framing overhead
+    pub fn min_size(mut self, bytes: usize) -> Self {
+        self.min_size = bytes;
+        self
+    }
+
+    pub fn apply(&self, request_headers: &HeaderMap, mut response: Response) -> Response {
+        let chosen = request_headers
+            .get("Accept-Encoding")
+            .and_then(|accept| negotiate(accept, &self.encodings));
+
+        let status = response.status_code();
+        if status == StatusCode::NOT_MODIFIED {
+            // the tag has to match the one the 200 carried, which only got
+            // one when it was compressed
+            let stood_for = response.extensions().get::<NotModified>();
+            let headers = stood_for.map_or(response.headers(), |stood_for| &stood_for.0);
+            let compressible = !response.headers().contains_key("Content-Encoding")
+                && self.compressible(StatusCode::OK, headers, None);
+            if compressible {
+                if let Some(encoding) = chosen {
+                    tag_etag(response.headers_mut(), encoding);
+                }
+                add_vary(response.headers_mut(), "Accept-Encoding");
+            }
+            return response;
+        }
+        let length = response.body().len();
+        if !self.compressible(status, response.headers(), length) {
+            return response;
+        }
+        // whether or not this client gets a compressed body, the next one may
+        add_vary(response.headers_mut(), "Accept-Encoding");
+        let Some(encoding) = chosen else {
+            return response;
+        };
+
+        let body = mem::take(response.body_mut());
+        let body = match body {
+            Body::Full(bytes) => match compress(encoding, &bytes) {
+                Ok(compressed) => Body::from(compressed),
+                Err(_) => {
+                    *response.body_mut() = Body::Full(bytes);
+                    return response;
+                }
+            },
+            Body::Stream(stream) => compress_stream(encoding, stream),
+            Body::Empty => Body::Empty,
+        };
+        *response.body_mut() = body;
+
+        let headers = response.headers_mut();
+        headers.insert("Content-Encoding", encoding.as_str());
+        // the encoded length is not known up front, ranges would have to apply
+        // to the encoded bytes
+        headers.remove("Content-Length");
+        headers.remove("Accept-Ranges");
+        tag_etag(headers, encoding);
+        response
+    }
+
+    // `length` of the body when it is buffered, the `Content-Length` is used
+    // otherwise
+    fn compressible(&self, status: StatusCode, headers: &HeaderMap, length: Option<usize>) -> bool {
+        if status.is_informational()
+            || status == StatusCode::NO_CONTENT
+            || status == StatusCode::PARTIAL_CONTENT
+        {
+            return false;
+        }
+
+        if headers.contains_key("Content-Encoding") {
+            return false;
+        }
+        let no_transform = headers
+            .get_all("Cache-Control")
+            .flat_map(|v| v.split(','))
+            .any(|d| d.trim().eq_ignore_ascii_case("no-transform"));
+        if no_transform {
+            return false;
+        }
+        if headers.get("Content-Type").is_some_and(is_compressed_type) {
+            return false;
+        }
+
+        let length = length.or_else(|| headers.get("Content-Length").and_then(|v| v.parse().ok()));
+        match length {
+            Some(length) => length >= self.min_size,
+            // a stream of unknown length, likely not a tiny one
+            None => true,
+        }
+    }
+}
+
+impl Layer for Compression {
+    fn layer(&self, inner: BoxService) -> BoxService {
+        let compression = self.clone();
+        from_fn(move |request: Request, next: Next| {
+            let compression = compression.clone();
+            async move {
+                let headers = request.headers.clone();
+                let response = next.run(request).await;
+                compression.apply(&headers, response)
+            }
+        })
+        .layer(inner)
+    }
+}
+
+/// Whether content of this type is compressed already, unknown binary data is
+/// assumed to be.
+pub(crate) fn is_compressed_type(content_type: &str) -> bool {
+    let content_type = content_type.trim().to_ascii_lowercase();
+    COMPRESSED_TYPES.iter().any(|t| content_type.starts_with(t))
+        && !content_type.starts_with("image/svg")
+}
+
+/// The coding out of `supported` the client rates highest in its
+/// `Accept-Encoding`, `None` when it accepts none of them or prefers identity.
+pub fn negotiate(accept: &str, supported: &[Encoding]) -> Option<Encoding> {
+    let mut best: Option<(f32, Encoding)> = None;
+    for &encoding in supported {
+        let q = accepted_quality(accept, encoding.as_str());
+        if q > 0.0 && best.map_or(true, |(best_q, _)| q > best_q) {
+            best = Some((q, encoding));
+        }
+    }
+    let (q, encoding) = best?;
+    // identity is acceptable unless the client ruled it out, ties go to compression
+    if accepted_quality_explicit(accept, "identity").is_some_and(|identity| identity > q) {
+        return None;
+    }
+    Some(encoding)
+}
+
+/// q-value `Accept-Encoding` gives `coding`, 0 when it isn't accepted.
+pub(crate) fn accepted_quality(accept: &str, coding: &str) -> f32 {
+    let gzip_alias = coding.eq_ignore_ascii_case("gzip");
+    accepted_quality_explicit(accept, coding)
+        .or_else(|| {
+            gzip_alias
+                .then(|| accepted_quality_explicit(accept, "x-gzip"))
+                .flatten()
+        })
+        .or_else(|| accepted_quality_explicit(accept, "*"))
+        .unwrap_or(0.0)
+}
+
+fn accepted_quality_explicit(accept: &str, coding: &str) -> Option<f32> {
+    accept.split(',').find_map(|item| {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim();
+        if !name.eq_ignore_ascii_case(coding) {
+            return None;
+        }
+        let q = parts
+            .find_map(|p| p.trim().strip_prefix("q="))
+            .and_then(|q| q.trim().parse().ok())
+            .unwrap_or(1.0);
+        Some(q)
+    })
+}
+
+// each coding is a representation of its own and needs a tag of its own,
+// `"abc"` becomes `"abc-gzip"`
+fn tag_etag(headers: &mut HeaderMap, encoding: Encoding) {
+    let Some(etag) = headers.get("ETag") else {
+        return;
+    };
+    if let Some(opaque) = etag.strip_suffix('"') {
+        let suffix = format!("-{}\"", encoding.as_str());
+        if !etag.ends_with(&suffix) {
+            let tagged = format!("{}{}", opaque, suffix);
+            headers.insert("ETag", tagged);
+        }
+    }
+}
+
+/// The tag without what `tag_etag` appended, so a client revalidating the
+/// compressed representation matches the plain one.
+pub(crate) fn untagged_etag(etag: &str) -> String {
+    for encoding in [
+        Encoding::Brotli,
+        Encoding::Zstd,
+        Encoding::Gzip,
+        Encoding::Deflate,
+    ] {
+        let suffix = format!("-{}\"", encoding.as_str());
+        if let Some(opaque) = etag.strip_suffix(suffix.as_str()) {
+            return format!("{}\"", opaque);
+        }
+    }
+    etag.to_string()
+}
+
+fn compress(encoding: Encoding, input: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = Encoder::new(encoding)?;
+    encoder.write(input)?;
+    encoder.finish()
+}
+
+// feeds the stream through the encoder, flushing after every chunk so a slow
+// stream reaches the client as it is produced and not once the codec's window
+// filled up
+fn compress_stream(encoding: Encoding, body: BodyStream) -> Body {
+    let encoder = match Encoder::new(encoding) {
+        Ok(encoder) => encoder,
+        Err(e) => return Body::from_stream(stream::once(async { Err::<Bytes, _>(e) })),
+    };
+    // the state is dropped after the last chunk or the first error
+    let chunks = stream::unfold(Some((body, encoder)), |state| async move {
+        let (mut body, mut encoder) = state?;
+        loop {
+            match body.next().await {
+                Some(Ok(chunk)) => match encoder.write(&chunk).and_then(|_| encoder.flush()) {
+                    Ok(out) if out.is_empty() => continue,
+                    Ok(out) => return Some((Ok(Bytes::from(out)), Some((body, encoder)))),
+                    Err(e) => return Some((Err(BoxError::from(e)), None)),
+                },
+                Some(Err(e)) => return Some((Err(e), None)),
+                None => {
+                    let tail = encoder.finish().map(Bytes::from).map_err(BoxError::from);
+                    return Some((tail, None));
+                }
+            }
+        }
+    });
+    Body::from_stream(chunks)
+}
+
+// the codecs write into a Vec that is emptied after every call
+enum Encoder {
+    Gzip(flate2::write::GzEncoder<Vec<u8>>),
+    Deflate(flate2::write::ZlibEncoder<Vec<u8>>),
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> io::Result<Self> {
+        let level = flate2::Compression::default();
+        Ok(match encoding {
+            Encoding::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(Vec::new(), level)),
+            // "deflate" in HTTP means the zlib format, not a raw deflate stream
+            Encoding::Deflate => {
+                Encoder::Deflate(flate2::write::ZlibEncoder::new(Vec::new(), level))
+            }
+            // quality 5 and a 4 MiB window, fast enough to do on every response
+            Encoding::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(
+                Vec::new(),
+                4096,
+                5,
+                22,
+            ))),
+            Encoding::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(Vec::new(), 3)?),
+        })
+    }
+
+    // compresses `input`, whatever the codec already let go of can be taken
+    // with `flush` or `finish`
+    fn write(&mut self, input: &[u8]) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(e) => e.write_all(input),
+            Encoder::Deflate(e) => e.write_all(input),
+            Encoder::Brotli(e) => e.write_all(input),
+            Encoder::Zstd(e) => e.write_all(input),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<Vec<u8>> {
+        let out = match self {
+            Encoder::Gzip(e) => {
+                e.flush()?;
+                e.get_mut()
+            }
+            Encoder::Deflate(e) => {
+                e.flush()?;
+                e.get_mut()
+            }
+            Encoder::Brotli(e) => {
+                e.flush()?;
+                e.get_mut()
+            }
+            Encoder::Zstd(e) => {
+                e.flush()?;
+                e.get_mut()
+            }
+        };
+        Ok(mem::take(out))
+    }
+
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(e) => e.finish(),
+            Encoder::Deflate(e) => e.finish(),
+            Encoder::Brotli(e) => Ok(e.into_inner()),
+            Encoder::Zstd(e) => e.finish(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::client::Method;
+    use crate::server::conditional::Conditional;
+    use crate::IntoResponse;
+
+    fn accept(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("Accept-Encoding", value);
+        headers
+    }
+
+    fn text(size: usize) -> Response {
+        let mut response = "x".repeat(size).into_response();
+        response.headers_mut().insert("ETag", "\"v1\"");
+        response
+    }
+
+    // the 304 the conditional layer makes of `response` for a client holding
+    // the gzip variant
+    fn revalidated(response: Response) -> Response {
+        let mut request = accept("gzip");
+        request.insert("If-None-Match", "\"v1-gzip\"");
+        let response = Conditional::new().apply(&Method::GET, &request, response);
+        assert_eq!(response.status_code(), StatusCode::NOT_MODIFIED);
+        Compression::new().apply(&request, response)
+    }
+
+    #[test]
+    fn compresses_big_enough_text() {
+        let response = Compression::new().apply(&accept("gzip, br;q=0.5"), text(4096));
+        let headers = response.headers();
+        assert_eq!(headers.get("Content-Encoding"), Some("gzip"));
+        assert_eq!(headers.get("ETag"), Some("\"v1-gzip\""));
+        assert!(headers.contains_token("Vary", "Accept-Encoding"));
+        assert!(response.body().len().unwrap() < 4096);
+    }
+
+    #[test]
+    fn leaves_small_and_compressed_bodies_alone() {
+        let compression = Compression::new();
+        let small = compression.apply(&accept("gzip"), text(10));
+        assert_eq!(small.headers().get("Content-Encoding"), None);
+        assert_eq!(small.headers().get("Vary"), None);
+
+        let mut image = text(4096);
+        image.headers_mut().insert("Content-Type", "image/png");
+        let image = compression.apply(&accept("gzip"), image);
+        assert_eq!(image.headers().get("Content-Encoding"), None);
+        assert_eq!(image.headers().get("ETag"), Some("\"v1\""));
+    }
+
+    #[test]
+    fn not_modified_is_tagged_like_the_compressed_200() {
+        let response = revalidated(text(4096));
+        assert_eq!(response.headers().get("ETag"), Some("\"v1-gzip\""));
+        assert!(response.headers().contains_token("Vary", "Accept-Encoding"));
+    }
+
+    #[test]
+    fn not_modified_keeps_the_tag_of_an_uncompressed_200() {
+        let small = revalidated(text(10));
+        assert_eq!(small.headers().get("ETag"), Some("\"v1\""));
+
+        let mut image = text(4096);
+        image.headers_mut().insert("Content-Type", "image/png");
+        let image = revalidated(image);
+        assert_eq!(image.headers().get("ETag"), Some("\"v1\""));
+        assert_eq!(image.headers().get("Vary"), None);
+    }
+
+    #[test]
+    fn picks_the_coding_the_client_rates_highest() {
+        let supported = Compression::new().encodings;
+        assert_eq!(negotiate("gzip, br", &supported), Some(Encoding::Brotli));
+        assert_eq!(
+            negotiate("gzip;q=1, br;q=0.5", &supported),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(negotiate("x-gzip", &supported), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*;q=0.1, identity", &supported), None);
+        assert_eq!(negotiate("br;q=0, gzip;q=0", &supported), None);
+        assert_eq!(negotiate("", &supported), None);
+    }
+
+    #[test]
+    fn untagging_undoes_tagging() {
+        let mut headers = HeaderMap::new();
+        headers.insert("ETag", "W/\"abc\"");
+        tag_etag(&mut headers, Encoding::Zstd);
+        assert_eq!(headers.get("ETag"), Some("W/\"abc-zstd\""));
+        assert_eq!(untagged_etag("W/\"abc-zstd\""), "W/\"abc\"");
+        assert_eq!(untagged_etag("\"abc\""), "\"abc\"");
+    }
+}
@@ -0,0 +1,447 @@
+use std::fs::Metadata;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::compression::untagged_etag;
+use super::middleware::{from_fn, BoxService, Layer, Next};
+use crate::body::Body;
+use crate::client::client::{Method, Request};
+use crate::header::HeaderMap;
+use crate::into_response::{Response, ResponseBuilder};
+use crate::status_code::StatusCode;
+
+// headers a 304 keeps from the response it stands for
+const NOT_MODIFIED_HEADERS: [&str; 7] = [
+    "ETag",
+    "Last-Modified",
+    "Vary",
+    "Cache-Control",
+    "Expires",
+    "Content-Location",
+    "Date",
+];
+
+/// Evaluates `If-Match`, `If-None-Match`, `If-Modified-Since` and
+/// `If-Unmodified-Since` against successful responses and turns them into
+/// `304 Not Modified` or `412 Precondition Failed` when a precondition says so.
+/// Responses without an `ETag` get one hashed from their body, as long as the
+/// body is buffered. Opt in with `RouterBuilder::conditional`, or use it as a `Layer`.
+///
+/// Only `GET` and `HEAD` are handled this way. The validators come from the
+/// response, so the handler has already run by the time they are compared,
+/// which is too late for a `PUT` or `DELETE`. Handlers of unsafe methods call
+/// `Conditional::check` with the validators of what they are about to change
+/// before changing it.
+///
+/// ```ignore
+/// fn update(request: Request) -> Response {
+///     let current = store.get(id);
+///     if let Some(failed) = Conditional::check(&request, Some(&current.etag), None) {
+///         return failed;
+///     }
+///     store.put(id, ...);
+///     ...
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Conditional {
+    etag: Option<ETagKind>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ETagKind {
+    /// byte for byte identical bodies, the only kind `If-Range` and `If-Match` accept
+    Strong,
+    /// semantically equivalent bodies, `W/` prefixed
+    Weak,
+}
+
+/// Headers of the response a `304` stands for, `Content-Length` included, in
+/// the extensions of the `304`. Layers further out that would have changed
+/// that response go by them, compression tags the ETag only when it would
+/// have compressed the body.
+#[derive(Clone, Debug)]
+pub struct NotModified(pub HeaderMap);
+
+/// Validators of the representation a response carries.
+#[derive(Clone, Debug, Default)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<SystemTime>,
+}
+
+impl Default for Conditional {
+    fn default() -> Self {
+        Self {
+            etag: Some(ETagKind::Strong),
+        }
+    }
+}
+
+impl Conditional {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// kind of the generated ETags, `None` to only use the ones handlers set
+    pub fn etag(mut self, kind: Option<ETagKind>) -> Self {
+        self.etag = kind;
+        self
+    }
+
+    /// Evaluates the preconditions of `request` against the validators of
+    /// the current representation, before a handler acts on it. The `304` or
+    /// `412` to answer with, `None` when the handler goes on. Neither validator
+    /// means there is no current representation, `If-Match: *` fails then and
+    /// `If-None-Match: *` passes.
+    pub fn check(
+        request: &Request,
+        etag: Option<&str>,
+        last_modified: Option<SystemTime>,
+    ) -> Option<Response> {
+        let validators = Validators {
+            etag: etag.map(str::to_string),
+            last_modified,
+        };
+        let exists = etag.is_some() || last_modified.is_some();
+        let status = validators.evaluate_for(request.get_method(), &request.headers, exists)?;
+        let mut headers = HeaderMap::new();
+        if let Some(etag) = etag {
+            headers.insert("ETag", etag);
+        }
+        if let Some(modified) = last_modified {
+            headers.insert("Last-Modified", httpdate::fmt_http_date(modified));
+        }
+        Some(precondition_response(status, &headers))
+    }
+
+    /// Evaluates the preconditions of a `GET` or `HEAD` against `response`,
+    /// responses to other methods are returned as they are.
+    pub fn apply(&self, method: &Method, headers: &HeaderMap, mut response: Response) -> Response {
+        // the handler of anything else already acted, see `Conditional::check`
+        if !matches!(method, Method::GET | Method::HEAD) {
+            return response;
+        }
+        // preconditions only ever replace a success
+        if !response.status_code().is_success() {
+            return response;
+        }
+        if let (Some(kind), StatusCode::OK) = (self.etag, response.status_code()) {
+            if !response.headers().contains_key("ETag") {
+                if let Some(body) = response.body().as_bytes() {
+                    let etag = hashed_etag(body, kind);
+                    response.headers_mut().insert("ETag", etag);
+                }
+            }
+        }
+
+        match Validators::from_response(&response).evaluate(method, headers) {
+            Some(status) => {
+                let mut headers = response.headers().clone();
+                if let (Some(length), false) = (
+                    response.body().len(),
+                    headers.contains_key("Content-Length"),
+                ) {
+                    headers.insert("Content-Length", length.to_string());
+                }
+                precondition_response(status, &headers)
+            }
+            None => response,
+        }
+    }
+}
+
+impl Layer for Conditional {
+    fn layer(&self, inner: BoxService) -> BoxService {
+        let conditional = self.clone();
+        from_fn(move |request: Request, next: Next| {
+            let conditional = conditional.clone();
+            async move {
+                if !matches!(request.get_method(), Method::GET | Method::HEAD) {
+                    return next.run(request).await;
+                }
+                let method = request.get_method().clone();
+                let headers = request.headers.clone();
+                let response = next.run(request).await;
+                conditional.apply(&method, &headers, response)
+            }
+        })
+        .layer(inner)
+    }
+}
+
+impl Validators {
+    /// A strong tag built from size and modification time, what static files
+    /// are tagged with.
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        let last_modified = metadata.modified().ok();
+        let since = last_modified
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        let etag = format!(
+            "\"{:x}-{:x}.{:x}\"",
+            metadata.len(),
+            since.as_secs(),
+            since.subsec_nanos()
+        );
+        Self {
+            etag: Some(etag),
+            last_modified,
+        }
+    }
+
+    pub fn from_response(response: &Response) -> Self {
+        let headers = response.headers();
+        Self {
+            etag: headers.get("ETag").map(str::to_string),
+            last_modified: headers
+                .get("Last-Modified")
+                .and_then(|date| httpdate::parse_http_date(date).ok()),
+        }
+    }
+
+    /// The status a request's preconditions call for, in the order of RFC 9110
+    /// section 13.2.2, or `None` when the request goes on as usual.
+    pub fn evaluate(&self, method: &Method, headers: &HeaderMap) -> Option<StatusCode> {
+        self.evaluate_for(method, headers, true)
+    }
+
+    // `exists` is whether there is a current representation for `*` to match
+    fn evaluate_for(
+        &self,
+        method: &Method,
+        headers: &HeaderMap,
+        exists: bool,
+    ) -> Option<StatusCode> {
+        let safe = matches!(method, Method::GET | Method::HEAD);
+
+        if let Some(if_match) = headers.get("If-Match") {
+            if !self.tag_matches(if_match, true, exists) {
+                return Some(StatusCode::PRECONDITION_FAILED);
+            }
+        } else if let Some(since) = headers.get("If-Unmodified-Since") {
+            // an unparsable date is ignored, so is one we have nothing to compare with
+            if let (Some(modified), Ok(since)) =
+                (self.last_modified, httpdate::parse_http_date(since))
+            {
+                if whole_seconds(modified) > whole_seconds(since) {
+                    return Some(StatusCode::PRECONDITION_FAILED);
+                }
+            }
+        }
+
+        if let Some(if_none_match) = headers.get("If-None-Match") {
+            if self.tag_matches(if_none_match, false, exists) {
+                return Some(if safe {
+                    StatusCode::NOT_MODIFIED
+                } else {
+                    StatusCode::PRECONDITION_FAILED
+                });
+            }
+        } else if let Some(since) = headers.get("If-Modified-Since") {
+            if let (true, Some(modified), Ok(since)) =
+                (safe, self.last_modified, httpdate::parse_http_date(since))
+            {
+                if whole_seconds(modified) <= whole_seconds(since) {
+                    return Some(StatusCode::NOT_MODIFIED);
+                }
+            }
+        }
+        None
+    }
+
+    // `*` or a list of entity tags, compared strongly for If-Match and weakly
+    // for If-None-Match
+    fn tag_matches(&self, list: &str, strong: bool, exists: bool) -> bool {
+        if list.trim() == "*" {
+            return exists;
+        }
+        let Some(etag) = &self.etag else {
+            return false;
+        };
+        if strong && etag.starts_with("W/") {
+            return false;
+        }
+        // a weak comparison also accepts the tag of a compressed variant
+        let opaque = |tag: &str| {
+            let tag = tag.trim().trim_start_matches("W/");
+            if strong {
+                tag.to_string()
+            } else {
+                untagged_etag(tag)
+            }
+        };
+        list.split(',')
+            .map(str::trim)
+            .filter(|tag| !(strong && tag.starts_with("W/")))
+            .any(|tag| opaque(tag) == opaque(etag))
+    }
+}
+
+/// The `304` or `412` answer to a request whose preconditions decided for it,
+/// keeping the headers of `headers` a cache needs to update what it stored.
+/// A `304` carries all of `headers` as `NotModified` in its extensions.
+pub fn precondition_response(status: StatusCode, headers: &HeaderMap) -> Response {
+    let mut builder = ResponseBuilder::new().status_code(status);
+    if status == StatusCode::NOT_MODIFIED {
+        for (name, value) in headers.iter() {
+            if NOT_MODIFIED_HEADERS
+                .iter()
+                .any(|keep| keep.eq_ignore_ascii_case(name))
+            {
+                builder = builder.header((name, value));
+            }
+        }
+        builder = builder.extension(NotModified(headers.clone()));
+    }
+    builder.with_body(Body::Empty).build()
+}
+
+// FNV-1a over the body, quoted, the length goes in too to make collisions of
+// differently sized bodies impossible
+fn hashed_etag(body: &[u8], kind: ETagKind) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in body {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    let tag = format!("\"{:x}-{:016x}\"", body.len(), hash);
+    match kind {
+        ETagKind::Strong => tag,
+        ETagKind::Weak => format!("W/{}", tag),
+    }
+}
+
+// HTTP dates have no sub second part, neither may the comparison
+pub(crate) fn whole_seconds(time: SystemTime) -> Duration {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    Duration::from_secs(since.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::IntoResponse;
+
+    const ETAG: &str = "\"v2\"";
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, *value);
+        }
+        headers
+    }
+
+    fn validators() -> Validators {
+        Validators {
+            etag: Some(ETAG.to_string()),
+            last_modified: httpdate::parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").ok(),
+        }
+    }
+
+    fn evaluate(method: Method, pairs: &[(&str, &str)]) -> Option<StatusCode> {
+        validators().evaluate(&method, &headers(pairs))
+    }
+
+    #[test]
+    fn if_match_takes_precedence_over_if_unmodified_since() {
+        let stale = ("If-Unmodified-Since", "Sat, 05 Nov 1994 00:00:00 GMT");
+        assert_eq!(
+            evaluate(Method::PUT, &[stale]),
+            Some(StatusCode::PRECONDITION_FAILED)
+        );
+        assert_eq!(evaluate(Method::PUT, &[("If-Match", ETAG), stale]), None);
+        assert_eq!(
+            evaluate(Method::PUT, &[("If-Match", "\"v1\""), stale]),
+            Some(StatusCode::PRECONDITION_FAILED)
+        );
+        // If-Match compares strongly
+        assert_eq!(
+            evaluate(Method::PUT, &[("If-Match", "W/\"v2\"")]),
+            Some(StatusCode::PRECONDITION_FAILED)
+        );
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence_over_if_modified_since() {
+        let fresh = ("If-Modified-Since", "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(
+            evaluate(Method::GET, &[fresh]),
+            Some(StatusCode::NOT_MODIFIED)
+        );
+        assert_eq!(
+            evaluate(Method::GET, &[("If-None-Match", "\"v1\""), fresh]),
+            None
+        );
+        assert_eq!(
+            evaluate(Method::GET, &[("If-None-Match", "\"v1\", W/\"v2\"")]),
+            Some(StatusCode::NOT_MODIFIED)
+        );
+    }
+
+    #[test]
+    fn failed_if_match_wins_over_if_none_match() {
+        let pairs = [("If-Match", "\"v1\""), ("If-None-Match", ETAG)];
+        assert_eq!(
+            evaluate(Method::GET, &pairs),
+            Some(StatusCode::PRECONDITION_FAILED)
+        );
+    }
+
+    #[test]
+    fn unsafe_methods_fail_instead_of_not_modified() {
+        assert_eq!(
+            evaluate(Method::DELETE, &[("If-None-Match", "*")]),
+            Some(StatusCode::PRECONDITION_FAILED)
+        );
+        let fresh = ("If-Modified-Since", "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(evaluate(Method::POST, &[fresh]), None);
+    }
+
+    #[test]
+    fn apply_leaves_unsafe_methods_alone() {
+        let conditional = Conditional::new();
+        let request = headers(&[("If-None-Match", "*")]);
+        let response = conditional.apply(&Method::PUT, &request, "stored".into_response());
+        assert_eq!(response.status_code(), StatusCode::OK);
+        assert!(!response.headers().contains_key("ETag"));
+
+        let response = conditional.apply(&Method::GET, &request, "stored".into_response());
+        assert_eq!(response.status_code(), StatusCode::NOT_MODIFIED);
+        assert!(response.headers().contains_key("ETag"));
+        assert!(response.body().is_empty());
+    }
+
+    #[test]
+    fn check_answers_before_the_handler() {
+        let request = Request::with_headers(Method::PUT, "/doc", &[("If-Match", "\"v1\"")]);
+        let failed = Conditional::check(&request, Some(ETAG), None).unwrap();
+        assert_eq!(failed.status_code(), StatusCode::PRECONDITION_FAILED);
+        assert!(Conditional::check(&request, Some("\"v1\""), None).is_none());
+
+        let request = Request::with_headers(Method::GET, "/doc", &[("If-None-Match", ETAG)]);
+        let modified = validators().last_modified;
+        let cached = Conditional::check(&request, Some(ETAG), modified).unwrap();
+        assert_eq!(cached.status_code(), StatusCode::NOT_MODIFIED);
+        assert_eq!(cached.headers().get("ETag"), Some(ETAG));
+        assert_eq!(
+            cached.headers().get("Last-Modified"),
+            Some("Sun, 06 Nov 1994 08:49:37 GMT")
+        );
+    }
+
+    #[test]
+    fn wildcards_need_a_current_representation() {
+        let update = Request::with_headers(Method::PUT, "/doc", &[("If-Match", "*")]);
+        let failed = Conditional::check(&update, None, None).unwrap();
+        assert_eq!(failed.status_code(), StatusCode::PRECONDITION_FAILED);
+        assert!(Conditional::check(&update, Some(ETAG), None).is_none());
+        let modified = validators().last_modified;
+        assert!(Conditional::check(&update, None, modified).is_none());
+
+        let create = Request::with_headers(Method::PUT, "/doc", &[("If-None-Match", "*")]);
+        assert!(Conditional::check(&create, None, None).is_none());
+        let exists = Conditional::check(&create, Some(ETAG), None).unwrap();
+        assert_eq!(exists.status_code(), StatusCode::PRECONDITION_FAILED);
+    }
+}
@@ -0,0 +1,144 @@
+use std::time::Duration;
+
+use super::logging::Logging;
+use crate::into_response::ErrorFormat;
+
+pub(crate) const DEFAULT_MAX_BODY_SIZE: usize = 2 * 1024 * 1024;
+
+/// Minimum rate a client has to keep up while sending a request. The check only
+/// kicks in once `grace_period` has elapsed so that slow starts are tolerated.
+#[derive(Clone, Debug)]
+pub struct MinDataRate {
+    pub bytes_per_second: u64,
+    pub grace_period: Duration,
+}
+
+/// Knobs for the connection handling. Use the chained setters on top of
+/// `ServerConfig::new()` (or `Default`) and hand it to `Server::config`.
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    pub(crate) max_request_line: usize,
+    pub(crate) max_header_bytes: usize,
+    pub(crate) max_headers: usize,
+    pub(crate) header_read_timeout: Duration,
+    pub(crate) min_data_rate: Option<MinDataRate>,
+    pub(crate) keep_alive: bool,
+    pub(crate) keep_alive_timeout: Duration,
+    pub(crate) max_requests_per_connection: usize,
+    pub(crate) max_pipelined_requests: usize,
+    pub(crate) max_body_size: usize,
+    pub(crate) server_header: Option<String>,
+    pub(crate) error_format: ErrorFormat,
+    pub(crate) logging: Logging,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            max_request_line: 8 * 1024,
+            max_header_bytes: 16 * 1024,
+            max_headers: 100,
+            header_read_timeout: Duration::from_secs(10),
+            min_data_rate: Some(MinDataRate {
+                bytes_per_second: 240,
+                grace_period: Duration::from_secs(5),
+            }),
+            keep_alive: true,
+            keep_alive_timeout: Duration::from_secs(5),
+            max_requests_per_connection: 1000,
+            max_pipelined_requests: 16,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            server_header: None,
+            error_format: ErrorFormat::PlainText,
+            logging: Logging::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// longest request line (method, target and version) accepted, answered with 414 otherwise
+    pub fn max_request_line(mut self, bytes: usize) -> Self {
+        self.max_request_line = bytes;
+        self
+    }
+
+    /// upper bound for the whole head of the request, answered with 431 otherwise
+    pub fn max_header_bytes(mut self, bytes: usize) -> Self {
+        self.max_header_bytes = bytes;
+        self
+    }
+
+    /// maximum number of header fields, answered with 431 otherwise
+    pub fn max_headers(mut self, count: usize) -> Self {
+        self.max_headers = count;
+        self
+    }
+
+    /// time a client gets to deliver the complete head, answered with 408 otherwise
+    pub fn header_read_timeout(mut self, timeout: Duration) -> Self {
+        self.header_read_timeout = timeout;
+        self
+    }
+
+    /// largest request body accepted, anything announcing more is answered with
+    /// 413 without reading the body
+    pub fn max_body_size(mut self, bytes: usize) -> Self {
+        self.max_body_size = bytes;
+        self
+    }
+
+    /// `None` disables the data rate check
+    pub fn min_data_rate(mut self, rate: Option<MinDataRate>) -> Self {
+        self.min_data_rate = rate;
+        self
+    }
+
+    /// `false` closes every connection after the first response
+    pub fn keep_alive(mut self, enabled: bool) -> Self {
+        self.keep_alive = enabled;
+        self
+    }
+
+    /// how long a kept alive connection may sit idle between requests
+    pub fn keep_alive_timeout(mut self, timeout: Duration) -> Self {
+        self.keep_alive_timeout = timeout;
+        self
+    }
+
+    /// after this many requests the connection is closed, the last response
+    /// carries `Connection: close`
+    pub fn max_requests_per_connection(mut self, count: usize) -> Self {
+        self.max_requests_per_connection = count;
+        self
+    }
+
+    /// how many already received requests are answered in one go, anything
+    /// queued behind them stays unparsed in the buffer until they are written
+    pub fn max_pipelined_requests(mut self, count: usize) -> Self {
+        self.max_pipelined_requests = count.max(1);
+        self
+    }
+
+    /// value of the `Server` header added to every response, `None` leaves it out
+    pub fn server_header(mut self, value: Option<&str>) -> Self {
+        self.server_header = value.map(str::to_string);
+        self
+    }
+
+    /// how errors returned by handlers are rendered, plain text by default. The
+    /// layers around the handler already see the rendered response.
+    pub fn error_format(mut self, format: ErrorFormat) -> Self {
+        self.error_format = format;
+        self
+    }
+
+    /// access log format and level, Common Log Format at `Info` by default
+    pub fn logging(mut self, logging: Logging) -> Self {
+        self.logging = logging;
+        self
+    }
+}
@@ -0,0 +1,410 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::middleware::{from_fn, BoxService, Layer, Next};
+use crate::client::client::{Method, Request};
+use crate::header::{add_vary, HeaderMap};
+use crate::into_response::Response;
+use crate::status_code::StatusCode;
+use crate::IntoResponse;
+
+type OriginPredicate = Arc<dyn Fn(&str) -> bool + Send + Sync + 'static>;
+
+/// Origins a `Cors` layer lets read its responses.
+#[derive(Clone)]
+pub enum AllowOrigin {
+    /// every origin, `*` unless credentials are allowed, then the request's
+    /// origin is echoed since browsers reject `*` with credentials
+    Any,
+    List(Vec<String>),
+    Predicate(OriginPredicate),
+}
+
+impl AllowOrigin {
+    pub fn exact(origin: &str) -> Self {
+        AllowOrigin::List(vec![origin.to_string()])
+    }
+
+    pub fn list<I, S>(origins: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        AllowOrigin::List(origins.into_iter().map(Into::into).collect())
+    }
+
+    /// decides per origin, for things like every subdomain of a site
+    pub fn predicate<F>(predicate: F) -> Self
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        AllowOrigin::Predicate(Arc::new(predicate))
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        match self {
+            AllowOrigin::Any => true,
+            // scheme and host are case insensitive, browsers send them lowercase
+            AllowOrigin::List(origins) => origins.iter().any(|o| o.eq_ignore_ascii_case(origin)),
+            AllowOrigin::Predicate(predicate) => predicate(origin),
+        }
+    }
+}
+
+impl fmt::Debug for AllowOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AllowOrigin::Any => f.write_str("Any"),
+            AllowOrigin::List(origins) => f.debug_tuple("List").field(origins).finish(),
+            AllowOrigin::Predicate(_) => f.write_str("Predicate(..)"),
+        }
+    }
+}
+
+/// Cross origin resource sharing. Preflight `OPTIONS` requests are answered
+/// right here, whether or not the path has an `OPTIONS` route, and responses
+/// to allowed origins get the `Access-Control-*` headers. Requests without an
+/// `Origin` pass through untouched. Put it on the router with
+/// `RouterBuilder::layer` so preflights reach it for every path, route layers
+/// never see them.
+///
+/// ```ignore
+/// let router = Router::builder()
+///     .route("/api/items", HandlerTypes::full(create, Method::POST))
+///     .layer(
+///         Cors::new()
+///             .allow_origin(AllowOrigin::exact("https://app.example.com"))
+///             .allow_methods([Method::GET, Method::POST])
+///             .allow_headers(["Content-Type", "Authorization"])
+///             .allow_credentials(true)
+///             .max_age(Duration::from_secs(600)),
+///     )
+///     .build();
+/// ```
+#[derive(Clone, Debug)]
+pub struct Cors {
+    origin: AllowOrigin,
+    methods: Vec<Method>,
+    // `None` allows whatever the preflight asks for
+    headers: Option<Vec<String>>,
+    expose: Vec<String>,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Self {
+            origin: AllowOrigin::Any,
+            methods: vec![Method::GET, Method::HEAD, Method::POST],
+            headers: Some(Vec::new()),
+            expose: Vec::new(),
+            credentials: false,
+            max_age: None,
+        }
+    }
+}
+
+impl Cors {
+    /// any origin, `GET`, `HEAD` and `POST`, no extra headers, no credentials
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn allow_origin(mut self, origin: AllowOrigin) -> Self {
+        self.origin = origin;
+        self
+    }
+
+    pub fn allow_methods(mut self, methods: impl IntoIterator<Item = Method>) -> Self {
+        self.methods = methods.into_iter().collect();
+        self
+    }
+
+    /// request headers besides the CORS safelisted ones
+    pub fn allow_headers<I, S>(mut self, headers: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.headers = Some(headers.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// allows every header a preflight asks for
+    pub fn allow_any_header(mut self) -> Self {
+        self.headers = None;
+        self
+    }
+
+    /// response headers scripts get to read besides the safelisted ones
+    pub fn expose_headers<I, S>(mut self, headers: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.expose = headers.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// lets cookies and `Authorization` come along
+    pub fn allow_credentials(mut self, credentials: bool) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    /// how long browsers may cache a preflight answer
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Answers a preflight, `None` for anything that has to go on to the
+    /// handler.
+    pub fn preflight(&self, request: &Request) -> Option<Response> {
+        let origin = request.get_header("Origin")?;
+        if request.get_method() != &Method::OPTIONS
+            || !request
+                .headers
+                .contains_key("Access-Control-Request-Method")
+        {
+            return None;
+        }
+
+        let mut response = (StatusCode::NO_CONTENT, ()).into_response();
+        let headers = response.headers_mut();
+        // the answer depends on what is asked for, caches have to keep them apart
+        add_vary(headers, "Access-Control-Request-Method");
+        add_vary(headers, "Access-Control-Request-Headers");
+        if !self.allow_origin_headers(origin, headers) {
+            // no allow headers, the browser refuses the actual request
+            return Some(response);
+        }
+
+        let methods: Vec<&str> = self.methods.iter().map(Method::as_str).collect();
+        headers.insert("Access-Control-Allow-Methods", methods.join(", "));
+        let allowed = match &self.headers {
+            Some(allowed) => allowed.join(", "),
+            None => request
+                .get_header("Access-Control-Request-Headers")
+                .unwrap_or_default()
+                .to_string(),
+        };
+        if !allowed.is_empty() {
+            headers.insert("Access-Control-Allow-Headers", allowed);
+        }
+        if let Some(max_age) = self.max_age {
+            headers.insert("Access-Control-Max-Age", max_age.as_secs().to_string());
+        }
+        Some(response)
+    }
+
+    /// Adds the headers an allowed origin needs to read `response`.
+    pub fn apply(&self, origin: Option<&str>, mut response: Response) -> Response {
+        let Some(origin) = origin else {
+            return response;
+        };
+        let headers = response.headers_mut();
+        if self.allow_origin_headers(origin, headers) && !self.expose.is_empty() {
+            headers.insert("Access-Control-Expose-Headers", self.expose.join(", "));
+        }
+        response
+    }
+
+    // Access-Control-Allow-Origin and -Credentials, false when the origin
+    // isn't allowed
+    fn allow_origin_headers(&self, origin: &str, headers: &mut HeaderMap) -> bool {
+        if !matches!(self.origin, AllowOrigin::Any) || self.credentials {
+            add_vary(headers, "Origin");
+        }
+        if !self.origin.allows(origin) {
+            return false;
+        }
+        if matches!(self.origin, AllowOrigin::Any) && !self.credentials {
+            headers.insert("Access-Control-Allow-Origin", "*");
+        } else {
+            headers.insert("Access-Control-Allow-Origin", origin);
+        }
+        if self.credentials {
+            headers.insert("Access-Control-Allow-Credentials", "true");
+        }
+        true
+    }
+}
+
+impl Layer for Cors {
+    fn layer(&self, inner: BoxService) -> BoxService {
+        let cors = self.clone();
+        from_fn(move |request: Request, next: Next| {
+            let cors = cors.clone();
+            async move {
+                if let Some(response) = cors.preflight(&request) {
+                    return response;
+                }
+                let origin = request.get_header("Origin").map(str::to_string);
+                let response = next.run(request).await;
+                cors.apply(origin.as_deref(), response)
+            }
+        })
+        .layer(inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: Method, headers: &[(&str, &str)]) -> Request {
+        Request::with_headers(method, "/api/items", headers)
+    }
+
+    fn preflight(headers: &[(&str, &str)]) -> Request {
+        let mut all = vec![("Access-Control-Request-Method", "POST")];
+        all.extend_from_slice(headers);
+        request(Method::OPTIONS, &all)
+    }
+
+    fn vary(response: &Response) -> Vec<&str> {
+        response.headers().get_all("Vary").collect()
+    }
+
+    fn app() -> Cors {
+        Cors::new()
+            .allow_origin(AllowOrigin::list(["https://app.example.com"]))
+            .allow_methods([Method::GET, Method::POST])
+            .allow_headers(["Content-Type", "Authorization"])
+            .allow_credentials(true)
+            .max_age(Duration::from_secs(600))
+    }
+
+    #[test]
+    fn preflight_for_an_allowed_origin() {
+        let origin = ("Origin", "https://APP.example.com");
+        let response = app().preflight(&preflight(&[origin])).unwrap();
+        let headers = response.headers();
+        assert_eq!(response.status_code(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            headers.get("Access-Control-Allow-Origin"),
+            Some("https://APP.example.com")
+        );
+        assert_eq!(
+            headers.get("Access-Control-Allow-Credentials"),
+            Some("true")
+        );
+        assert_eq!(
+            headers.get("Access-Control-Allow-Methods"),
+            Some("GET, POST")
+        );
+        assert_eq!(
+            headers.get("Access-Control-Allow-Headers"),
+            Some("Content-Type, Authorization")
+        );
+        assert_eq!(headers.get("Access-Control-Max-Age"), Some("600"));
+        assert_eq!(
+            vary(&response),
+            [
+                "Access-Control-Request-Method",
+                "Access-Control-Request-Headers",
+                "Origin"
+            ]
+        );
+    }
+
+    #[test]
+    fn preflight_for_another_origin_allows_nothing() {
+        let origin = ("Origin", "https://evil.example.com");
+        let response = app().preflight(&preflight(&[origin])).unwrap();
+        assert_eq!(response.status_code(), StatusCode::NO_CONTENT);
+        assert!(!response
+            .headers()
+            .iter()
+            .any(|(name, _)| name.starts_with("Access-Control-Allow")));
+        assert!(vary(&response).contains(&"Origin"));
+    }
+
+    #[test]
+    fn only_preflights_are_answered() {
+        let cors = app();
+        let origin = ("Origin", "https://app.example.com");
+        // no Access-Control-Request-Method, a plain OPTIONS for the handler
+        assert!(cors
+            .preflight(&request(Method::OPTIONS, &[origin]))
+            .is_none());
+        assert!(cors.preflight(&preflight(&[])).is_none());
+        let post = request(
+            Method::POST,
+            &[origin, ("Access-Control-Request-Method", "POST")],
+        );
+        assert!(cors.preflight(&post).is_none());
+    }
+
+    #[test]
+    fn any_origin_without_credentials_is_a_wildcard() {
+        let cors = Cors::new().allow_any_header();
+        let asked = ("Access-Control-Request-Headers", "X-Custom, Content-Type");
+        let request = preflight(&[("Origin", "https://a.example"), asked]);
+        let response = cors.preflight(&request).unwrap();
+        let headers = response.headers();
+        assert_eq!(headers.get("Access-Control-Allow-Origin"), Some("*"));
+        assert_eq!(headers.get("Access-Control-Allow-Credentials"), None);
+        assert_eq!(
+            headers.get("Access-Control-Allow-Headers"),
+            Some("X-Custom, Content-Type")
+        );
+        assert_eq!(headers.get("Access-Control-Max-Age"), None);
+        // the answer doesn't depend on the origin
+        assert!(!vary(&response).contains(&"Origin"));
+    }
+
+    #[test]
+    fn any_origin_with_credentials_is_echoed() {
+        let cors = Cors::new().allow_credentials(true);
+        let request = preflight(&[("Origin", "https://a.example")]);
+        let response = cors.preflight(&request).unwrap();
+        assert_eq!(
+            response.headers().get("Access-Control-Allow-Origin"),
+            Some("https://a.example")
+        );
+        assert!(vary(&response).contains(&"Origin"));
+    }
+
+    #[test]
+    fn predicates_decide_per_origin() {
+        let cors = Cors::new().allow_origin(AllowOrigin::predicate(|origin| {
+            origin.ends_with(".example.com")
+        }));
+        let allowed = preflight(&[("Origin", "https://a.example.com")]);
+        let response = cors.preflight(&allowed).unwrap();
+        assert!(response
+            .headers()
+            .contains_key("Access-Control-Allow-Origin"));
+        let refused = preflight(&[("Origin", "https://example.org")]);
+        let response = cors.preflight(&refused).unwrap();
+        assert!(!response
+            .headers()
+            .contains_key("Access-Control-Allow-Origin"));
+    }
+
+    #[test]
+    fn actual_responses_get_the_allow_and_expose_headers() {
+        let cors = app().expose_headers(["X-Request-Id"]);
+        let response = cors.apply(Some("https://app.example.com"), "ok".into_response());
+        let headers = response.headers();
+        assert_eq!(
+            headers.get("Access-Control-Allow-Origin"),
+            Some("https://app.example.com")
+        );
+        assert_eq!(
+            headers.get("Access-Control-Expose-Headers"),
+            Some("X-Request-Id")
+        );
+        assert_eq!(headers.get("Access-Control-Max-Age"), None);
+        assert_eq!(headers.get("Access-Control-Allow-Methods"), None);
+
+        let refused = cors.apply(Some("https://evil.example.com"), "ok".into_response());
+        assert_eq!(refused.headers().get("Access-Control-Expose-Headers"), None);
+        let untouched = cors.apply(None, "ok".into_response());
+        assert!(!untouched.headers().contains_key("Vary"));
+    }
+}
@@ -0,0 +1,612 @@
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures_util::StreamExt;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::body::{Body, BodyStream};
+use crate::client::client::Method;
+use crate::into_response::Response;
+use crate::status_code::StatusCode;
+
+/// What the encoder needs to know about the exchange a response belongs to.
+pub struct EncodeContext<'a> {
+    pub method: &'a Method,
+    pub http10: bool,
+    /// whether the connection is meant to stay open after this response
+    pub keep_alive: bool,
+    /// value of the `Server` header, none is sent when `None`
+    pub server: Option<&'a str>,
+}
+
+impl Default for EncodeContext<'_> {
+    fn default() -> Self {
+        Self {
+            method: &Method::GET,
+            http10: false,
+            keep_alive: true,
+            server: None,
+        }
+    }
+}
+
+/// Serialized response and whether the connection can carry another one after
+/// it. Close delimited bodies or a handler asking for `Connection: close` turn
+/// `keep_alive` off even when the context had it on.
+pub struct Encoded {
+    /// head and, unless it is streamed, the body
+    pub bytes: Vec<u8>,
+    /// streamed body still to be written after `bytes` with `write_body`
+    pub body: Option<BodyStream>,
+    /// size of the body in `bytes`, without chunk framing
+    pub body_len: u64,
+    pub framing: Framing,
+    pub keep_alive: bool,
+}
+
+/// How the end of the body is told to the client.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Framing {
+    NoBody,
+    Length(u64),
+    Chunked,
+    Close,
+}
+
+pub fn encode(response: Response, ctx: &EncodeContext) -> Encoded {
+    let (mut bytes, framing, keep_alive) = encode_head(&response, ctx);
+    let sends_body = *ctx.method != Method::HEAD && framing != Framing::NoBody;
+
+    let mut body_len = 0;
+    let body = match response.into_body() {
+        Body::Stream(stream) if sends_body => Some(stream),
+        Body::Full(full) if sends_body => {
+            append_body(&full, framing, &mut bytes);
+            body_len = full.len() as u64;
+            None
+        }
+        _ => {
+            if sends_body && framing == Framing::Chunked {
+                bytes.extend_from_slice(b"0\r\n\r\n");
+            }
+            None
+        }
+    };
+
+    Encoded {
+        bytes,
+        body,
+        body_len,
+        framing,
+        keep_alive,
+    }
+}
+
+/// Like `encode` but leaves a streamed body out, for when only a reference to
+/// the response is at hand.
+pub fn encode_buffered(response: &Response, ctx: &EncodeContext) -> Vec<u8> {
+    let (mut bytes, framing, _) = encode_head(response, ctx);
+    if *ctx.method != Method::HEAD && framing != Framing::NoBody {
+        if let Some(body) = response.body().as_bytes() {
+            append_body(body, framing, &mut bytes);
+        }
+    }
+    bytes
+}
+
+fn encode_head(response: &Response, ctx: &EncodeContext) -> (Vec<u8>, Framing, bool) {
+    let status = response.status_code();
+    let body = response.body();
+    let is_head = *ctx.method == Method::HEAD;
+
+    // Connection and the framing headers are ours to decide, the handler's
+    // values only serve as hints
+    let mut headers = response.headers().clone();
+    let handler_closes = headers
+        .remove("Connection")
+        .is_some_and(|v| has_token(&v, "close"));
+    let wants_chunked = headers
+        .get("Transfer-Encoding")
+        .is_some_and(|v| has_token(v, "chunked"));
+    let declared_length = headers
+        .get("Content-Length")
+        .and_then(|v| v.parse::<u64>().ok());
+    let mut keep_alive = ctx.keep_alive && !handler_closes;
+
+    let framing = if status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+    {
+        headers.remove("Transfer-Encoding");
+        // a 304 may repeat the length of the representation it stands for
+        if status != StatusCode::NOT_MODIFIED {
+            headers.remove("Content-Length");
+        }
+        Framing::NoBody
+    } else if let (Some(length), false) = (body.len(), wants_chunked) {
+        // a HEAD handler may announce the length without producing the body
+        match declared_length {
+            Some(declared) if is_head && length == 0 => Framing::Length(declared),
+            _ => {
+                headers.insert("Content-Length", length.to_string());
+                Framing::Length(length as u64)
+            }
+        }
+    } else if let (Some(declared), false) = (declared_length, wants_chunked) {
+        // a stream whose size the handler knows, a file for instance
+        Framing::Length(declared)
+    } else if ctx.http10 {
+        // 1.0 clients can't decode chunks, the end of the body is the end of the connection
+        headers.remove("Transfer-Encoding");
+        headers.remove("Content-Length");
+        keep_alive = false;
+        Framing::Close
+    } else {
+        headers.insert("Transfer-Encoding", "chunked");
+        headers.remove("Content-Length");
+        Framing::Chunked
+    };
+
+    let mut out: Vec<u8> = Vec::with_capacity(256 + body.len().unwrap_or(0));
+    write!(
+        out,
+        "HTTP/1.1 {} {}\r\n",
+        status.as_u16(),
+        response.reason()
+    )
+    .unwrap();
+
+    if !headers.contains_key("Date") {
+        write_header("Date", &http_date(), &mut out);
+    }
+    if let Some(server) = ctx.server {
+        if !headers.contains_key("Server") {
+            write_header("Server", server, &mut out);
+        }
+    }
+    for (k, v) in headers.iter() {
+        write_header(k, v, &mut out);
+    }
+    if !keep_alive {
+        write_header("Connection", "close", &mut out);
+    } else if ctx.http10 {
+        // 1.0 clients close by default, tell them we are not going to
+        write_header("Connection", "keep-alive", &mut out);
+    }
+    out.extend_from_slice(b"\r\n");
+
+    (out, framing, keep_alive)
+}
+
+fn append_body(body: &[u8], framing: Framing, out: &mut Vec<u8>) {
+    match framing {
+        Framing::NoBody => {}
+        Framing::Length(_) | Framing::Close => out.extend_from_slice(body),
+        Framing::Chunked => {
+            if !body.is_empty() {
+                write_chunk(body, out);
+            }
+            out.extend_from_slice(b"0\r\n\r\n");
+        }
+    }
+}
+
+/// Writes a streamed body as its chunks come in, each one only after the socket
+/// took the previous one. Returns whether the body went out exactly as framed,
+/// a stream falling short of its `Content-Length` leaves the connection unusable.
+/// `written` counts the body bytes sent, also when writing fails halfway.
+pub async fn write_body<W>(
+    out: &mut W,
+    mut body: BodyStream,
+    framing: Framing,
+    written: &mut u64,
+) -> io::Result<bool>
+where
+    W: AsyncWrite + Unpin,
+{
+    *written = 0;
+    let mut frame = Vec::new();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(io::Error::other)?;
+        if chunk.is_empty() {
+            continue;
+        }
+        *written += chunk.len() as u64;
+        match framing {
+            Framing::Chunked => {
+                frame.clear();
+                write_chunk(&chunk, &mut frame);
+                out.write_all(&frame).await?;
+            }
+            Framing::Length(length) if *written > length => {
+                return Err(io::Error::other("body is longer than its Content-Length"));
+            }
+            _ => out.write_all(&chunk).await?,
+        }
+    }
+    if framing == Framing::Chunked {
+        out.write_all(b"0\r\n\r\n").await?;
+    }
+    out.flush().await?;
+
+    Ok(match framing {
+        Framing::Length(length) => *written == length,
+        _ => true,
+    })
+}
+
+pub(crate) fn write_chunk(chunk: &[u8], out: &mut Vec<u8>) {
+    write!(out, "{:X}\r\n", chunk.len()).unwrap();
+    out.extend_from_slice(chunk);
+    out.extend_from_slice(b"\r\n");
+}
+
+fn write_header(key: &str, value: &str, out: &mut Vec<u8>) {
+    // a line break in either part would let the value smuggle in extra headers
+    if [key, value].iter().any(|s| s.contains(['\r', '\n'])) {
+        return;
+    }
+    write!(out, "{}: {}\r\n", key, value).unwrap();
+}
+
+fn has_token(value: &str, token: &str) -> bool {
+    value
+        .split(',')
+        .any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
+thread_local! {
+    // (unix second, formatted date) so formatting happens once a second per thread
+    static DATE: RefCell<(u64, String)> = const { RefCell::new((0, String::new())) };
+}
+
+/// Current time as an IMF-fixdate, the format of the `Date` header.
+pub fn http_date() -> String {
+    let now = SystemTime::now();
+    let secs = now
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    DATE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        if cache.0 != secs || cache.1.is_empty() {
+            *cache = (secs, httpdate::fmt_http_date(now));
+        }
+        cache.1.clone()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::into_response::ResponseBuilder;
+    use bytes::Bytes;
+    use futures_util::stream;
+
+    const DATE: &str = "Sun, 06 Nov 1994 08:49:37 GMT";
+
+    fn response(status: StatusCode, body: Body, headers: &[(&str, &str)]) -> Response {
+        let mut builder = ResponseBuilder::new()
+            .status_code(status)
+            .header(("Date", DATE));
+        for header in headers {
+            builder = builder.header(*header);
+        }
+        builder.with_body(body).build()
+    }
+
+    fn full(body: &str) -> Body {
+        Body::Full(Bytes::from(body.to_string()))
+    }
+
+    fn chunks(parts: &[&'static str]) -> Body {
+        let parts: Vec<Result<Bytes, io::Error>> =
+            parts.iter().map(|p| Ok(Bytes::from(*p))).collect();
+        Body::from_stream(stream::iter(parts))
+    }
+
+    fn encode_as(method: &Method, http10: bool, response: Response) -> Encoded {
+        let ctx = EncodeContext {
+            method,
+            http10,
+            ..EncodeContext::default()
+        };
+        encode(response, &ctx)
+    }
+
+    fn text(encoded: &Encoded) -> &str {
+        std::str::from_utf8(&encoded.bytes).unwrap()
+    }
+
+    async fn streamed(encoded: Encoded) -> String {
+        let mut out = Vec::new();
+        let mut written = 0;
+        let body = encoded.body.expect("a streamed body");
+        assert!(write_body(&mut out, body, encoded.framing, &mut written)
+            .await
+            .unwrap());
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn status_line_date_and_server_come_first() {
+        let ctx = EncodeContext {
+            server: Some("skibidi"),
+            ..EncodeContext::default()
+        };
+        let response = ResponseBuilder::new()
+            .status_code(StatusCode::NOT_FOUND)
+            .header(("X-A", "1"))
+            .build();
+        let bytes = encode(response, &ctx).bytes;
+        let text = String::from_utf8(bytes).unwrap();
+        let (date_line, rest) = text
+            .strip_prefix("HTTP/1.1 404 Not Found\r\nDate: ")
+            .and_then(|rest| rest.split_once("\r\n"))
+            .expect("status line then Date");
+        assert!(httpdate::parse_http_date(date_line).is_ok());
+        assert_eq!(
+            rest,
+            "Server: skibidi\r\nX-A: 1\r\nContent-Length: 0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn handler_date_and_server_are_kept() {
+        let ctx = EncodeContext {
+            server: Some("skibidi"),
+            ..EncodeContext::default()
+        };
+        let encoded = encode(
+            response(StatusCode::OK, full("hi"), &[("Server", "custom")]),
+            &ctx,
+        );
+        assert_eq!(
+            text(&encoded),
+            "HTTP/1.1 200 OK\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\nServer: custom\r\n\
+             Content-Length: 2\r\n\r\nhi"
+        );
+    }
+
+    #[test]
+    fn buffered_bodies_get_a_content_length() {
+        let encoded = encode_as(
+            &Method::GET,
+            false,
+            response(StatusCode::OK, full("hello"), &[]),
+        );
+        assert_eq!(
+            text(&encoded),
+            "HTTP/1.1 200 OK\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\n\
+             Content-Length: 5\r\n\r\nhello"
+        );
+        assert_eq!(encoded.framing, Framing::Length(5));
+        assert_eq!(encoded.body_len, 5);
+        assert!(encoded.keep_alive);
+    }
+
+    #[test]
+    fn wrong_handler_lengths_are_replaced() {
+        let encoded = encode_as(
+            &Method::GET,
+            false,
+            response(StatusCode::OK, full("hello"), &[("Content-Length", "50")]),
+        );
+        assert_eq!(
+            text(&encoded),
+            "HTTP/1.1 200 OK\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\n\
+             Content-Length: 5\r\n\r\nhello"
+        );
+    }
+
+    #[test]
+    fn chunked_buffered_body() {
+        let encoded = encode_as(
+            &Method::GET,
+            false,
+            response(
+                StatusCode::OK,
+                full("hello"),
+                &[("Transfer-Encoding", "chunked")],
+            ),
+        );
+        assert_eq!(
+            text(&encoded),
+            "HTTP/1.1 200 OK\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\n\
+             Transfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn streams_are_chunked() {
+        let encoded = encode_as(
+            &Method::GET,
+            false,
+            response(StatusCode::OK, chunks(&["hello", "", " world!"]), &[]),
+        );
+        assert_eq!(
+            text(&encoded),
+            "HTTP/1.1 200 OK\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\n\
+             Transfer-Encoding: chunked\r\n\r\n"
+        );
+        assert_eq!(encoded.framing, Framing::Chunked);
+        assert_eq!(
+            streamed(encoded).await,
+            "5\r\nhello\r\n7\r\n world!\r\n0\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn streams_with_a_length_are_sent_raw() {
+        let encoded = encode_as(
+            &Method::GET,
+            false,
+            response(
+                StatusCode::OK,
+                chunks(&["ab", "cd"]),
+                &[("Content-Length", "4")],
+            ),
+        );
+        assert_eq!(
+            text(&encoded),
+            "HTTP/1.1 200 OK\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\n\
+             Content-Length: 4\r\n\r\n"
+        );
+        assert_eq!(streamed(encoded).await, "abcd");
+    }
+
+    #[tokio::test]
+    async fn http10_streams_are_close_delimited() {
+        let encoded = encode_as(
+            &Method::GET,
+            true,
+            response(StatusCode::OK, chunks(&["ab", "cd"]), &[]),
+        );
+        assert_eq!(
+            text(&encoded),
+            "HTTP/1.1 200 OK\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\n\
+             Connection: close\r\n\r\n"
+        );
+        assert_eq!(encoded.framing, Framing::Close);
+        assert!(!encoded.keep_alive);
+        assert_eq!(streamed(encoded).await, "abcd");
+    }
+
+    #[test]
+    fn http10_keep_alive_is_announced() {
+        let encoded = encode_as(&Method::GET, true, response(StatusCode::OK, full("x"), &[]));
+        assert_eq!(
+            text(&encoded),
+            "HTTP/1.1 200 OK\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\n\
+             Content-Length: 1\r\nConnection: keep-alive\r\n\r\nx"
+        );
+    }
+
+    #[test]
+    fn handler_connection_close_is_honoured() {
+        let encoded = encode_as(
+            &Method::GET,
+            false,
+            response(StatusCode::OK, full("x"), &[("Connection", "close")]),
+        );
+        assert_eq!(
+            text(&encoded),
+            "HTTP/1.1 200 OK\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\n\
+             Content-Length: 1\r\nConnection: close\r\n\r\nx"
+        );
+        assert!(!encoded.keep_alive);
+    }
+
+    #[test]
+    fn head_keeps_the_length_but_not_the_body() {
+        let encoded = encode_as(
+            &Method::HEAD,
+            false,
+            response(StatusCode::OK, full("hello"), &[]),
+        );
+        assert_eq!(
+            text(&encoded),
+            "HTTP/1.1 200 OK\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\n\
+             Content-Length: 5\r\n\r\n"
+        );
+        assert!(encoded.body.is_none());
+
+        // a HEAD handler may only announce the length
+        let encoded = encode_as(
+            &Method::HEAD,
+            false,
+            response(StatusCode::OK, Body::Empty, &[("Content-Length", "1234")]),
+        );
+        assert_eq!(
+            text(&encoded),
+            "HTTP/1.1 200 OK\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\n\
+             Content-Length: 1234\r\n\r\n"
+        );
+        assert_eq!(encoded.framing, Framing::Length(1234));
+    }
+
+    #[test]
+    fn head_of_a_stream_sends_no_chunks() {
+        let encoded = encode_as(
+            &Method::HEAD,
+            false,
+            response(StatusCode::OK, chunks(&["x"]), &[]),
+        );
+        assert_eq!(
+            text(&encoded),
+            "HTTP/1.1 200 OK\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\n\
+             Transfer-Encoding: chunked\r\n\r\n"
+        );
+        assert!(encoded.body.is_none());
+    }
+
+    #[test]
+    fn no_content_drops_body_and_framing() {
+        let encoded = encode_as(
+            &Method::GET,
+            false,
+            response(
+                StatusCode::NO_CONTENT,
+                full("ignored"),
+                &[("Content-Length", "7"), ("Transfer-Encoding", "chunked")],
+            ),
+        );
+        assert_eq!(
+            text(&encoded),
+            "HTTP/1.1 204 No Content\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\n\r\n"
+        );
+        assert_eq!(encoded.framing, Framing::NoBody);
+        assert!(encoded.keep_alive);
+    }
+
+    #[test]
+    fn not_modified_keeps_the_representation_length() {
+        let encoded = encode_as(
+            &Method::GET,
+            false,
+            response(
+                StatusCode::NOT_MODIFIED,
+                Body::Empty,
+                &[("ETag", "\"v1\""), ("Content-Length", "512")],
+            ),
+        );
+        assert_eq!(
+            text(&encoded),
+            "HTTP/1.1 304 Not Modified\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\n\
+             ETag: \"v1\"\r\nContent-Length: 512\r\n\r\n"
+        );
+        assert_eq!(encoded.framing, Framing::NoBody);
+    }
+
+    #[test]
+    fn header_lines_with_line_breaks_are_dropped() {
+        let encoded = encode_as(
+            &Method::GET,
+            false,
+            response(
+                StatusCode::OK,
+                Body::Empty,
+                &[
+                    ("X-Evil", "a\r\nSet-Cookie: stolen"),
+                    ("X-Also\n", "b"),
+                    ("X-Lf", "c\nd"),
+                    ("X-Fine", "ok"),
+                ],
+            ),
+        );
+        assert_eq!(
+            text(&encoded),
+            "HTTP/1.1 200 OK\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\n\
+             X-Fine: ok\r\nContent-Length: 0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn encode_buffered_matches_encode() {
+        let ctx = EncodeContext::default();
+        let buffered = encode_buffered(&response(StatusCode::OK, full("hello"), &[]), &ctx);
+        let encoded = encode(response(StatusCode::OK, full("hello"), &[]), &ctx);
+        assert_eq!(buffered, encoded.bytes);
+    }
+}
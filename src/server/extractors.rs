@@ -1,24 +1,340 @@
+use std::any::type_name;
+use std::io::{self, Read};
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+
+use super::compression::Encoding;
+use super::middleware::{BoxFuture, BoxService, Layer, Service};
 use crate::client::client::Request;
-use serde::de::{DeserializeOwned, Error};
+use crate::into_response::{Response, ResponseBuilder};
+use crate::status_code::StatusCode;
+use crate::IntoResponse;
+
 pub struct Json<T>(pub T);
 
+/// `application/x-www-form-urlencoded` body.
+pub struct Form<T>(pub T);
+
+/// A value from the request's extensions. As a layer it puts a clone of the
+/// value into every request, the way to share state with handlers.
+///
+/// ```ignore
+/// let router = Router::builder()
+///     .route("/", HandlerTypes::full(index, Method::GET))
+///     .layer(Extension(pool))
+///     .build();
+///
+/// fn index(request: Request) -> Result<String, ExtractError> {
+///     let Extension(pool) = request.extension::<Pool>()?;
+///     ...
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Extension<T>(pub T);
+
+// what bodies may come compressed with, for the `Accept-Encoding` of a 415
+pub(crate) const ACCEPTED_ENCODINGS: &str = "gzip, deflate, br, zstd";
+
+/// Why the body of a request couldn't be turned into what the handler asked
+/// for. Converts into the matching error response.
+#[derive(Error, Debug)]
+pub enum ExtractError {
+    #[error("request has no body")]
+    MissingBody,
+    #[error("unsupported content encoding: {0}")]
+    UnsupportedEncoding(String),
+    #[error("decompressed body exceeds the configured limit")]
+    TooLarge,
+    #[error("body does not match its content encoding: {0}")]
+    InvalidEncoding(io::Error),
+    #[error("body is not valid UTF-8")]
+    InvalidUtf8,
+    #[error("invalid JSON body: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("invalid form body: {0}")]
+    Form(#[from] serde_urlencoded::de::Error),
+    /// nothing put the value in, a bug in the server rather than the request
+    #[error("missing request extension: {0}")]
+    MissingExtension(&'static str),
+}
+
+impl ExtractError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ExtractError::UnsupportedEncoding(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ExtractError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ExtractError::MissingExtension(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            // well formed JSON of the wrong shape
+            ExtractError::Json(e) if e.is_data() => StatusCode::UNPROCESSABLE_ENTITY,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl IntoResponse for ExtractError {
+    fn into_response(self) -> Response {
+        let mut builder = ResponseBuilder::new()
+            .status_code(self.status_code())
+            .header(("Content-Type", "text/plain"));
+        if let ExtractError::UnsupportedEncoding(_) = self {
+            // tells the client what it may use instead, RFC 7694
+            builder = builder.header(("Accept-Encoding", ACCEPTED_ENCODINGS));
+        }
+        builder.with_body(self.to_string()).build()
+    }
+}
+
 impl Request {
-    fn json<T: DeserializeOwned>(self) -> Result<Json<T>, serde_json::Error> {
-        if let Some(body) = &self.body {
-            let parsed = serde_json::from_slice::<T>(&body)?;
-            Ok(Json(parsed))
-        } else {
-            Err(serde_json::Error::custom("ayy"))
+    /// Deserializes the body as JSON, decompressing it first if it came with a
+    /// `Content-Encoding`.
+    pub fn json<T: DeserializeOwned>(self) -> Result<Json<T>, ExtractError> {
+        let body = self.bytes()?;
+        let parsed = serde_json::from_slice::<T>(&body)?;
+        Ok(Json(parsed))
+    }
+
+    /// Deserializes an urlencoded form body, decompressing it first if it came
+    /// with a `Content-Encoding`.
+    pub fn form<T: DeserializeOwned>(self) -> Result<Form<T>, ExtractError> {
+        let body = self.bytes()?;
+        let parsed = serde_urlencoded::from_bytes::<T>(&body)?;
+        Ok(Form(parsed))
+    }
+
+    /// A clone of the extension of type `T`, which doesn't use up the request.
+    pub fn extension<T: Clone + Send + Sync + 'static>(
+        &self,
+    ) -> Result<Extension<T>, ExtractError> {
+        self.extensions()
+            .get::<T>()
+            .map(|value| Extension(value.clone()))
+            .ok_or(ExtractError::MissingExtension(type_name::<T>()))
+    }
+
+    pub fn string(self) -> Result<String, ExtractError> {
+        let body = self.bytes()?;
+        String::from_utf8(body).map_err(|_| ExtractError::InvalidUtf8)
+    }
+
+    /// The raw body with its `Content-Encoding` undone. The body size limit
+    /// applies to the decompressed bytes, a tiny gzip bomb gets a 413 just like
+    /// a large plain body.
+    pub fn bytes(self) -> Result<Vec<u8>, ExtractError> {
+        let limit = self.body_limit();
+        let Some(body) = self.body else {
+            return Err(ExtractError::MissingBody);
+        };
+        let Some(content_encoding) = self.headers.get("Content-Encoding") else {
+            return Ok(body);
+        };
+
+        // codings are listed in the order they were applied, undo them backwards
+        let mut encodings = Vec::new();
+        for token in content_encoding.split(',').map(str::trim) {
+            if token.is_empty() || token.eq_ignore_ascii_case("identity") {
+                continue;
+            }
+            match Encoding::from_token(token) {
+                Some(encoding) => encodings.push(encoding),
+                None => return Err(ExtractError::UnsupportedEncoding(token.to_string())),
+            }
+        }
+
+        let mut body = body;
+        for encoding in encodings.into_iter().rev() {
+            body = decode(encoding, &body, limit)?;
         }
+        Ok(body)
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> Layer for Extension<T> {
+    fn layer(&self, inner: BoxService) -> BoxService {
+        Arc::new(ExtensionService {
+            value: self.0.clone(),
+            inner,
+        })
+    }
+}
+
+struct ExtensionService<T> {
+    value: T,
+    inner: BoxService,
+}
+
+impl<T: Clone + Send + Sync + 'static> Service for ExtensionService<T> {
+    fn call(&self, mut request: Request) -> BoxFuture<Response> {
+        request.extensions_mut().insert(self.value.clone());
+        self.inner.call(request)
+    }
+}
+
+// a window covering the whole limit is all a frame within it ever needs,
+// 8 MiB at least since that is what encoders use without a known size, and
+// never more than the 128 MiB decoders accept by default
+fn zstd_window_log(limit: usize) -> u32 {
+    (usize::BITS - limit.leading_zeros()).clamp(23, 27)
+}
+
+// the window a zstd frame asks for, from its header (RFC 8878 3.1.1.1), `None`
+// for skippable frames and headers the decoder is going to reject anyway
+fn zstd_frame_window(frame: &[u8]) -> Option<u64> {
+    if frame.get(..4)? != [0x28, 0xB5, 0x2F, 0xFD] {
+        return None;
+    }
+    let descriptor = *frame.get(4)?;
+    if descriptor & 0x20 == 0 {
+        let window = *frame.get(5)?;
+        let base = 1u64 << (10 + (window >> 3));
+        return Some(base + base / 8 * u64::from(window & 7));
+    }
+    // a single segment frame is its own window, as large as its content
+    let dictionary = [0, 1, 2, 4][usize::from(descriptor & 3)];
+    let width = [1, 2, 4, 8][usize::from(descriptor >> 6)];
+    let field = frame.get(5 + dictionary..5 + dictionary + width)?;
+    let mut size = [0; 8];
+    size[..width].copy_from_slice(field);
+    let size = u64::from_le_bytes(size);
+    Some(if width == 2 { size + 256 } else { size })
+}
+
+// whether every frame of `input` makes do with a window of `window_log`, the
+// decoder refuses the others with nothing but a message to tell them apart
+fn zstd_windows_fit(input: &[u8], window_log: u32) -> bool {
+    let max = (1u64 << window_log) + 1;
+    let mut at = 0;
+    while at < input.len() {
+        let frame = &input[at..];
+        if zstd_frame_window(frame).is_some_and(|window| window > max) {
+            return false;
+        }
+        match zstd::zstd_safe::find_frame_compressed_size(frame) {
+            Ok(size) if size > 0 => at += size,
+            _ => break,
+        }
+    }
+    true
+}
+
+// decompresses at most `limit` bytes, one more means the body is over it
+fn decode(encoding: Encoding, input: &[u8], limit: usize) -> Result<Vec<u8>, ExtractError> {
+    let reader: Box<dyn Read + '_> = match encoding {
+        Encoding::Gzip => Box::new(flate2::read::MultiGzDecoder::new(input)),
+        Encoding::Deflate => Box::new(flate2::read::ZlibDecoder::new(input)),
+        Encoding::Brotli => Box::new(brotli::Decompressor::new(input, 4096)),
+        Encoding::Zstd => {
+            // a window beyond the bound is a body we'd refuse as too large anyway
+            let window_log = zstd_window_log(limit);
+            if !zstd_windows_fit(input, window_log) {
+                return Err(ExtractError::TooLarge);
+            }
+            let mut decoder = zstd::stream::read::Decoder::with_buffer(input)
+                .map_err(ExtractError::InvalidEncoding)?;
+            // the frame picks the window, which is memory we allocate up front
+            decoder
+                .window_log_max(window_log)
+                .map_err(ExtractError::InvalidEncoding)?;
+            Box::new(decoder)
+        }
+    };
+
+    let mut out = Vec::new();
+    reader
+        .take(limit as u64 + 1)
+        .read_to_end(&mut out)
+        .map_err(ExtractError::InvalidEncoding)?;
+    if out.len() > limit {
+        return Err(ExtractError::TooLarge);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn zstd_with_window(data: &[u8], window_log: u32) -> Vec<u8> {
+        let mut encoder = zstd::stream::write::Encoder::new(Vec::new(), 3).unwrap();
+        encoder.window_log(window_log).unwrap();
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn window_bound_follows_the_limit() {
+        assert_eq!(zstd_window_log(1024), 23);
+        assert_eq!(zstd_window_log(16 << 20), 25);
+        assert_eq!(zstd_window_log(usize::MAX), 27);
+    }
+
+    #[test]
+    fn zstd_within_the_window_decodes() {
+        let data = vec![b'z'; 4096];
+        let decoded = decode(Encoding::Zstd, &zstd_with_window(&data, 20), 1 << 20).unwrap();
+        assert_eq!(decoded, data);
+    }
+
+    #[test]
+    fn zstd_windows_past_the_bound_are_too_large() {
+        let compressed = zstd_with_window(b"tiny", 28);
+        let error = decode(Encoding::Zstd, &compressed, 1 << 20).unwrap_err();
+        assert!(matches!(error, ExtractError::TooLarge), "{:?}", error);
+        assert_eq!(error.status_code(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn single_segment_frames_are_as_large_as_their_content() {
+        let compressed = zstd::bulk::compress(&[7; 1000], 3).unwrap();
+        assert_eq!(zstd_frame_window(&compressed), Some(1000));
+
+        // just the header of a single segment frame claiming a GiB
+        let mut header = vec![0x28, 0xB5, 0x2F, 0xFD, 0xE0];
+        header.extend((1u64 << 30).to_le_bytes());
+        let error = decode(Encoding::Zstd, &header, 1 << 20).unwrap_err();
+        assert!(matches!(error, ExtractError::TooLarge), "{:?}", error);
+    }
+
+    #[test]
+    fn every_frame_has_to_fit_the_window() {
+        let mut frames = zstd_with_window(b"small", 20);
+        frames.extend(zstd_with_window(b"large", 28));
+        let error = decode(Encoding::Zstd, &frames, 1 << 20).unwrap_err();
+        assert!(matches!(error, ExtractError::TooLarge), "{:?}", error);
+
+        let mut fine = zstd_with_window(b"one ", 20);
+        fine.extend(zstd_with_window(b"two", 21));
+        assert_eq!(decode(Encoding::Zstd, &fine, 1 << 20).unwrap(), b"one two");
+    }
+
+    #[test]
+    fn bombs_stop_at_the_limit() {
+        let compressed = gzip(&vec![0; 1 << 20]);
+        assert!(compressed.len() < 4096);
+        let error = decode(Encoding::Gzip, &compressed, 64 * 1024).unwrap_err();
+        assert!(matches!(error, ExtractError::TooLarge));
+        let exact = decode(Encoding::Gzip, &compressed, 1 << 20).unwrap();
+        assert_eq!(exact.len(), 1 << 20);
     }
 
-    fn string(self) -> Result<String, ()> {
-        if let Some(body) = self.body {
-            match String::from_utf8(body) {
-                Ok(string) => return Ok(string),
-                Err(_) => return Err(()),
-            };
+    #[test]
+    fn garbage_is_an_invalid_encoding() {
+        for encoding in [Encoding::Gzip, Encoding::Deflate, Encoding::Zstd] {
+            let error = decode(encoding, b"definitely not compressed", 1024).unwrap_err();
+            assert!(
+                matches!(error, ExtractError::InvalidEncoding(_)),
+                "{:?}",
+                error
+            );
+            assert_eq!(error.status_code(), StatusCode::BAD_REQUEST);
         }
-        Err(())
     }
 }
@@ -1,14 +1,21 @@
-use crate::client::parse::parse_http;
-use crate::client::parse::parse_http_blocking;
-use crate::into_response::Response;
+use crate::client::client::{Method, Request};
+use crate::client::parse::{parse_buffered, parse_http, read_body, ParseError};
+use crate::into_response::{with_error_format, HandlerError, Response};
 use crate::server::router::RouterService;
+pub use crate::status_code::StatusCode;
 use crate::write_async;
-use crate::write_blocking;
-use crate::HandlerTypes;
 use crate::IntoResponse;
-use std::net::TcpStream;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
 
-use super::router::RouterError;
+use super::config::ServerConfig;
+use super::encoder::{encode, write_body, EncodeContext};
+use super::logging::{Access, LogLevel, Logging};
+use super::middleware::PendingBody;
+use super::router::{MatchedPath, PathParams, RouteMatch, RouterError};
+use super::trace;
 
 // pub fn handle_connection_blocking(mut stream: TcpStream, service: RouterService) {
 //     loop {
@@ -72,72 +79,720 @@ use super::router::RouterError;
 //     }
 // }
 
-pub async fn handle_connection(mut stream: tokio::net::TcpStream, service: RouterService) {
+pub async fn handle_connection(
+    mut stream: tokio::net::TcpStream,
+    service: RouterService,
+    config: Arc<ServerConfig>,
+) {
+    // bytes read past the end of the current request, pipelined requests and
+    // the start of the next one live here
+    let mut buf: Vec<u8> = Vec::with_capacity(1024);
+    let mut served = 0;
+    let remote = stream.peer_addr().ok();
+    let logging = &config.logging;
+    logging.event(
+        LogLevel::Debug,
+        "connection_opened",
+        &[("remote", addr(remote))],
+    );
     loop {
-        match parse_http(&mut stream).await {
-            Ok(request) => match service
-                .router
-                .matches(request.get_path(), request.get_method().clone())
-            {
-                Ok(route_match) => {
-                    let resp = match &route_match.handler {
-                        HandlerTypes::ZeroParams(a) => a.0.call().unwrap().to_bytes(),
-                        HandlerTypes::Params(a) => a.0.call(request.headers).unwrap().to_bytes(),
-                        HandlerTypes::Body(a) => a.0.call(request).unwrap().to_bytes(),
-                        HandlerTypes::Full(a) => a.0.call(request).unwrap().to_bytes(),
-                    };
+        let idle_timeout = (served > 0).then_some(config.keep_alive_timeout);
+        let first = parse_http(&mut stream, &mut buf, &config, idle_timeout).await;
 
-                    if let Err(_) = write_async(&mut stream, &resp).await {
-                        break;
-                    } else {
-                        break;
+        // answer everything the client already queued up behind the first
+        // request, in order, and write it all back at once
+        let mut out = Vec::new();
+        // answered into `out`, logged once it is flushed
+        let mut unflushed: Vec<Answered> = Vec::new();
+        let mut next = Some(first);
+        let mut batch = 0;
+        let mut keep_alive = true;
+        while let Some(parsed) = next.take() {
+            let (mut request, pending) = match parsed {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    log_parse_error(logging, remote, &e);
+                    if let Some(status) = e.status_code() {
+                        let response = (status, ()).into_response();
+                        let ctx = EncodeContext {
+                            keep_alive: false,
+                            server: config.server_header.as_deref(),
+                            ..Default::default()
+                        };
+                        out.extend_from_slice(&encode(response, &ctx).bytes);
                     }
-                }
-                // path not found
-                Err(RouterError::PathNotFound) => {
-                    let _ = write_async(&mut stream, StatusCode::NOT_FOUND.as_str()).await;
+                    keep_alive = false;
                     break;
                 }
-                //method not allowed
-                Err(RouterError::MethodNotAllowed) => {
-                    let _ = write_async(&mut stream, StatusCode::METHOD_NOT_ALLOWED.as_str()).await;
-                    break;
+            };
+            served += 1;
+            batch += 1;
+
+            keep_alive = config.keep_alive
+                && served < config.max_requests_per_connection
+                && wants_keep_alive(&request);
+            let is_http10 = request.get_version() == "HTTP/1.0";
+            let method = request.get_method().clone();
+            let path = request.get_path().to_string();
+            let started = Instant::now();
+            // before anything else looks at the request, so spans, panic
+            // reports and log lines all carry the id
+            let router = &service.router;
+            let request_id = router.request_id().map(|ids| ids.assign(&mut request));
+            let span = trace::request(&request, remote);
+            // only copied when there is an access log to write them to
+            let (version, user_agent, referer) = if logging.enabled(LogLevel::Info) {
+                let header = |name| request.get_header(name).map(str::to_string);
+                let version = request.get_version().to_string();
+                (version, header("User-Agent"), header("Referer"))
+            } else {
+                (String::new(), None, None)
+            };
+
+            let conn = Connection {
+                stream: &mut stream,
+                buf: &mut buf,
+                service: &service,
+                config: &config,
+                remote,
+            };
+            let answered = answer(conn, request, pending);
+            let (mut response, reusable) = trace::instrument(answered, &span).await;
+            if let Some(error) = response.take_error() {
+                trace::handler_error(&span, &error);
+                let id = request_id.as_deref();
+                report_error(&error, &config.logging, remote, &method, &path, id);
+            }
+            if let (Some(ids), Some(id)) = (router.request_id(), &request_id) {
+                ids.echo(&mut response, id);
+            }
+            let status = response.status_code();
+            trace::handled(&span, status);
+            let ctx = EncodeContext {
+                method: &method,
+                http10: is_http10,
+                keep_alive: keep_alive && reusable,
+                server: config.server_header.as_deref(),
+            };
+            let encoded = encode(response, &ctx);
+            keep_alive = encoded.keep_alive;
+            out.extend_from_slice(&encoded.bytes);
+            let mut answered = Answered {
+                span,
+                method,
+                path,
+                version,
+                status,
+                bytes: encoded.body_len,
+                started,
+                user_agent,
+                referer,
+                request_id,
+            };
+
+            match encoded.body {
+                Some(body) => {
+                    // whatever is queued goes out first so responses stay in order
+                    let queued = write_async(&mut stream, &out).await;
+                    out.clear();
+                    let written = match queued {
+                        Ok(()) => {
+                            for earlier in unflushed.drain(..) {
+                                earlier.written(logging, remote);
+                            }
+                            let framing = encoded.framing;
+                            write_body(&mut stream, body, framing, &mut answered.bytes).await
+                        }
+                        Err(e) => Err(e),
+                    };
+                    match written {
+                        Ok(complete) => {
+                            keep_alive = keep_alive && complete;
+                            answered.written(logging, remote);
+                        }
+                        Err(e) => {
+                            for failed in unflushed.drain(..).chain([answered]) {
+                                failed.failed(&e);
+                            }
+                            log_io_error(logging, remote, &e);
+                            return;
+                        }
+                    }
                 }
-            },
-            Err(e) => {
-                eprintln!("ERRORED INSIDE PARSING: {:?}", e);
-                let _ = write_async(&mut stream, StatusCode::INTERNAL_SERVER_ERROR.as_str()).await;
+                None => unflushed.push(answered),
+            }
+
+            if keep_alive && batch < config.max_pipelined_requests {
+                // pipelined requests are only taken once they arrived in full
+                next = parse_buffered(&mut buf, &config)
+                    .map(|parsed| parsed.map(|request| (request, 0)))
+                    .transpose();
+            }
+        }
+
+        if !out.is_empty() {
+            if let Err(e) = write_async(&mut stream, &out).await {
+                for failed in unflushed {
+                    failed.failed(&e);
+                }
+                log_io_error(logging, remote, &e);
                 break;
             }
         }
+        for answered in unflushed {
+            answered.written(logging, remote);
+        }
+        if !keep_alive {
+            break;
+        }
+    }
+}
+
+// what the access log and the request span get to know about a response,
+// kept until it is out on the wire
+struct Answered {
+    span: trace::Span,
+    method: Method,
+    path: String,
+    version: String,
+    status: StatusCode,
+    // body bytes, of a stream the ones written so far
+    bytes: u64,
+    started: Instant,
+    user_agent: Option<String>,
+    referer: Option<String>,
+    request_id: Option<String>,
+}
+
+impl Answered {
+    // the latency includes writing the response
+    fn written(self, logging: &Logging, remote: Option<SocketAddr>) {
+        trace::written(&self.span, self.bytes, None);
+        logging.access(&Access {
+            remote,
+            method: &self.method,
+            path: &self.path,
+            version: &self.version,
+            status: self.status,
+            bytes: self.bytes,
+            latency: self.started.elapsed(),
+            user_agent: self.user_agent.as_deref(),
+            referer: self.referer.as_deref(),
+            request_id: self.request_id.as_deref(),
+        });
+    }
+
+    // never made it to the client in full, the error is logged once for the
+    // connection and the access log only has responses that went out
+    fn failed(self, error: &io::Error) {
+        trace::written(&self.span, self.bytes, Some(error));
+    }
+}
+
+// the details stay in the log, the client only got the public part, rendered
+// in the configured format when the error turned into a response
+fn report_error(
+    error: &HandlerError,
+    logging: &Logging,
+    remote: Option<SocketAddr>,
+    method: &Method,
+    path: &str,
+    request_id: Option<&str>,
+) {
+    let status = error.status_code();
+    let level = if status.is_server_error() {
+        LogLevel::Error
+    } else {
+        LogLevel::Warn
+    };
+    logging.event(
+        level,
+        "handler_error",
+        &[
+            ("remote", addr(remote)),
+            ("method", method.to_string()),
+            ("path", path.to_string()),
+            ("status", status.as_u16().to_string()),
+            ("request_id", request_id.unwrap_or_default().to_string()),
+            ("error", error.chain()),
+        ],
+    );
+}
+
+// a client going away is business as usual, a request we can't make sense of
+// is worth a warning
+fn log_parse_error(logging: &Logging, remote: Option<SocketAddr>, error: &ParseError) {
+    trace::parse_failed(error);
+    match error {
+        ParseError::CloseConn | ParseError::Idle => logging.event(
+            LogLevel::Debug,
+            "connection_closed",
+            &[("remote", addr(remote)), ("reason", error.to_string())],
+        ),
+        ParseError::Io(e) => log_io_error(logging, remote, e),
+        _ => logging.event(
+            LogLevel::Warn,
+            "parse_failed",
+            &[
+                ("remote", addr(remote)),
+                (
+                    "status",
+                    error.status_code().map_or(0, |s| s.as_u16()).to_string(),
+                ),
+                ("error", error.to_string()),
+            ],
+        ),
+    }
+}
+
+fn log_io_error(logging: &Logging, remote: Option<SocketAddr>, error: &io::Error) {
+    let level = match error.kind() {
+        io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::BrokenPipe
+        | io::ErrorKind::UnexpectedEof => LogLevel::Debug,
+        _ => LogLevel::Warn,
+    };
+    logging.event(
+        level,
+        "io_error",
+        &[("remote", addr(remote)), ("error", error.to_string())],
+    );
+}
+
+fn addr(remote: Option<SocketAddr>) -> String {
+    remote.map_or_else(|| "-".to_string(), |addr| addr.to_string())
+}
+
+// HTTP/1.1 connections are persistent unless the client says otherwise,
+// HTTP/1.0 ones have to opt in
+fn wants_keep_alive(request: &Request) -> bool {
+    let connection = request.get_header("Connection").unwrap_or("");
+    let has_token = |token: &str| {
+        connection
+            .split(',')
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    };
+
+    if has_token("close") {
+        return false;
+    }
+    match request.get_version() {
+        "HTTP/1.1" => true,
+        "HTTP/1.0" => has_token("keep-alive"),
+        _ => false,
+    }
+}
+
+// what answering a request needs of the connection it came in on
+struct Connection<'a> {
+    stream: &'a mut tokio::net::TcpStream,
+    // bytes read past the head of the request
+    buf: &'a mut Vec<u8>,
+    service: &'a RouterService,
+    config: &'a ServerConfig,
+    remote: Option<SocketAddr>,
+}
+
+// Routes the request and reads the `pending` part of its body off the wire, but
+// only once it is clear the matched handler is going to look at it. An
+// `Expect: 100-continue` client gets its go ahead even later, when the handler
+// asks for the body after the route's layers let the request through. The flag
+// is false when the body had to be left unread and the connection can't be reused.
+async fn answer(conn: Connection<'_>, mut request: Request, pending: usize) -> (Response, bool) {
+    let expects_continue = match request.get_header("Expect") {
+        None => false,
+        Some(expect) if expect.eq_ignore_ascii_case("100-continue") => true,
+        Some(_) => {
+            return (
+                (StatusCode::EXPECTATION_FAILED, ()).into_response(),
+                pending == 0,
+            )
+        }
+    };
+    // 1.0 clients don't know about 100 Continue and send the body right away
+    let expects_continue = expects_continue && request.get_version() == "HTTP/1.1";
+
+    let (service, config) = (conn.service, conn.config);
+    let route = service
+        .router
+        .matches(request.get_path(), request.get_method().clone());
+    trace::routed(route.as_ref().ok().map(|route_match| route_match.pattern));
+    let reads_body = matches!(&route, Ok(route_match) if route_match.reads_body);
+
+    if expects_continue && reads_body {
+        if let Ok(RouteMatch {
+            expect_check: Some(check),
+            ..
+        }) = &route
+        {
+            if let Err(status) = check(&request) {
+                return ((status, ()).into_response(), pending == 0);
+            }
+        }
+    }
+
+    if pending > 0 {
+        if expects_continue && !reads_body {
+            // the client still waits for us, whatever it sends next could be
+            // the body or a new request
+            return (dispatch(service, route, request, config).await, false);
+        }
+        if expects_continue && conn.buf.len() < pending {
+            return answer_continue(conn, route, request, pending).await;
+        }
+        // a body nobody reads still has to come off the wire to find the next request
+        match read_body(conn.stream, conn.buf, config, pending).await {
+            Ok(body) if reads_body => request.set_body(body),
+            Ok(_) => {}
+            Err(e) => {
+                log_parse_error(&config.logging, conn.remote, &e);
+                let status = e.status_code().unwrap_or(StatusCode::BAD_REQUEST);
+                return ((status, ()).into_response(), false);
+            }
+        }
+    }
+
+    (dispatch(service, route, request, config).await, true)
+}
+
+// the client waits for a 100 Continue before sending the body, that only goes
+// out once the handler asks for the body, layers answering before that leave
+// it unsent and the connection unusable
+async fn answer_continue(
+    conn: Connection<'_>,
+    route: Result<RouteMatch<'_>, RouterError>,
+    mut request: Request,
+    pending: usize,
+) -> (Response, bool) {
+    let Connection {
+        stream,
+        buf,
+        service,
+        config,
+        remote,
+    } = conn;
+    let (body, asked) = PendingBody::new();
+    request.extensions_mut().insert(body);
+    let dispatched = dispatch(service, route, request, config);
+    tokio::pin!(dispatched);
+
+    let reply = tokio::select! {
+        response = &mut dispatched => return (response, false),
+        reply = asked => reply,
+    };
+    let Ok(reply) = reply else {
+        // the request went away without anybody reading the body
+        return (dispatched.await, false);
+    };
+
+    let go_ahead = write_async(stream, b"HTTP/1.1 100 Continue\r\n\r\n").await;
+    if go_ahead.is_err() {
+        return ((StatusCode::BAD_REQUEST, ()).into_response(), false);
+    }
+    match read_body(stream, buf, config, pending).await {
+        Ok(body) => {
+            let _ = reply.send(body);
+            (dispatched.await, true)
+        }
+        Err(e) => {
+            log_parse_error(&config.logging, remote, &e);
+            let status = e.status_code().unwrap_or(StatusCode::BAD_REQUEST);
+            ((status, ()).into_response(), false)
+        }
     }
 }
 
-#[derive(Clone)]
-pub enum StatusCode {
-    ALL_OK,
-    INTERNAL_SERVER_ERROR,
-    NOT_FOUND,
-    CREATED,
-    ACCEPTED,
-    BAD_REQUEST,
-    UNAUTHORIZED,
-    FORBIDDEN,
-    METHOD_NOT_ALLOWED,
+async fn dispatch(
+    service: &RouterService,
+    route: Result<RouteMatch<'_>, RouterError>,
+    mut request: Request,
+    config: &ServerConfig,
+) -> Response {
+    let router = &service.router;
+    let catch_panic = router.catch_panic();
+    let logging = &config.logging;
+    let called = async move {
+        match route {
+            Ok(route_match) => {
+                let extensions = request.extensions_mut();
+                extensions.insert(MatchedPath(route_match.pattern.to_string()));
+                extensions.insert(PathParams(route_match.params));
+                catch_panic
+                    .call(request, route_match.pattern, route_match.service, logging)
+                    .await
+            }
+            // 404 and 405 still go through the router's layers
+            Err(error) => {
+                catch_panic
+                    .call(request, "-", router.fallback(&error), logging)
+                    .await
+            }
+        }
+    };
+    // handler errors turn into responses in the configured format before any
+    // layer sees them
+    with_error_format(config.error_format, called).await
 }
 
-impl StatusCode {
-    pub fn as_str(&self) -> &'static str {
-        match self {
-            StatusCode::ALL_OK => "HTTP/1.1 200 OK\r\n\r\n",
-            StatusCode::INTERNAL_SERVER_ERROR => "HTTP/1.1 500 Internal Server Error\r\n\r\n",
-            StatusCode::NOT_FOUND => "HTTP/1.1 404 Not Found\r\n\r\n",
-            StatusCode::CREATED => "HTTP/1.1 201 Created\r\n\r\n",
-            StatusCode::ACCEPTED => "HTTP/1.1 202 Accepted\r\n\r\n",
-            StatusCode::BAD_REQUEST => "HTTP/1.1 400 Bad Request\r\n\r\n",
-            StatusCode::UNAUTHORIZED => "HTTP/1.1 401 Unauthorized\r\n\r\n",
-            StatusCode::FORBIDDEN => "HTTP/1.1 403 Forbidden\r\n\r\n",
-            StatusCode::METHOD_NOT_ALLOWED => "HTTP/1.1 405 Method Not Allowed\r\n\r\n",
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::body::Body;
+    use crate::into_response::ErrorFormat;
+    use crate::into_response::ResponseBuilder;
+    use crate::server::auth::BasicAuth;
+    use crate::server::compression::Compression;
+    use crate::server::middleware::{from_fn, Next};
+    use crate::server::router::{Route, Router};
+    use crate::HandlerTypes;
+    use bytes::Bytes;
+    use futures_util::stream;
+    use std::io::Read;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn router() -> RouterService {
+        Router::builder()
+            .route("/a", HandlerTypes::empty(|| "a", Method::GET))
+            .route("/b", HandlerTypes::empty(|| "bb", Method::GET))
+            .route(
+                "/echo",
+                HandlerTypes::body(
+                    |request: Request| request.body.unwrap_or_default(),
+                    Method::POST,
+                ),
+            )
+            .route(
+                "/private",
+                Route::new(HandlerTypes::body(
+                    |request: Request| request.body.unwrap_or_default(),
+                    Method::POST,
+                ))
+                .layer(BasicAuth::user("private", "root", "pw")),
+            )
+            .route(
+                "/stream",
+                HandlerTypes::empty(
+                    || {
+                        let parts: Vec<Result<Bytes, io::Error>> =
+                            vec![Ok(Bytes::from("st")), Ok(Bytes::from("ream"))];
+                        ResponseBuilder::new()
+                            .status_code(StatusCode::OK)
+                            .with_body(Body::from_stream(stream::iter(parts)))
+                            .build()
+                    },
+                    Method::GET,
+                ),
+            )
+            .build()
+            .into_service()
+    }
+
+    fn quiet() -> ServerConfig {
+        ServerConfig::new().logging(Logging::new().level(LogLevel::Off))
+    }
+
+    async fn connect() -> (tokio::net::TcpStream, tokio::task::JoinHandle<()>) {
+        connect_to(router(), quiet()).await
+    }
+
+    async fn connect_to(
+        service: RouterService,
+        config: ServerConfig,
+    ) -> (tokio::net::TcpStream, tokio::task::JoinHandle<()>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let connection = tokio::spawn(handle_connection(server, service, Arc::new(config)));
+        (client, connection)
+    }
+
+    // what the server answers to `raw` sent in one go, until it closes
+    async fn exchange(raw: &str) -> String {
+        let (mut client, connection) = connect().await;
+        client.write_all(raw.as_bytes()).await.unwrap();
+        let mut answer = Vec::new();
+        client.read_to_end(&mut answer).await.unwrap();
+        connection.await.unwrap();
+        String::from_utf8(answer).unwrap()
+    }
+
+    // status lines and bodies in the order they arrived, headers dropped
+    fn summary(answer: &str) -> Vec<String> {
+        let mut parts = Vec::new();
+        let mut rest = answer;
+        while let Some((head, after)) = rest.split_once("\r\n\r\n") {
+            let status = head.lines().next().unwrap_or_default().to_string();
+            let chunked = head.contains("Transfer-Encoding: chunked");
+            let length = head
+                .lines()
+                .find_map(|line| line.strip_prefix("Content-Length: "))
+                .map(|length| length.parse::<usize>().unwrap());
+            let (body, after) = match (length, chunked) {
+                (Some(length), _) => after.split_at(length),
+                (None, true) => {
+                    let end = after.find("0\r\n\r\n").unwrap() + 5;
+                    after.split_at(end)
+                }
+                (None, false) => (after, ""),
+            };
+            parts.push(format!("{} {}", status, body));
+            rest = after;
         }
+        parts
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests_are_answered_in_order() {
+        let raw = "GET /b HTTP/1.1\r\n\r\n\
+                   POST /echo HTTP/1.1\r\nContent-Length: 4\r\n\r\nbody\
+                   GET /stream HTTP/1.1\r\n\r\n\
+                   GET /missing HTTP/1.1\r\n\r\n\
+                   GET /a HTTP/1.1\r\nConnection: close\r\n\r\n";
+        assert_eq!(
+            summary(&exchange(raw).await),
+            [
+                "HTTP/1.1 200 OK bb",
+                "HTTP/1.1 200 OK body",
+                "HTTP/1.1 200 OK 2\r\nst\r\n4\r\nream\r\n0\r\n\r\n",
+                "HTTP/1.1 404 Not Found ",
+                "HTTP/1.1 200 OK a",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn requests_after_a_close_are_not_answered() {
+        let raw = "GET /a HTTP/1.1\r\nConnection: close\r\n\r\nGET /b HTTP/1.1\r\n\r\n";
+        assert_eq!(summary(&exchange(raw).await), ["HTTP/1.1 200 OK a"]);
+    }
+
+    #[tokio::test]
+    async fn a_broken_request_ends_the_pipeline_after_the_earlier_answers() {
+        let raw =
+            "GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\nBad Name: x\r\n\r\nGET /a HTTP/1.1\r\n\r\n";
+        let answer = exchange(raw).await;
+        assert_eq!(
+            summary(&answer),
+            ["HTTP/1.1 200 OK a", "HTTP/1.1 400 Bad Request "]
+        );
+        assert!(answer.ends_with("Connection: close\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn layers_turn_down_an_expect_continue_request_before_its_body() {
+        let raw = "POST /private HTTP/1.1\r\nContent-Length: 4\r\nExpect: 100-continue\r\n\r\n";
+        let answer = exchange(raw).await;
+        assert!(!answer.contains("100 Continue"));
+        assert!(answer.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        assert!(answer.contains("Connection: close\r\n"));
+    }
+
+    #[tokio::test]
+    async fn the_body_is_asked_for_once_the_layers_let_the_request_through() {
+        let (mut client, connection) = connect().await;
+        let head = "POST /private HTTP/1.1\r\nContent-Length: 4\r\nExpect: 100-continue\r\n\
+                    Authorization: Basic cm9vdDpwdw==\r\nConnection: close\r\n\r\n";
+        client.write_all(head.as_bytes()).await.unwrap();
+
+        let mut interim = [0; 25];
+        client.read_exact(&mut interim).await.unwrap();
+        assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+
+        client.write_all(b"body").await.unwrap();
+        let mut answer = Vec::new();
+        client.read_to_end(&mut answer).await.unwrap();
+        connection.await.unwrap();
+        let answer = String::from_utf8(answer).unwrap();
+        assert_eq!(summary(&answer), ["HTTP/1.1 200 OK body"]);
+    }
+
+    fn failing() -> Result<&'static str, HandlerError> {
+        Err(HandlerError::bad_request("no ".repeat(700)).code("nope"))
+    }
+
+    // everything the server sent for `raw` on a `router` with problem+json
+    // errors, split into head and body
+    async fn problem_exchange(router: RouterService, raw: &str) -> (String, Vec<u8>) {
+        let config = quiet().error_format(ErrorFormat::ProblemJson);
+        let (mut client, connection) = connect_to(router, config).await;
+        client.write_all(raw.as_bytes()).await.unwrap();
+        let mut answer = Vec::new();
+        client.read_to_end(&mut answer).await.unwrap();
+        connection.await.unwrap();
+        let end = answer.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let body = answer.split_off(end + 4);
+        (String::from_utf8(answer).unwrap(), body)
+    }
+
+    #[tokio::test]
+    async fn problem_json_errors_go_through_compression_like_other_bodies() {
+        let router = Router::builder()
+            .route("/fail", HandlerTypes::empty(failing, Method::GET))
+            .compression(Compression::new())
+            .build()
+            .into_service();
+        let raw = "GET /fail HTTP/1.1\r\nAccept-Encoding: gzip\r\nConnection: close\r\n\r\n";
+        let (head, body) = problem_exchange(router, raw).await;
+        assert!(head.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(head.contains("Content-Type: application/problem+json\r\n"));
+        assert!(head.contains("Content-Encoding: gzip\r\n"));
+
+        let mut json = String::new();
+        flate2::read::GzDecoder::new(&body[..])
+            .read_to_string(&mut json)
+            .unwrap();
+        let problem: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(problem["status"], 400);
+        assert_eq!(problem["code"], "nope");
+        assert_eq!(problem["detail"], "no ".repeat(700));
+    }
+
+    #[tokio::test]
+    async fn layers_rewriting_an_error_have_the_last_word() {
+        let router = Router::builder()
+            .route("/fail", HandlerTypes::empty(failing, Method::GET))
+            .layer(from_fn(|request: Request, next: Next| async move {
+                let mut response = next.run(request).await;
+                assert_eq!(
+                    response.headers().get("Content-Type"),
+                    Some("application/problem+json")
+                );
+                response.headers_mut().insert("Content-Type", "text/plain");
+                *response.body_mut() = "rewritten".into();
+                response
+            }))
+            .build()
+            .into_service();
+        let raw = "GET /fail HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let (head, body) = problem_exchange(router, raw).await;
+        assert!(head.contains("Content-Type: text/plain\r\n"));
+        assert_eq!(body, b"rewritten");
+    }
+
+    #[tokio::test]
+    async fn the_router_puts_the_matched_route_into_the_extensions() {
+        let handler = |request: Request| {
+            let extensions = request.extensions();
+            let pattern = extensions.get::<MatchedPath>().unwrap().0.clone();
+            let id = extensions.get::<PathParams>().unwrap().0["id"].clone();
+            format!("{} {}", pattern, id)
+        };
+        let nested = Router::builder()
+            .route("/item/{id}", HandlerTypes::full(handler, Method::GET))
+            .build();
+        let router = Router::builder()
+            .nest("/api", nested)
+            .build()
+            .into_service();
+        let (mut client, connection) = connect_to(router, quiet()).await;
+        let raw = "GET /api/item/7?full=1 HTTP/1.1\r\nConnection: close\r\n\r\n";
+        client.write_all(raw.as_bytes()).await.unwrap();
+        let mut answer = String::new();
+        client.read_to_string(&mut answer).await.unwrap();
+        connection.await.unwrap();
+        assert_eq!(summary(&answer), ["HTTP/1.1 200 OK /api/item/{id} 7"]);
     }
 }
@@ -0,0 +1,477 @@
+use std::marker::PhantomData;
+use std::path::Path;
+use std::time::Duration;
+use std::{fs, io};
+
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm, PublicKeyUse,
+};
+use jsonwebtoken::{errors::ErrorKind, Algorithm, DecodingKey, Validation};
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+
+use super::auth::{authorization, BearerAuth};
+use super::middleware::{BoxService, Layer};
+use crate::client::client::Request;
+
+/// Why a key set couldn't be loaded or a token wasn't accepted.
+#[derive(Error, Debug)]
+pub enum JwtError {
+    #[error("could not read the key set: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid key set: {0}")]
+    KeySet(#[from] serde_json::Error),
+    #[error("invalid key: {0}")]
+    Key(jsonwebtoken::errors::Error),
+    #[error("request has no bearer token")]
+    Missing,
+    #[error("no key for the token's algorithm and key id")]
+    UnknownKey,
+    #[error("invalid token: {0}")]
+    Token(jsonwebtoken::errors::Error),
+}
+
+// a verification key with what it may verify
+#[derive(Clone)]
+struct Key {
+    id: Option<String>,
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+/// Validates JSON Web Tokens, HS256 with a shared secret, RS256 and ES256
+/// with public keys given as PEM or as a JWK set. The signature, `exp`, `nbf`
+/// and, when configured, `iss` and `aud` are checked, then the claims are
+/// deserialized into `C`. As a layer it takes the token from
+/// `Authorization: Bearer` and puts the claims into the request's extensions,
+/// answering `401` with a `Bearer` challenge when there is no valid token.
+///
+/// ```ignore
+/// #[derive(Clone, Deserialize)]
+/// struct Claims {
+///     sub: String,
+/// }
+///
+/// let jwt = Jwt::<Claims>::new()
+///     .jwk_set_file("jwks.json")?
+///     .issuer("https://id.example.com")
+///     .audience("orders")
+///     .leeway(Duration::from_secs(30));
+///
+/// let router = Router::builder()
+///     .route("/orders", HandlerTypes::full(orders, Method::GET))
+///     .layer(jwt)
+///     .build();
+///
+/// fn orders(request: Request) -> Result<String, ExtractError> {
+///     let Extension(claims) = request.extension::<Claims>()?;
+///     ...
+/// }
+/// ```
+pub struct Jwt<C> {
+    keys: Vec<Key>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    leeway: Duration,
+    realm: String,
+    claims: PhantomData<fn() -> C>,
+}
+
+// derived Clone would want `C: Clone` for the marker
+impl<C> Clone for Jwt<C> {
+    fn clone(&self) -> Self {
+        Self {
+            keys: self.keys.clone(),
+            issuer: self.issuer.clone(),
+            audience: self.audience.clone(),
+            leeway: self.leeway,
+            realm: self.realm.clone(),
+            claims: PhantomData,
+        }
+    }
+}
+
+impl<C> Default for Jwt<C> {
+    fn default() -> Self {
+        Self {
+            keys: Vec::new(),
+            issuer: None,
+            audience: None,
+            leeway: Duration::from_secs(60),
+            realm: "api".to_string(),
+            claims: PhantomData,
+        }
+    }
+}
+
+impl<C: DeserializeOwned + Clone + Send + Sync + 'static> Jwt<C> {
+    /// no keys yet, a minute of leeway, no issuer or audience checks
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// shared secret for HS256 tokens
+    pub fn hs256(mut self, secret: &[u8]) -> Self {
+        self.keys.push(Key {
+            id: None,
+            algorithm: Algorithm::HS256,
+            key: DecodingKey::from_secret(secret),
+        });
+        self
+    }
+
+    /// PEM encoded RSA public key for RS256 tokens
+    pub fn rs256_pem(mut self, pem: &[u8]) -> Result<Self, JwtError> {
+        let key = DecodingKey::from_rsa_pem(pem).map_err(JwtError::Key)?;
+        self.keys.push(Key {
+            id: None,
+            algorithm: Algorithm::RS256,
+            key,
+        });
+        Ok(self)
+    }
+
+    /// PEM encoded P-256 public key for ES256 tokens
+    pub fn es256_pem(mut self, pem: &[u8]) -> Result<Self, JwtError> {
+        let key = DecodingKey::from_ec_pem(pem).map_err(JwtError::Key)?;
+        self.keys.push(Key {
+            id: None,
+            algorithm: Algorithm::ES256,
+            key,
+        });
+        Ok(self)
+    }
+
+    /// Adds the RS256 and ES256 signing keys of a JWK set, what identity
+    /// providers publish under `jwks_uri`. Keys for encryption or other
+    /// algorithms are skipped.
+    pub fn jwk_set(mut self, json: &str) -> Result<Self, JwtError> {
+        let set: JwkSet = serde_json::from_str(json)?;
+        for jwk in &set.keys {
+            if let Some(algorithm) = jwk_algorithm(jwk) {
+                let key = DecodingKey::from_jwk(jwk).map_err(JwtError::Key)?;
+                self.keys.push(Key {
+                    id: jwk.common.key_id.clone(),
+                    algorithm,
+                    key,
+                });
+            }
+        }
+        Ok(self)
+    }
+
+    /// `jwk_set` read from a local file, fetching it is left to the deployment
+    pub fn jwk_set_file(self, path: impl AsRef<Path>) -> Result<Self, JwtError> {
+        let json = fs::read_to_string(path)?;
+        self.jwk_set(&json)
+    }
+
+    /// required value of `iss`
+    pub fn issuer(mut self, issuer: &str) -> Self {
+        self.issuer = Some(issuer.to_string());
+        self
+    }
+
+    /// value `aud` has to be or contain
+    pub fn audience(mut self, audience: &str) -> Self {
+        self.audience = Some(audience.to_string());
+        self
+    }
+
+    /// clock skew allowed for `exp` and `nbf`
+    pub fn leeway(mut self, leeway: Duration) -> Self {
+        self.leeway = leeway;
+        self
+    }
+
+    /// realm of the `WWW-Authenticate` challenge, `api` by default
+    pub fn realm(mut self, realm: &str) -> Self {
+        self.realm = realm.to_string();
+        self
+    }
+
+    /// Verifies a compact JWS and returns its claims.
+    pub fn decode(&self, token: &str) -> Result<C, JwtError> {
+        let header = jsonwebtoken::decode_header(token).map_err(JwtError::Token)?;
+        // keys without an id are tried for any token of their algorithm
+        let candidates = self.keys.iter().filter(|key| {
+            key.algorithm == header.alg
+                && match (&key.id, &header.kid) {
+                    (Some(id), Some(kid)) => id == kid,
+                    _ => true,
+                }
+        });
+
+        let mut result = Err(JwtError::UnknownKey);
+        for key in candidates {
+            result = jsonwebtoken::decode::<C>(token, &key.key, &self.validation(key.algorithm))
+                .map(|data| data.claims)
+                .map_err(JwtError::Token);
+            match &result {
+                // another key might have signed it, the claims are the same for all
+                Err(JwtError::Token(e)) if *e.kind() == ErrorKind::InvalidSignature => continue,
+                _ => break,
+            }
+        }
+        result
+    }
+
+    /// The claims of the bearer token `request` came with, for handlers
+    /// checking tokens themselves rather than through the layer.
+    pub fn claims(&self, request: &Request) -> Result<C, JwtError> {
+        let token = authorization(request, "Bearer").ok_or(JwtError::Missing)?;
+        self.decode(token)
+    }
+
+    fn validation(&self, algorithm: Algorithm) -> Validation {
+        let mut validation = Validation::new(algorithm);
+        validation.leeway = self.leeway.as_secs();
+        validation.validate_nbf = true;
+        // `iss` and `aud` are only compared when present, a configured check
+        // has to make them mandatory as well
+        let mut required = vec!["exp"];
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+            required.push("iss");
+        }
+        match &self.audience {
+            Some(audience) => {
+                validation.set_audience(&[audience]);
+                required.push("aud");
+            }
+            None => validation.validate_aud = false,
+        }
+        validation.set_required_spec_claims(&required);
+        validation
+    }
+}
+
+impl<C: DeserializeOwned + Clone + Send + Sync + 'static> Layer for Jwt<C> {
+    fn layer(&self, inner: BoxService) -> BoxService {
+        let jwt = self.clone();
+        BearerAuth::new(&self.realm, move |token| jwt.decode(token).ok()).layer(inner)
+    }
+}
+
+// the algorithm a JWK verifies, named or implied by its key type
+fn jwk_algorithm(jwk: &Jwk) -> Option<Algorithm> {
+    if let Some(PublicKeyUse::Encryption) = jwk.common.public_key_use {
+        return None;
+    }
+    match (&jwk.common.key_algorithm, &jwk.algorithm) {
+        (Some(KeyAlgorithm::RS256), AlgorithmParameters::RSA(_)) => Some(Algorithm::RS256),
+        (Some(KeyAlgorithm::ES256), AlgorithmParameters::EllipticCurve(_)) => {
+            Some(Algorithm::ES256)
+        }
+        (None, AlgorithmParameters::RSA(_)) => Some(Algorithm::RS256),
+        (None, AlgorithmParameters::EllipticCurve(ec)) if ec.curve == EllipticCurve::P256 => {
+            Some(Algorithm::ES256)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::client::Method;
+    use jsonwebtoken::{encode, get_current_timestamp, EncodingKey, Header};
+    use serde::Deserialize;
+    use serde_json::{json, Value};
+
+    const SECRET: &[u8] = b"test secret";
+
+    #[derive(Clone, Debug, Deserialize)]
+    struct Claims {
+        sub: String,
+    }
+
+    fn token(claims: Value) -> String {
+        encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(SECRET),
+        )
+        .unwrap()
+    }
+
+    fn jwt() -> Jwt<Claims> {
+        Jwt::new().hs256(SECRET).leeway(Duration::from_secs(5))
+    }
+
+    fn kind(result: Result<Claims, JwtError>) -> ErrorKind {
+        match result {
+            Err(JwtError::Token(e)) => e.into_kind(),
+            other => panic!("expected a token error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decodes_valid_claims() {
+        let exp = get_current_timestamp() + 60;
+        let claims = jwt().decode(&token(json!({"sub": "ferris", "exp": exp})));
+        assert_eq!(claims.unwrap().sub, "ferris");
+    }
+
+    #[test]
+    fn rejects_expired_tokens_past_the_leeway() {
+        let now = get_current_timestamp();
+        let within = token(json!({"sub": "a", "exp": now - 2}));
+        assert!(jwt().decode(&within).is_ok());
+        let expired = token(json!({"sub": "a", "exp": now - 60}));
+        assert_eq!(kind(jwt().decode(&expired)), ErrorKind::ExpiredSignature);
+    }
+
+    #[test]
+    fn rejects_tokens_not_yet_valid() {
+        let now = get_current_timestamp();
+        let early = token(json!({"sub": "a", "exp": now + 600, "nbf": now + 300}));
+        assert_eq!(kind(jwt().decode(&early)), ErrorKind::ImmatureSignature);
+    }
+
+    #[test]
+    fn requires_exp() {
+        let forever = token(json!({"sub": "a"}));
+        let missing = ErrorKind::MissingRequiredClaim("exp".to_string());
+        assert_eq!(kind(jwt().decode(&forever)), missing);
+    }
+
+    #[test]
+    fn configured_issuer_and_audience_are_required() {
+        let jwt = jwt().issuer("skibidi").audience("orders");
+        let exp = get_current_timestamp() + 60;
+
+        let bare = token(json!({"sub": "a", "exp": exp}));
+        assert!(matches!(
+            kind(jwt.decode(&bare)),
+            ErrorKind::MissingRequiredClaim(_)
+        ));
+        let no_aud = token(json!({"sub": "a", "exp": exp, "iss": "skibidi"}));
+        let missing = ErrorKind::MissingRequiredClaim("aud".to_string());
+        assert_eq!(kind(jwt.decode(&no_aud)), missing);
+
+        let wrong_iss = token(json!({"sub": "a", "exp": exp, "iss": "other", "aud": "orders"}));
+        assert_eq!(kind(jwt.decode(&wrong_iss)), ErrorKind::InvalidIssuer);
+        let wrong_aud = token(json!({"sub": "a", "exp": exp, "iss": "skibidi", "aud": "billing"}));
+        assert_eq!(kind(jwt.decode(&wrong_aud)), ErrorKind::InvalidAudience);
+
+        let good = token(json!({"sub": "a", "exp": exp, "iss": "skibidi", "aud": ["x", "orders"]}));
+        assert!(jwt.decode(&good).is_ok());
+    }
+
+    #[test]
+    fn rejects_other_secrets_and_algorithms() {
+        let exp = get_current_timestamp() + 60;
+        let claims = json!({"sub": "a", "exp": exp});
+        let forged = encode(&Header::default(), &claims, &EncodingKey::from_secret(b"x")).unwrap();
+        assert_eq!(kind(jwt().decode(&forged)), ErrorKind::InvalidSignature);
+
+        // no RS256 key configured, the HS256 secret must not be tried
+        let rs = format!(
+            "{}.{}",
+            "eyJhbGciOiJSUzI1NiJ9",
+            forged.split_once('.').unwrap().1
+        );
+        assert!(matches!(jwt().decode(&rs), Err(JwtError::UnknownKey)));
+        assert!(matches!(
+            jwt().decode("not a token"),
+            Err(JwtError::Token(_))
+        ));
+    }
+
+    #[test]
+    fn claims_come_from_the_bearer_header() {
+        let exp = get_current_timestamp() + 60;
+        let value = format!("Bearer {}", token(json!({"sub": "ferris", "exp": exp})));
+        let request = Request::with_headers(Method::GET, "/", &[("Authorization", &value)]);
+        assert_eq!(jwt().claims(&request).unwrap().sub, "ferris");
+
+        let anonymous = Request::with_headers(Method::GET, "/", &[]);
+        assert!(matches!(jwt().claims(&anonymous), Err(JwtError::Missing)));
+    }
+
+    const RSA_PRIVATE: &[u8] = include_bytes!("testdata/rsa_private.pem");
+    const RSA_PUBLIC: &[u8] = include_bytes!("testdata/rsa_public.pem");
+    const EC_PRIVATE: &[u8] = include_bytes!("testdata/ec_private.pem");
+    const EC_PUBLIC: &[u8] = include_bytes!("testdata/ec_public.pem");
+
+    // `jwks.json` has the public keys above as `rsa-1` and `ec-1`, the RSA key
+    // again as `rsa-enc` for encryption and a P-384 key as `ec-384`
+    fn jwks_path() -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("src/server/testdata/jwks.json")
+    }
+
+    fn signed(algorithm: Algorithm, kid: Option<&str>) -> String {
+        let key = match algorithm {
+            Algorithm::RS256 => EncodingKey::from_rsa_pem(RSA_PRIVATE).unwrap(),
+            Algorithm::ES256 => EncodingKey::from_ec_pem(EC_PRIVATE).unwrap(),
+            other => panic!("no fixture key for {:?}", other),
+        };
+        let mut header = Header::new(algorithm);
+        header.kid = kid.map(str::to_string);
+        let claims = json!({"sub": "ferris", "exp": get_current_timestamp() + 60});
+        encode(&header, &claims, &key).unwrap()
+    }
+
+    #[test]
+    fn verifies_rs256_and_es256_with_pem_keys() {
+        let jwt = Jwt::<Claims>::new()
+            .rs256_pem(RSA_PUBLIC)
+            .unwrap()
+            .es256_pem(EC_PUBLIC)
+            .unwrap();
+        for algorithm in [Algorithm::RS256, Algorithm::ES256] {
+            let claims = jwt.decode(&signed(algorithm, None)).unwrap();
+            assert_eq!(claims.sub, "ferris");
+            // a key id is of no concern to keys without one
+            assert!(jwt.decode(&signed(algorithm, Some("any"))).is_ok());
+        }
+
+        let rsa_only = Jwt::<Claims>::new().rs256_pem(RSA_PUBLIC).unwrap();
+        let es = signed(Algorithm::ES256, None);
+        assert!(matches!(rsa_only.decode(&es), Err(JwtError::UnknownKey)));
+        assert!(matches!(
+            Jwt::<Claims>::new().rs256_pem(b"not a key"),
+            Err(JwtError::Key(_))
+        ));
+    }
+
+    #[test]
+    fn verifies_with_the_signing_keys_of_a_jwk_set_file() {
+        let jwt = Jwt::<Claims>::new().jwk_set_file(jwks_path()).unwrap();
+        // the encryption and P-384 keys are left out
+        assert_eq!(jwt.keys.len(), 2);
+
+        let rs = jwt.decode(&signed(Algorithm::RS256, Some("rsa-1")));
+        assert_eq!(rs.unwrap().sub, "ferris");
+        let es = jwt.decode(&signed(Algorithm::ES256, Some("ec-1")));
+        assert_eq!(es.unwrap().sub, "ferris");
+        assert!(jwt.decode(&signed(Algorithm::RS256, None)).is_ok());
+    }
+
+    #[test]
+    fn skipped_and_mismatched_key_ids_are_unknown() {
+        let jwt = Jwt::<Claims>::new().jwk_set_file(jwks_path()).unwrap();
+        for (algorithm, kid) in [
+            (Algorithm::RS256, "rsa-enc"),
+            (Algorithm::ES256, "ec-384"),
+            (Algorithm::RS256, "ec-1"),
+            (Algorithm::ES256, "nope"),
+        ] {
+            let token = signed(algorithm, Some(kid));
+            assert!(
+                matches!(jwt.decode(&token), Err(JwtError::UnknownKey)),
+                "{}",
+                kid
+            );
+        }
+    }
+
+    #[test]
+    fn broken_key_sets_are_errors() {
+        let missing = Jwt::<Claims>::new().jwk_set_file("no/such/jwks.json");
+        assert!(matches!(missing, Err(JwtError::Io(_))));
+        let garbage = Jwt::<Claims>::new().jwk_set("{\"keys\": 1}");
+        assert!(matches!(garbage, Err(JwtError::KeySet(_))));
+    }
+}
@@ -0,0 +1,244 @@
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::{Map, Value};
+
+use super::zip::civil_from_days;
+use crate::client::client::Method;
+use crate::status_code::StatusCode;
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// How much the server logs, every level includes the ones above it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    /// access log lines
+    Info,
+    /// connections coming and going, clients hanging up mid response
+    Debug,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Apache's Common Log Format, events as `key=value` pairs
+    Common,
+    /// Common plus referer and user agent
+    Combined,
+    /// one JSON object per line, for access lines and events alike. The only
+    /// format with the latency in it
+    Json,
+}
+
+/// Access log lines on stdout, one per request, and structured events for
+/// parse failures, handler errors, panics and IO errors on stderr. Set it up
+/// with `ServerConfig::logging`.
+///
+/// ```ignore
+/// let config = ServerConfig::new().logging(
+///     Logging::new().format(LogFormat::Json).level(LogLevel::Warn),
+/// );
+/// ```
+#[derive(Clone, Debug)]
+pub struct Logging {
+    level: LogLevel,
+    format: LogFormat,
+}
+
+/// What the access log gets to know about an answered request.
+pub(crate) struct Access<'a> {
+    pub remote: Option<SocketAddr>,
+    pub method: &'a Method,
+    pub path: &'a str,
+    pub version: &'a str,
+    pub status: StatusCode,
+    /// body bytes written, without chunk framing
+    pub bytes: u64,
+    pub latency: Duration,
+    pub user_agent: Option<&'a str>,
+    pub referer: Option<&'a str>,
+    pub request_id: Option<&'a str>,
+}
+
+impl Default for Logging {
+    fn default() -> Self {
+        Self {
+            level: LogLevel::Info,
+            format: LogFormat::Common,
+        }
+    }
+}
+
+impl Logging {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn level(mut self, level: LogLevel) -> Self {
+        self.level = level;
+        self
+    }
+
+    pub fn format(mut self, format: LogFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn enabled(&self, level: LogLevel) -> bool {
+        level != LogLevel::Off && level <= self.level
+    }
+
+    pub(crate) fn access(&self, access: &Access) {
+        if !self.enabled(LogLevel::Info) {
+            return;
+        }
+        let now = SystemTime::now();
+        let remote = access.remote.map(|addr| addr.ip().to_string());
+        let line = match self.format {
+            LogFormat::Common | LogFormat::Combined => {
+                let bytes = match access.bytes {
+                    0 => "-".to_string(),
+                    bytes => bytes.to_string(),
+                };
+                let mut line = format!(
+                    "{} - - [{}] \"{} {} {}\" {} {}",
+                    remote.as_deref().unwrap_or("-"),
+                    clf_time(now),
+                    access.method,
+                    escape(access.path),
+                    access.version,
+                    access.status.as_u16(),
+                    bytes
+                );
+                if self.format == LogFormat::Combined {
+                    line.push_str(&format!(
+                        " \"{}\" \"{}\"",
+                        escape(access.referer.unwrap_or("-")),
+                        escape(access.user_agent.unwrap_or("-"))
+                    ));
+                }
+                line
+            }
+            LogFormat::Json => {
+                let mut object = Map::new();
+                object.insert("time".into(), rfc3339(now).into());
+                object.insert("remote".into(), remote.into());
+                object.insert("method".into(), access.method.as_str().into());
+                object.insert("path".into(), access.path.into());
+                object.insert("version".into(), access.version.into());
+                object.insert("status".into(), access.status.as_u16().into());
+                object.insert("bytes".into(), access.bytes.into());
+                let millis = access.latency.as_secs_f64() * 1000.0;
+                object.insert("latency_ms".into(), millis.into());
+                object.insert("user_agent".into(), access.user_agent.into());
+                object.insert("referer".into(), access.referer.into());
+                object.insert("request_id".into(), access.request_id.into());
+                Value::Object(object).to_string()
+            }
+        };
+        println!("{}", line);
+    }
+
+    /// Logs a named event with its fields, as `key=value` pairs or as JSON.
+    pub(crate) fn event(&self, level: LogLevel, event: &str, fields: &[(&str, String)]) {
+        if !self.enabled(level) {
+            return;
+        }
+        let now = SystemTime::now();
+        let level = match level {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug | LogLevel::Off => "debug",
+        };
+        let line = match self.format {
+            LogFormat::Json => {
+                let mut object = Map::new();
+                object.insert("time".into(), rfc3339(now).into());
+                object.insert("level".into(), level.into());
+                object.insert("event".into(), event.into());
+                for (key, value) in fields {
+                    object.insert(key.to_string(), value.as_str().into());
+                }
+                Value::Object(object).to_string()
+            }
+            LogFormat::Common | LogFormat::Combined => {
+                let mut line = format!("{} {} {}", rfc3339(now), level.to_uppercase(), event);
+                for (key, value) in fields {
+                    line.push_str(&format!(" {}={}", key, logfmt_value(value)));
+                }
+                line
+            }
+        };
+        eprintln!("{}", line);
+    }
+}
+
+// quotes and backslashes are escaped so a request line can't end the field,
+// control characters so it can't start a new line
+fn escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\x{:02x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// bare when it is a single word, quoted otherwise
+fn logfmt_value(value: &str) -> String {
+    let bare = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| !c.is_whitespace() && !c.is_control() && c != '"' && c != '=');
+    if bare {
+        value.to_string()
+    } else {
+        format!("\"{}\"", escape(value))
+    }
+}
+
+// [10/Oct/2000:13:55:36 +0000]
+fn clf_time(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let of_day = secs % 86400;
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        of_day / 3600,
+        of_day % 3600 / 60,
+        of_day % 60
+    )
+}
+
+// 2000-10-10T13:55:36.123Z
+fn rfc3339(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let of_day = secs % 86400;
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        of_day / 3600,
+        of_day % 3600 / 60,
+        of_day % 60,
+        since.subsec_millis()
+    )
+}
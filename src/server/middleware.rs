@@ -0,0 +1,175 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::oneshot;
+
+use crate::client::client::Request;
+use crate::into_response::Response;
+use crate::status_code::StatusCode;
+use crate::{HandlerTypes, IntoResponse};
+
+pub type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send + 'static>>;
+
+/// Turns a request into a response, eventually. A route's handler is one, so
+/// is every layer wrapped around it.
+pub trait Service: Send + Sync + 'static {
+    fn call(&self, request: Request) -> BoxFuture<Response>;
+}
+
+pub type BoxService = Arc<dyn Service>;
+
+/// Wraps a service into one that gets to see the request before and the
+/// response after it. Layers go on a single route with `Route::layer`, on all
+/// routes of a router with `RouterBuilder::layer`, nested routers keep theirs.
+///
+/// The order is the order they are added in: the first layer is the outermost
+/// one, it sees the request first and the response last. Router layers wrap
+/// the layers of nested routers, which wrap the layers of the routes.
+pub trait Layer: Send + Sync + 'static {
+    fn layer(&self, inner: BoxService) -> BoxService;
+}
+
+/// The rest of the chain below a `from_fn` middleware.
+#[derive(Clone)]
+pub struct Next {
+    inner: BoxService,
+}
+
+impl Next {
+    pub async fn run(self, request: Request) -> Response {
+        self.inner.call(request).await
+    }
+}
+
+type MiddlewareFn = dyn Fn(Request, Next) -> BoxFuture<Response> + Send + Sync + 'static;
+
+/// A layer made of an async function getting the request and what comes next.
+/// Not calling `next` answers the request right there.
+///
+/// ```ignore
+/// let router = Router::builder()
+///     .route("/", HandlerTypes::empty(index, Method::GET))
+///     .layer(from_fn(|request: Request, next: Next| async move {
+///         if request.get_header("X-Api-Key") != Some("secret") {
+///             return (StatusCode::UNAUTHORIZED, ()).into_response();
+///         }
+///         let mut response = next.run(request).await;
+///         response.headers_mut().insert("X-Checked", "yes");
+///         response
+///     }))
+///     .build();
+/// ```
+#[derive(Clone)]
+pub struct FromFn {
+    f: Arc<MiddlewareFn>,
+}
+
+pub fn from_fn<F, Fut, R>(f: F) -> FromFn
+where
+    F: Fn(Request, Next) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = R> + Send + 'static,
+    R: IntoResponse,
+{
+    let f = move |request, next| {
+        let future = f(request, next);
+        Box::pin(async move { future.await.into_response() }) as BoxFuture<Response>
+    };
+    FromFn { f: Arc::new(f) }
+}
+
+impl Layer for FromFn {
+    fn layer(&self, inner: BoxService) -> BoxService {
+        Arc::new(FromFnService {
+            f: self.f.clone(),
+            inner,
+        })
+    }
+}
+
+struct FromFnService {
+    f: Arc<MiddlewareFn>,
+    inner: BoxService,
+}
+
+impl Service for FromFnService {
+    fn call(&self, request: Request) -> BoxFuture<Response> {
+        let next = Next {
+            inner: self.inner.clone(),
+        };
+        (self.f)(request, next)
+    }
+}
+
+// handlers are plain functions, their response is ready right away
+impl Service for HandlerTypes {
+    fn call(&self, request: Request) -> BoxFuture<Response> {
+        let response = match self {
+            HandlerTypes::ZeroParams(a) => a.0.call(),
+            HandlerTypes::Params(a) => a.0.call(request.params().clone()),
+            HandlerTypes::Body(a) => a.0.call(request),
+            HandlerTypes::Full(a) => a.0.call(request),
+        };
+        let response = response.unwrap_or_else(IntoResponse::into_response);
+        Box::pin(async move { response })
+    }
+}
+
+// where the connection sends the body once a handler asks for it
+type BodyReply = oneshot::Sender<Vec<u8>>;
+
+/// A body the client holds back until it gets a `100 Continue`. The connection
+/// only sends that and reads the body once a handler asks for it, so layers
+/// turning the request down never make the client send it.
+#[derive(Clone)]
+pub(crate) struct PendingBody {
+    ask: Arc<Mutex<Option<oneshot::Sender<BodyReply>>>>,
+}
+
+impl PendingBody {
+    pub(crate) fn new() -> (Self, oneshot::Receiver<BodyReply>) {
+        let (ask, asked) = oneshot::channel();
+        let pending = PendingBody {
+            ask: Arc::new(Mutex::new(Some(ask))),
+        };
+        (pending, asked)
+    }
+
+    // `None` once the connection gave up on reading it
+    async fn read(self) -> Option<Vec<u8>> {
+        let ask = self.ask.lock().unwrap_or_else(|e| e.into_inner()).take()?;
+        let (reply, body) = oneshot::channel();
+        ask.send(reply).ok()?;
+        body.await.ok()
+    }
+}
+
+// sits right above handlers reading the body, they get it only after every
+// layer let the request through
+pub(crate) struct AwaitBody(pub(crate) Arc<HandlerTypes>);
+
+impl Service for AwaitBody {
+    fn call(&self, mut request: Request) -> BoxFuture<Response> {
+        let Some(pending) = request.extensions_mut().remove::<PendingBody>() else {
+            return self.0.call(request);
+        };
+        let handler = self.0.clone();
+        Box::pin(async move {
+            match pending.read().await {
+                Some(body) => {
+                    request.set_body(body);
+                    handler.call(request).await
+                }
+                None => (StatusCode::BAD_REQUEST, ()).into_response(),
+            }
+        })
+    }
+}
+
+// the first layer ends up outermost
+pub(crate) fn wrap(service: BoxService, layers: &[Arc<dyn Layer>]) -> BoxService {
+    layers
+        .iter()
+        .rev()
+        .fold(service, |service, layer| layer.layer(service))
+}
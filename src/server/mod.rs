@@ -1,4 +1,22 @@
+pub mod auth;
+pub mod compression;
+pub mod conditional;
+pub mod config;
+pub mod cors;
+pub mod encoder;
 pub mod extractors;
 pub mod handle_connection;
+pub mod jwt;
+pub mod logging;
+pub mod middleware;
+pub mod negotiate;
+pub mod panic;
+pub mod range;
+pub mod request_id;
 pub mod router;
+pub mod serve_dir;
+// `server::server` is public api, same as `client::client`
+#[allow(clippy::module_inception)]
 pub mod server;
+mod trace;
+pub mod zip;
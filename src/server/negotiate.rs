@@ -0,0 +1,474 @@
+use crate::client::client::Request;
+use crate::header::add_vary;
+use crate::into_response::{Response, ResponseBuilder};
+use crate::status_code::StatusCode;
+use crate::IntoResponse;
+
+/// One entry of an `Accept` header, `type/subtype` with its parameters.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MediaRange {
+    pub main: String,
+    pub sub: String,
+    /// parameters other than `q`, names lowercased
+    pub params: Vec<(String, String)>,
+    pub q: f32,
+}
+
+/// One entry of `Accept-Language`, `Accept-Charset` or `Accept-Encoding`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QualityItem {
+    pub value: String,
+    pub q: f32,
+}
+
+/// Parses `Accept`. Entries that aren't a media range are skipped, so is
+/// everything with a q-value that doesn't parse.
+pub fn parse_accept(header: &str) -> Vec<MediaRange> {
+    let mut ranges = Vec::new();
+    for item in header.split(',') {
+        let mut parts = item.split(';');
+        let Some((main, sub)) = parts.next().and_then(|t| t.trim().split_once('/')) else {
+            continue;
+        };
+        let (main, sub) = (main.trim(), sub.trim());
+        if main.is_empty() || sub.is_empty() || (main == "*" && sub != "*") {
+            continue;
+        }
+
+        let mut params = Vec::new();
+        let mut q = Some(1.0);
+        for param in parts {
+            let Some((name, value)) = param.split_once('=') else {
+                continue;
+            };
+            let name = name.trim().to_ascii_lowercase();
+            let value = value.trim().trim_matches('"');
+            if name == "q" {
+                q = parse_q(value);
+            } else {
+                params.push((name, value.to_string()));
+            }
+        }
+        if let Some(q) = q {
+            ranges.push(MediaRange {
+                main: main.to_ascii_lowercase(),
+                sub: sub.to_ascii_lowercase(),
+                params,
+                q,
+            });
+        }
+    }
+    ranges
+}
+
+/// Parses a list of tokens with q-values, `Accept-Language` and friends.
+pub fn parse_quality_list(header: &str) -> Vec<QualityItem> {
+    let mut items = Vec::new();
+    for item in header.split(',') {
+        let mut parts = item.split(';');
+        let value = parts.next().unwrap_or("").trim();
+        if value.is_empty() {
+            continue;
+        }
+        let q = parts
+            .filter_map(|p| p.split_once('='))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+            .map_or(Some(1.0), |(_, q)| parse_q(q.trim()));
+        if let Some(q) = q {
+            items.push(QualityItem {
+                value: value.to_ascii_lowercase(),
+                q,
+            });
+        }
+    }
+    items
+}
+
+// q-values are 0 to 1 with at most three decimals
+fn parse_q(value: &str) -> Option<f32> {
+    value
+        .parse::<f32>()
+        .ok()
+        .filter(|q| (0.0..=1.0).contains(q))
+}
+
+impl MediaRange {
+    // 3 for an exact match with parameters, down to 0 for `*/*`, `None` when
+    // the range doesn't cover the media type at all
+    fn specificity(&self, main: &str, sub: &str, params: &[(String, String)]) -> Option<u8> {
+        if self.main == "*" {
+            return Some(0);
+        }
+        if self.main != main {
+            return None;
+        }
+        if self.sub == "*" {
+            return Some(1);
+        }
+        if self.sub != sub {
+            return None;
+        }
+        if self.params.is_empty() {
+            return Some(2);
+        }
+        let all_present = self.params.iter().all(|(name, value)| {
+            params
+                .iter()
+                .any(|(n, v)| n == name && v.eq_ignore_ascii_case(value))
+        });
+        all_present.then_some(3)
+    }
+}
+
+/// How much `Accept` likes `media_type`, the q-value of the most specific range
+/// covering it. Without the header everything is acceptable.
+pub fn media_quality(accept: Option<&str>, media_type: &str) -> f32 {
+    let Some(accept) = accept else {
+        return 1.0;
+    };
+    let mut parts = media_type.split(';');
+    let essence = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+    let (main, sub) = essence.split_once('/').unwrap_or((&essence, ""));
+    let params: Vec<(String, String)> = parts
+        .filter_map(|p| p.split_once('='))
+        .map(|(n, v)| {
+            (
+                n.trim().to_ascii_lowercase(),
+                v.trim().trim_matches('"').to_string(),
+            )
+        })
+        .collect();
+
+    parse_accept(accept)
+        .iter()
+        .filter_map(|range| Some((range.specificity(main, sub, &params)?, range.q)))
+        .max_by_key(|(specificity, _)| *specificity)
+        .map_or(0.0, |(_, q)| q)
+}
+
+/// How much `Accept-Language` likes the language tag, by the longest range that
+/// is a prefix of it (RFC 4647 basic filtering). Without the header every
+/// language is acceptable.
+pub fn language_quality(accept_language: Option<&str>, language: &str) -> f32 {
+    let Some(accept_language) = accept_language else {
+        return 1.0;
+    };
+    let language = language.to_ascii_lowercase();
+    parse_quality_list(accept_language)
+        .iter()
+        .filter(|item| {
+            item.value == "*"
+                || language == item.value
+                || language
+                    .strip_prefix(&item.value)
+                    .is_some_and(|rest| rest.starts_with('-'))
+        })
+        .max_by_key(|item| {
+            if item.value == "*" {
+                0
+            } else {
+                item.value.len()
+            }
+        })
+        .map_or(0.0, |item| item.q)
+}
+
+/// How much `Accept-Charset` likes the charset. Without the header every charset
+/// is acceptable.
+pub fn charset_quality(accept_charset: Option<&str>, charset: &str) -> f32 {
+    let Some(accept_charset) = accept_charset else {
+        return 1.0;
+    };
+    let items = parse_quality_list(accept_charset);
+    items
+        .iter()
+        .find(|item| item.value.eq_ignore_ascii_case(charset))
+        .or_else(|| items.iter().find(|item| item.value == "*"))
+        .map_or(0.0, |item| item.q)
+}
+
+/// Several representations of the same resource, of which the one the client
+/// rates highest by its `Accept`, `Accept-Language` and `Accept-Charset` is
+/// rendered. Ties go to the one offered first. When the client accepts none of
+/// them the answer is `406 Not Acceptable`. The response goes out with the
+/// picked variant's media type and charset, whatever `Content-Type` its render
+/// came with.
+///
+/// ```ignore
+/// fn report(request: Request) -> Response {
+///     Negotiate::new()
+///         .offer("application/json", || report_json())
+///         .offer("text/html", || report_html())
+///         .variant(Variant::new("text/csv", || report_csv()).charset("utf-8"))
+///         .respond(&request)
+/// }
+/// ```
+#[derive(Default)]
+pub struct Negotiate {
+    variants: Vec<Variant>,
+}
+
+/// A representation offered to `Negotiate`, only rendered when it is picked.
+pub struct Variant {
+    media_type: String,
+    language: Option<String>,
+    charset: Option<String>,
+    render: Box<dyn FnOnce() -> Response + Send>,
+}
+
+impl Variant {
+    pub fn new<F, R>(media_type: &str, render: F) -> Self
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: IntoResponse,
+    {
+        Self {
+            media_type: media_type.to_string(),
+            language: None,
+            charset: None,
+            render: Box::new(move || render().into_response()),
+        }
+    }
+
+    /// language tag, sent as `Content-Language`
+    pub fn language(mut self, language: &str) -> Self {
+        self.language = Some(language.to_string());
+        self
+    }
+
+    /// charset, added to the `Content-Type`
+    pub fn charset(mut self, charset: &str) -> Self {
+        self.charset = Some(charset.to_string());
+        self
+    }
+
+    fn quality(&self, request: &Request) -> f32 {
+        let media = media_quality(request.get_header("Accept"), &self.media_type);
+        let language = match &self.language {
+            Some(language) => language_quality(request.get_header("Accept-Language"), language),
+            None => 1.0,
+        };
+        let charset = match &self.charset {
+            Some(charset) => charset_quality(request.get_header("Accept-Charset"), charset),
+            None => 1.0,
+        };
+        media * language * charset
+    }
+}
+
+impl Negotiate {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn variant(mut self, variant: Variant) -> Self {
+        self.variants.push(variant);
+        self
+    }
+
+    /// shorthand for a variant that only differs by media type
+    pub fn offer<F, R>(self, media_type: &str, render: F) -> Self
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: IntoResponse,
+    {
+        self.variant(Variant::new(media_type, render))
+    }
+
+    pub fn respond(self, request: &Request) -> Response {
+        // caches have to know which headers the choice depended on
+        let mut vary = vec!["Accept"];
+        if self.variants.iter().any(|v| v.language.is_some()) {
+            vary.push("Accept-Language");
+        }
+        if self.variants.iter().any(|v| v.charset.is_some()) {
+            vary.push("Accept-Charset");
+        }
+
+        let mut available: Vec<String> = Vec::new();
+        for variant in &self.variants {
+            if !available.contains(&variant.media_type) {
+                available.push(variant.media_type.clone());
+            }
+        }
+
+        let mut best: Option<(f32, Variant)> = None;
+        for variant in self.variants {
+            let q = variant.quality(request);
+            if q > 0.0 && best.as_ref().map_or(true, |(best_q, _)| q > *best_q) {
+                best = Some((q, variant));
+            }
+        }
+
+        let mut response = match best {
+            Some((_, variant)) => {
+                let mut response = (variant.render)();
+                let content_type = match &variant.charset {
+                    Some(charset) => format!("{}; charset={}", variant.media_type, charset),
+                    None => variant.media_type,
+                };
+                let headers = response.headers_mut();
+                headers.insert("Content-Type", content_type);
+                if let Some(language) = variant.language {
+                    headers.insert("Content-Language", language);
+                }
+                response
+            }
+            None => ResponseBuilder::new()
+                .status_code(StatusCode::NOT_ACCEPTABLE)
+                .header(("Content-Type", "text/plain"))
+                .with_body(format!(
+                    "available representations: {}",
+                    available.join(", ")
+                ))
+                .build(),
+        };
+        for field in vary {
+            add_vary(response.headers_mut(), field);
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::client::Method;
+    use crate::Html;
+
+    fn request(headers: &[(&str, &str)]) -> Request {
+        Request::with_headers(Method::GET, "/", headers)
+    }
+
+    #[test]
+    fn parses_media_ranges_with_q_values() {
+        let ranges = parse_accept("text/html;level=1, application/json; q=0.5 ,*/*;q=0");
+        assert_eq!(ranges.len(), 3);
+        assert_eq!(
+            (ranges[0].main.as_str(), ranges[0].sub.as_str()),
+            ("text", "html")
+        );
+        assert_eq!(ranges[0].params, [("level".to_string(), "1".to_string())]);
+        assert_eq!(ranges[0].q, 1.0);
+        assert_eq!(ranges[1].q, 0.5);
+        assert_eq!(ranges[2].q, 0.0);
+    }
+
+    #[test]
+    fn q_values_out_of_range_drop_the_entry() {
+        let ranges = parse_accept("a/b;q=1.5, c/d;q=-1, e/f;q=x, g/h;Q=0.25");
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(ranges[0].sub, "h");
+        assert_eq!(ranges[0].q, 0.25);
+    }
+
+    #[test]
+    fn malformed_media_ranges_are_skipped() {
+        let ranges = parse_accept("text, /html, text/, */html, , TEXT/Plain");
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(
+            (ranges[0].main.as_str(), ranges[0].sub.as_str()),
+            ("text", "plain")
+        );
+    }
+
+    #[test]
+    fn quality_lists() {
+        let items = parse_quality_list("en-US, pl;q=0.8, *;q=0.1, ;q=1, de;q=2");
+        let pairs: Vec<(&str, f32)> = items.iter().map(|i| (i.value.as_str(), i.q)).collect();
+        assert_eq!(pairs, [("en-us", 1.0), ("pl", 0.8), ("*", 0.1)]);
+    }
+
+    #[test]
+    fn the_most_specific_range_decides() {
+        let accept = Some("text/*;q=0.3, text/html;q=0.7, text/html;level=1, */*;q=0.5");
+        assert_eq!(media_quality(accept, "text/html;level=1"), 1.0);
+        assert_eq!(media_quality(accept, "text/html"), 0.7);
+        assert_eq!(media_quality(accept, "text/plain"), 0.3);
+        assert_eq!(media_quality(accept, "image/jpeg"), 0.5);
+        assert_eq!(media_quality(Some("text/html"), "image/png"), 0.0);
+        assert_eq!(media_quality(None, "image/png"), 1.0);
+    }
+
+    #[test]
+    fn languages_match_by_prefix() {
+        let accept = Some("en;q=0.5, en-GB, *;q=0.1");
+        assert_eq!(language_quality(accept, "en-GB"), 1.0);
+        assert_eq!(language_quality(accept, "en-US"), 0.5);
+        assert_eq!(language_quality(accept, "eng"), 0.1);
+        assert_eq!(language_quality(Some("fr"), "en"), 0.0);
+    }
+
+    #[test]
+    fn charsets_fall_back_to_the_wildcard() {
+        let accept = Some("utf-8, *;q=0.2");
+        assert_eq!(charset_quality(accept, "UTF-8"), 1.0);
+        assert_eq!(charset_quality(accept, "latin1"), 0.2);
+        assert_eq!(charset_quality(Some("utf-8"), "latin1"), 0.0);
+    }
+
+    fn greeting(request: &Request) -> Response {
+        Negotiate::new()
+            .offer("application/json", || "{}")
+            .offer("text/html", || Html("<p>hi</p>"))
+            .variant(Variant::new("text/plain", || "hi").language("en"))
+            .variant(Variant::new("text/plain", || "czesc").language("pl"))
+            .respond(request)
+    }
+
+    #[test]
+    fn picks_the_highest_rated_variant() {
+        let response = greeting(&request(&[("Accept", "text/html;q=0.9, text/plain")]));
+        assert_eq!(response.body().as_bytes(), Some(&b"hi"[..]));
+        assert_eq!(response.headers().get("Content-Language"), Some("en"));
+        let vary: Vec<&str> = response.headers().get_all("Vary").collect();
+        assert_eq!(vary, ["Accept", "Accept-Language"]);
+
+        let polish = request(&[
+            ("Accept", "text/plain"),
+            ("Accept-Language", "pl, en;q=0.5"),
+        ]);
+        assert_eq!(greeting(&polish).body().as_bytes(), Some(&b"czesc"[..]));
+    }
+
+    #[test]
+    fn ties_go_to_the_first_offer() {
+        let response = greeting(&request(&[("Accept", "*/*")]));
+        assert_eq!(response.body().as_bytes(), Some(&b"{}"[..]));
+        assert_eq!(
+            response.headers().get("Content-Type"),
+            Some("application/json")
+        );
+    }
+
+    #[test]
+    fn the_negotiated_media_type_replaces_the_rendered_one() {
+        let json = Negotiate::new()
+            .offer("application/json", || String::from(r#"{"a":1}"#))
+            .respond(&request(&[("Accept", "application/json")]));
+        assert_eq!(json.headers().get("Content-Type"), Some("application/json"));
+        assert_eq!(json.headers().get_all("Content-Type").count(), 1);
+
+        let html = greeting(&request(&[("Accept", "text/html")]));
+        assert_eq!(html.headers().get("Content-Type"), Some("text/html"));
+
+        let csv = Negotiate::new()
+            .variant(Variant::new("text/csv", || "a,b").charset("utf-8"))
+            .respond(&request(&[]));
+        assert_eq!(
+            csv.headers().get("Content-Type"),
+            Some("text/csv; charset=utf-8")
+        );
+    }
+
+    #[test]
+    fn nothing_acceptable_is_406() {
+        let response = greeting(&request(&[("Accept", "image/*")]));
+        assert_eq!(response.status_code(), StatusCode::NOT_ACCEPTABLE);
+        let body = response.body().as_bytes().unwrap();
+        assert_eq!(
+            std::str::from_utf8(body).unwrap(),
+            "available representations: application/json, text/html, text/plain"
+        );
+    }
+}
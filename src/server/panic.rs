@@ -0,0 +1,291 @@
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use futures_util::{FutureExt, Stream};
+
+use super::logging::{LogLevel, Logging};
+use super::middleware::BoxService;
+use super::trace;
+use crate::body::{Body, BodyStream, BoxError};
+use crate::client::client::{Method, Request};
+use crate::into_response::Response;
+use crate::status_code::StatusCode;
+use crate::IntoResponse;
+
+type PanicResponse = Arc<dyn Fn(&PanicInfo) -> Response + Send + Sync + 'static>;
+type PanicHook = Arc<dyn Fn(&PanicInfo) + Send + Sync + 'static>;
+
+/// Keeps a panicking handler or middleware from taking the connection down with it. The
+/// panic is logged with the route and request id, handed to the hook if there
+/// is one and answered with `500 Internal Server Error` or whatever `response`
+/// builds instead. A body stream panicking after the head went out can only
+/// end the connection, it is still logged and reported. Always on, configure
+/// it with `RouterBuilder::catch_panic`.
+///
+/// ```ignore
+/// let router = Router::builder()
+///     .route("/", HandlerTypes::empty(index, Method::GET))
+///     .catch_panic(
+///         CatchPanic::new()
+///             .response(|_| (StatusCode::SERVICE_UNAVAILABLE, "try again").into_response())
+///             .hook(|info| alert(&info.route, &info.message)),
+///     )
+///     .build();
+/// ```
+#[derive(Clone, Default)]
+pub struct CatchPanic {
+    response: Option<PanicResponse>,
+    hook: Option<PanicHook>,
+}
+
+/// Where a panic happened and what it said.
+#[derive(Clone, Debug)]
+pub struct PanicInfo {
+    pub method: Method,
+    pub path: String,
+    /// pattern of the matched route
+    pub route: String,
+    /// the id `RequestId` gave the request
+    pub request_id: Option<String>,
+    /// the panic message, when it was a string
+    pub message: String,
+}
+
+impl CatchPanic {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// builds the response sent instead of the one the handler never returned
+    pub fn response<F>(mut self, response: F) -> Self
+    where
+        F: Fn(&PanicInfo) -> Response + Send + Sync + 'static,
+    {
+        self.response = Some(Arc::new(response));
+        self
+    }
+
+    /// called for every caught panic, after it was logged
+    pub fn hook<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&PanicInfo) + Send + Sync + 'static,
+    {
+        self.hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Hands the request to `service` and turns a panic, while it is called
+    /// or while its future is polled, into the configured response. A streamed
+    /// body of the response is watched for panics as well.
+    pub(crate) async fn call(
+        &self,
+        request: Request,
+        route: &str,
+        service: &BoxService,
+        logging: &Logging,
+    ) -> Response {
+        let mut info = PanicInfo {
+            method: request.get_method().clone(),
+            path: request.get_path().to_string(),
+            route: route.to_string(),
+            request_id: request.request_id().map(str::to_string),
+            message: String::new(),
+        };
+
+        let called = match panic::catch_unwind(AssertUnwindSafe(|| service.call(request))) {
+            Ok(future) => AssertUnwindSafe(future).catch_unwind().await,
+            Err(payload) => Err(payload),
+        };
+        let mut response = match called {
+            Ok(response) => response,
+            Err(payload) => {
+                info.message = panic_message(payload.as_ref());
+                self.report(&info, logging);
+                return match &self.response {
+                    Some(response) => response(&info),
+                    None => (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response(),
+                };
+            }
+        };
+
+        if let Body::Stream(_) = response.body() {
+            if let Body::Stream(inner) = std::mem::take(response.body_mut()) {
+                *response.body_mut() = Body::Stream(Box::pin(GuardedStream {
+                    inner: Some(inner),
+                    catch: self.clone(),
+                    logging: logging.clone(),
+                    info,
+                }));
+            }
+        }
+        response
+    }
+
+    fn report(&self, info: &PanicInfo, logging: &Logging) {
+        trace::panicked(&info.route, &info.message);
+        logging.event(
+            LogLevel::Error,
+            "handler_panicked",
+            &[
+                ("method", info.method.to_string()),
+                ("path", info.path.clone()),
+                ("route", info.route.clone()),
+                ("request_id", info.request_id.clone().unwrap_or_default()),
+                ("message", info.message.clone()),
+            ],
+        );
+        if let Some(hook) = &self.hook {
+            hook(info);
+        }
+    }
+}
+
+// panics carry a &str or a String unless someone used `panic_any`
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "non string panic payload".to_string()
+    }
+}
+
+// a panic while polling ends the stream with an error, the connection is
+// closed since the body can't be completed anymore
+struct GuardedStream {
+    inner: Option<BodyStream>,
+    catch: CatchPanic,
+    logging: Logging,
+    info: PanicInfo,
+}
+
+impl Stream for GuardedStream {
+    type Item = Result<Bytes, BoxError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let Some(inner) = self.inner.as_mut() else {
+            return Poll::Ready(None);
+        };
+        match panic::catch_unwind(AssertUnwindSafe(|| inner.as_mut().poll_next(cx))) {
+            Ok(poll) => poll,
+            Err(payload) => {
+                // a panicked stream is never polled again
+                self.inner = None;
+                self.info.message = panic_message(payload.as_ref());
+                self.catch.report(&self.info, &self.logging);
+                Poll::Ready(Some(Err("body stream panicked".into())))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::config::ServerConfig;
+    use crate::server::handle_connection::handle_connection;
+    use crate::server::middleware::{from_fn, Next};
+    use crate::server::router::{Route, Router};
+    use crate::HandlerTypes;
+    use futures_util::{stream, StreamExt};
+    use std::sync::Mutex;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    type Panics = Arc<Mutex<Vec<String>>>;
+
+    fn router(panics: &Panics) -> Router {
+        let panics = panics.clone();
+        let streamed = || {
+            let parts = stream::iter([1, 2]).map(|part| {
+                if part == 2 {
+                    panic!("stream broke");
+                }
+                Ok::<_, std::io::Error>(Bytes::from("first"))
+            });
+            Body::from_stream(parts)
+        };
+        let polled = from_fn(|_: Request, _: Next| async move {
+            panic!("future broke");
+            #[allow(unreachable_code)]
+            StatusCode::OK
+        });
+        Router::builder()
+            .route("/ok", HandlerTypes::empty(|| "ok", Method::GET))
+            .route(
+                "/sync",
+                HandlerTypes::empty(|| -> &'static str { panic!("handler broke") }, Method::GET),
+            )
+            .route(
+                "/poll",
+                Route::new(HandlerTypes::empty(|| "unreachable", Method::GET)).layer(polled),
+            )
+            .route("/stream", HandlerTypes::empty(streamed, Method::GET))
+            .catch_panic(CatchPanic::new().hook(move |info| {
+                let note = format!("{} {}", info.route, info.message);
+                panics.lock().unwrap().push(note);
+            }))
+            .build()
+    }
+
+    // what a connection answers `raw` with, and whether its task got through
+    async fn exchange(router: Router, raw: &str) -> (String, bool) {
+        let config = ServerConfig::new().logging(Logging::new().level(LogLevel::Off));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let connection = tokio::spawn(handle_connection(
+            server,
+            router.into_service(),
+            Arc::new(config),
+        ));
+        client.write_all(raw.as_bytes()).await.unwrap();
+        let mut answer = Vec::new();
+        client.read_to_end(&mut answer).await.unwrap();
+        let survived = connection.await.is_ok();
+        (String::from_utf8(answer).unwrap(), survived)
+    }
+
+    async fn panics_then_serves(path: &str, message: &str) {
+        let panics = Panics::default();
+        let raw = format!(
+            "GET {} HTTP/1.1\r\n\r\nGET /ok HTTP/1.1\r\nConnection: close\r\n\r\n",
+            path
+        );
+        let (answer, survived) = exchange(router(&panics), &raw).await;
+        assert!(survived);
+        assert!(answer.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+        let (_, after) = answer.split_once("\r\n\r\n").unwrap();
+        assert!(after.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(after.ends_with("\r\n\r\nok"));
+        assert_eq!(*panics.lock().unwrap(), [format!("{} {}", path, message)]);
+    }
+
+    #[tokio::test]
+    async fn a_handler_panicking_when_called_is_a_500() {
+        panics_then_serves("/sync", "handler broke").await;
+    }
+
+    #[tokio::test]
+    async fn a_future_panicking_when_polled_is_a_500() {
+        panics_then_serves("/poll", "future broke").await;
+    }
+
+    #[tokio::test]
+    async fn a_body_panicking_mid_stream_ends_the_connection() {
+        let panics = Panics::default();
+        let raw = "GET /stream HTTP/1.1\r\n\r\nGET /ok HTTP/1.1\r\n\r\n";
+        let (answer, survived) = exchange(router(&panics), raw).await;
+        assert!(survived);
+        // the head went out before the panic, all that is left is to stop
+        assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(answer.ends_with("\r\n\r\n5\r\nfirst\r\n"));
+        assert_eq!(*panics.lock().unwrap(), ["/stream stream broke"]);
+    }
+}
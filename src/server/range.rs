@@ -0,0 +1,514 @@
+use std::io::{self, SeekFrom};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::{Bytes, BytesMut};
+use futures_util::stream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+
+use super::conditional::whole_seconds;
+use crate::body::{Body, BoxError};
+use crate::client::client::{Method, Request};
+use crate::into_response::{Response, ResponseBuilder};
+use crate::status_code::StatusCode;
+
+// size of the reads when streaming a range
+const READ_CHUNK: u64 = 64 * 1024;
+
+// more ranges than this, after merging overlapping ones, get the whole body
+// instead, a request for thousands of tiny ranges costs us more than it saves
+const MAX_RANGES: usize = 16;
+
+/// A seekable body that answers `Range` requests by itself. Without a usable
+/// `Range` header the whole body goes out with `200 OK`, otherwise the
+/// requested ranges do with `206 Partial Content`, several of them as
+/// `multipart/byteranges`.
+///
+/// ```ignore
+/// fn video(request: Request) -> Response {
+///     let file = std::fs::File::open("movie.mp4").unwrap();
+///     let size = file.metadata().unwrap().len();
+///     RangedBody::new(tokio::fs::File::from_std(file), size)
+///         .content_type("video/mp4")
+///         .respond(&request)
+/// }
+/// ```
+pub struct RangedBody<R> {
+    reader: R,
+    size: u64,
+    content_type: String,
+    last_modified: Option<SystemTime>,
+    etag: Option<String>,
+}
+
+/// Inclusive range of byte offsets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub(crate) fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+/// What a `Range` header asks of a body of a given size.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeRequest {
+    /// no range or one we don't understand, send everything
+    Full,
+    /// sorted, non overlapping ranges
+    Partial(Vec<ByteRange>),
+    /// none of the ranges overlaps the body
+    Unsatisfiable,
+}
+
+impl<R> RangedBody<R>
+where
+    R: AsyncRead + AsyncSeek + Send + Unpin + 'static,
+{
+    /// `size` is the length of everything `reader` produces from offset zero.
+    pub fn new(reader: R, size: u64) -> Self {
+        Self {
+            reader,
+            size,
+            content_type: "application/octet-stream".to_string(),
+            last_modified: None,
+            etag: None,
+        }
+    }
+
+    pub fn content_type(mut self, content_type: &str) -> Self {
+        self.content_type = content_type.to_string();
+        self
+    }
+
+    /// sent as `Last-Modified` and checked against `If-Range` dates
+    pub fn last_modified(mut self, time: SystemTime) -> Self {
+        self.last_modified = Some(time);
+        self
+    }
+
+    /// sent as `ETag` and checked against `If-Range` tags, quotes included
+    pub fn etag(mut self, etag: &str) -> Self {
+        self.etag = Some(etag.to_string());
+        self
+    }
+
+    pub fn respond(self, request: &Request) -> Response {
+        let mut builder = ResponseBuilder::new().header(("Accept-Ranges", "bytes"));
+        if let Some(time) = self.last_modified {
+            builder = builder.header(("Last-Modified", &httpdate::fmt_http_date(time)));
+        }
+        if let Some(etag) = &self.etag {
+            builder = builder.header(("ETag", etag));
+        }
+
+        let ranges = match request.get_header("Range") {
+            Some(range)
+                if matches!(request.get_method(), Method::GET | Method::HEAD)
+                    && self.if_range_holds(request) =>
+            {
+                parse_range(range, self.size)
+            }
+            _ => RangeRequest::Full,
+        };
+
+        match ranges {
+            RangeRequest::Full => {
+                let whole = ByteRange {
+                    start: 0,
+                    end: self.size.saturating_sub(1),
+                };
+                let segments = if self.size == 0 {
+                    Vec::new()
+                } else {
+                    vec![Segment::Range(whole)]
+                };
+                builder
+                    .status_code(StatusCode::OK)
+                    .header(("Content-Type", &self.content_type))
+                    .header(("Content-Length", &self.size.to_string()))
+                    .with_body(segment_body(self.reader, segments))
+                    .build()
+            }
+            RangeRequest::Unsatisfiable => builder
+                .status_code(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(("Content-Range", &format!("bytes */{}", self.size)))
+                .build(),
+            RangeRequest::Partial(ranges) if ranges.len() == 1 => {
+                let range = ranges[0];
+                builder
+                    .status_code(StatusCode::PARTIAL_CONTENT)
+                    .header(("Content-Type", &self.content_type))
+                    .header(("Content-Range", &content_range(range, self.size)))
+                    .header(("Content-Length", &range.len().to_string()))
+                    .with_body(segment_body(self.reader, vec![Segment::Range(range)]))
+                    .build()
+            }
+            RangeRequest::Partial(ranges) => {
+                let boundary = boundary();
+                let mut segments = Vec::with_capacity(ranges.len() * 2 + 1);
+                let mut length = 0;
+                for (i, range) in ranges.into_iter().enumerate() {
+                    // the CRLF in front of a delimiter belongs to it, the first
+                    // one goes without
+                    let head = format!(
+                        "{}--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+                        if i == 0 { "" } else { "\r\n" },
+                        boundary,
+                        self.content_type,
+                        content_range(range, self.size)
+                    );
+                    length += head.len() as u64 + range.len();
+                    segments.push(Segment::Text(Bytes::from(head)));
+                    segments.push(Segment::Range(range));
+                }
+                let tail = format!("\r\n--{}--\r\n", boundary);
+                length += tail.len() as u64;
+                segments.push(Segment::Text(Bytes::from(tail)));
+
+                builder
+                    .status_code(StatusCode::PARTIAL_CONTENT)
+                    .header((
+                        "Content-Type",
+                        &format!("multipart/byteranges; boundary={}", boundary),
+                    ))
+                    .header(("Content-Length", &length.to_string()))
+                    .with_body(segment_body(self.reader, segments))
+                    .build()
+            }
+        }
+    }
+
+    // An `If-Range` makes the `Range` conditional on the body being the one the
+    // client already holds part of. Tags compare strongly, dates exactly.
+    fn if_range_holds(&self, request: &Request) -> bool {
+        let Some(condition) = request.get_header("If-Range") else {
+            return true;
+        };
+        let condition = condition.trim();
+        if condition.starts_with('"') || condition.starts_with("W/") {
+            return match &self.etag {
+                Some(etag) => !etag.starts_with("W/") && etag == condition,
+                None => false,
+            };
+        }
+        match (self.last_modified, httpdate::parse_http_date(condition)) {
+            (Some(modified), Ok(date)) => whole_seconds(modified) == whole_seconds(date),
+            _ => false,
+        }
+    }
+}
+
+/// Reads a `Range` header against a body of `size` bytes. Overlapping and
+/// adjacent ranges are merged, syntax errors and units other than `bytes`
+/// leave the header ignored.
+pub fn parse_range(header: &str, size: u64) -> RangeRequest {
+    let Some((unit, specs)) = header.split_once('=') else {
+        return RangeRequest::Full;
+    };
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return RangeRequest::Full;
+    }
+
+    let mut ranges = Vec::new();
+    for spec in specs.split(',') {
+        let spec = spec.trim();
+        if spec.is_empty() {
+            continue;
+        }
+        let Some((first, last)) = spec.split_once('-') else {
+            return RangeRequest::Full;
+        };
+        let (first, last) = (first.trim(), last.trim());
+
+        let range = if first.is_empty() {
+            // suffix, the last `last` bytes
+            let Ok(suffix) = last.parse::<u64>() else {
+                return RangeRequest::Full;
+            };
+            if suffix == 0 || size == 0 {
+                continue;
+            }
+            ByteRange {
+                start: size.saturating_sub(suffix),
+                end: size - 1,
+            }
+        } else {
+            let Ok(start) = first.parse::<u64>() else {
+                return RangeRequest::Full;
+            };
+            let end = if last.is_empty() {
+                u64::MAX
+            } else {
+                match last.parse::<u64>() {
+                    Ok(end) if end >= start => end,
+                    _ => return RangeRequest::Full,
+                }
+            };
+            if start >= size {
+                continue;
+            }
+            ByteRange {
+                start,
+                end: end.min(size - 1),
+            }
+        };
+        ranges.push(range);
+    }
+
+    if ranges.is_empty() {
+        return RangeRequest::Unsatisfiable;
+    }
+
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(1) => {
+                last.end = last.end.max(range.end);
+            }
+            _ => merged.push(range),
+        }
+    }
+    if merged.len() > MAX_RANGES {
+        return RangeRequest::Full;
+    }
+    RangeRequest::Partial(merged)
+}
+
+fn content_range(range: ByteRange, size: u64) -> String {
+    format!("bytes {}-{}/{}", range.start, range.end, size)
+}
+
+// unique enough to never show up in the parts, nobody else ever sees it
+fn boundary() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("skibidi-{:08x}{:016x}", nanos, count)
+}
+
+enum Segment {
+    Text(Bytes),
+    Range(ByteRange),
+}
+
+// Streams the segments in order, seeking the reader to each range and reading
+// no more than it spans.
+fn segment_body<R>(reader: R, segments: Vec<Segment>) -> Body
+where
+    R: AsyncRead + AsyncSeek + Send + Unpin + 'static,
+{
+    let state = Some((reader, segments.into_iter(), 0u64));
+    // the state is dropped after the first error so the stream ends there
+    let chunks = stream::unfold(state, |state| async move {
+        let (mut reader, mut segments, mut remaining) = state?;
+        loop {
+            if remaining > 0 {
+                let mut buf = BytesMut::with_capacity(remaining.min(READ_CHUNK) as usize);
+                let read = (&mut reader)
+                    .take(remaining.min(READ_CHUNK))
+                    .read_buf(&mut buf)
+                    .await;
+                return match read {
+                    Ok(0) => {
+                        let e = io::Error::from(io::ErrorKind::UnexpectedEof);
+                        Some((Err(BoxError::from(e)), None))
+                    }
+                    Ok(n) => {
+                        let remaining = remaining - n as u64;
+                        Some((Ok(buf.freeze()), Some((reader, segments, remaining))))
+                    }
+                    Err(e) => Some((Err(BoxError::from(e)), None)),
+                };
+            }
+            match segments.next()? {
+                Segment::Text(text) => return Some((Ok(text), Some((reader, segments, 0)))),
+                Segment::Range(range) => {
+                    if let Err(e) = reader.seek(SeekFrom::Start(range.start)).await {
+                        return Some((Err(BoxError::from(e)), None));
+                    }
+                    remaining = range.len();
+                }
+            }
+        }
+    });
+    Body::from_stream(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+    use std::io::Cursor;
+
+    const DATA: &[u8] = b"0123456789abcdefghij";
+
+    fn range(start: u64, end: u64) -> ByteRange {
+        ByteRange { start, end }
+    }
+
+    fn partial(ranges: &[(u64, u64)]) -> RangeRequest {
+        RangeRequest::Partial(ranges.iter().map(|&(s, e)| range(s, e)).collect())
+    }
+
+    fn request(headers: &[(&str, &str)]) -> Request {
+        Request::with_headers(Method::GET, "/data", headers)
+    }
+
+    fn ranged() -> RangedBody<Cursor<&'static [u8]>> {
+        RangedBody::new(Cursor::new(DATA), DATA.len() as u64).content_type("text/plain")
+    }
+
+    async fn body(response: Response) -> Vec<u8> {
+        let Body::Stream(mut stream) = response.into_body() else {
+            return Vec::new();
+        };
+        let mut out = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            out.extend_from_slice(&chunk.unwrap());
+        }
+        out
+    }
+
+    #[test]
+    fn parses_the_three_forms() {
+        assert_eq!(parse_range("bytes=0-4", 20), partial(&[(0, 4)]));
+        assert_eq!(parse_range("bytes=15-", 20), partial(&[(15, 19)]));
+        assert_eq!(parse_range("bytes=-5", 20), partial(&[(15, 19)]));
+        assert_eq!(parse_range("BYTES = 2 - 3", 20), partial(&[(2, 3)]));
+    }
+
+    #[test]
+    fn clamps_to_the_body() {
+        assert_eq!(parse_range("bytes=10-100", 20), partial(&[(10, 19)]));
+        assert_eq!(parse_range("bytes=-100", 20), partial(&[(0, 19)]));
+        assert_eq!(parse_range("bytes=19-19", 20), partial(&[(19, 19)]));
+    }
+
+    #[test]
+    fn merges_overlapping_and_adjacent_ranges() {
+        assert_eq!(parse_range("bytes=5-9,0-2,3-4", 20), partial(&[(0, 9)]));
+        assert_eq!(
+            parse_range("bytes=0-3,2-6,10-12", 20),
+            partial(&[(0, 6), (10, 12)])
+        );
+        assert_eq!(
+            parse_range("bytes=0-1, ,8-9", 20),
+            partial(&[(0, 1), (8, 9)])
+        );
+    }
+
+    #[test]
+    fn ranges_past_the_end_are_unsatisfiable() {
+        assert_eq!(parse_range("bytes=20-30", 20), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 20), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), RangeRequest::Unsatisfiable);
+        // satisfiable ones still count
+        assert_eq!(parse_range("bytes=30-40,0-0", 20), partial(&[(0, 0)]));
+    }
+
+    #[test]
+    fn malformed_headers_are_ignored() {
+        for header in [
+            "bytes",
+            "items=0-1",
+            "bytes=a-b",
+            "bytes=5-2",
+            "bytes=1",
+            "bytes=--1",
+        ] {
+            assert_eq!(parse_range(header, 20), RangeRequest::Full, "{}", header);
+        }
+    }
+
+    #[test]
+    fn too_many_ranges_get_the_whole_body() {
+        let many: Vec<String> = (0..=MAX_RANGES as u64)
+            .map(|i| format!("{0}-{0}", i * 2))
+            .collect();
+        let header = format!("bytes={}", many.join(","));
+        assert_eq!(parse_range(&header, 100), RangeRequest::Full);
+    }
+
+    #[tokio::test]
+    async fn whole_body_without_a_range() {
+        let response = ranged().respond(&request(&[]));
+        assert_eq!(response.status_code(), StatusCode::OK);
+        assert_eq!(response.headers().get("Accept-Ranges"), Some("bytes"));
+        assert_eq!(response.headers().get("Content-Length"), Some("20"));
+        assert_eq!(body(response).await, DATA);
+    }
+
+    #[tokio::test]
+    async fn single_range() {
+        let response = ranged().respond(&request(&[("Range", "bytes=-3")]));
+        assert_eq!(response.status_code(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            response.headers().get("Content-Range"),
+            Some("bytes 17-19/20")
+        );
+        assert_eq!(response.headers().get("Content-Length"), Some("3"));
+        assert_eq!(body(response).await, b"hij");
+    }
+
+    #[tokio::test]
+    async fn unsatisfiable_range() {
+        let response = ranged().respond(&request(&[("Range", "bytes=50-")]));
+        assert_eq!(response.status_code(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers().get("Content-Range"), Some("bytes */20"));
+    }
+
+    #[tokio::test]
+    async fn multiple_ranges_are_multipart() {
+        let response = ranged().respond(&request(&[("Range", "bytes=0-1,10-12")]));
+        assert_eq!(response.status_code(), StatusCode::PARTIAL_CONTENT);
+        let content_type = response.headers().get("Content-Type").unwrap().to_string();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap()
+            .to_string();
+        let length: usize = response
+            .headers()
+            .get("Content-Length")
+            .unwrap()
+            .parse()
+            .unwrap();
+
+        let body = body(response).await;
+        let expected = format!(
+            "--{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/20\r\n\r\n01\
+             \r\n--{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes 10-12/20\r\n\r\nabc\
+             \r\n--{0}--\r\n",
+            boundary
+        );
+        assert_eq!(String::from_utf8(body).unwrap(), expected);
+        assert_eq!(length, expected.len());
+    }
+
+    #[test]
+    fn boundaries_differ_per_response() {
+        assert_ne!(boundary(), boundary());
+    }
+
+    #[tokio::test]
+    async fn if_range_mismatch_sends_everything() {
+        let ranged = || ranged().etag("\"v1\"");
+        let stale = request(&[("Range", "bytes=0-1"), ("If-Range", "\"v0\"")]);
+        assert_eq!(ranged().respond(&stale).status_code(), StatusCode::OK);
+        let weak = request(&[("Range", "bytes=0-1"), ("If-Range", "W/\"v1\"")]);
+        assert_eq!(ranged().respond(&weak).status_code(), StatusCode::OK);
+        let current = request(&[("Range", "bytes=0-1"), ("If-Range", "\"v1\"")]);
+        assert_eq!(
+            ranged().respond(&current).status_code(),
+            StatusCode::PARTIAL_CONTENT
+        );
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::middleware::{from_fn, BoxService, Layer, Next};
+use crate::client::client::Request;
+use crate::into_response::Response;
+
+// ids longer than this are replaced rather than logged
+const MAX_LEN: usize = 128;
+
+/// The id of a request, in its extensions once `RequestId` settled on it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Id(pub String);
+
+type Generator = Arc<dyn Fn() -> String + Send + Sync + 'static>;
+
+/// Gives every request an id, the one it came with if that looks sane or a new
+/// one, and echoes it in the response. Handlers get it from
+/// `Request::request_id`, the access log and tracing spans pick it up too.
+/// Set it with `RouterBuilder::request_id` so the id exists before anything
+/// else runs; as a plain `Layer` it only covers what is wrapped inside it.
+///
+/// ```ignore
+/// let router = Router::builder()
+///     .route("/", HandlerTypes::full(index, Method::GET))
+///     .request_id(RequestId::new().header("X-Correlation-Id"))
+///     .build();
+/// ```
+#[derive(Clone)]
+pub struct RequestId {
+    header: String,
+    generator: Generator,
+    accept_incoming: bool,
+}
+
+impl Default for RequestId {
+    fn default() -> Self {
+        Self {
+            header: "X-Request-Id".to_string(),
+            generator: Arc::new(generate),
+            accept_incoming: true,
+        }
+    }
+}
+
+impl RequestId {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// header the id is read from and echoed in, `X-Request-Id` by default
+    pub fn header(mut self, name: &str) -> Self {
+        self.header = name.to_string();
+        self
+    }
+
+    /// makes the ids for requests that didn't bring a usable one, random
+    /// UUIDs by default
+    pub fn generator<F>(mut self, generator: F) -> Self
+    where
+        F: Fn() -> String + Send + Sync + 'static,
+    {
+        self.generator = Arc::new(generator);
+        self
+    }
+
+    /// `false` ignores what clients send and always generates a new id, for
+    /// servers facing clients that shouldn't pick their own
+    pub fn accept_incoming(mut self, accept: bool) -> Self {
+        self.accept_incoming = accept;
+        self
+    }
+
+    /// Settles on the id of `request`, stores it there and in the request's
+    /// header. A request that already has one keeps it.
+    pub(crate) fn assign(&self, request: &mut Request) -> String {
+        if let Some(id) = request.request_id() {
+            return id.to_string();
+        }
+        let incoming = request
+            .get_header(&self.header)
+            .filter(|id| self.accept_incoming && is_valid(id));
+        let id = match incoming {
+            Some(id) => id.to_string(),
+            None => (self.generator)(),
+        };
+        // whatever runs further down sees the same id in the header
+        request.headers.insert(self.header.clone(), id.clone());
+        request.extensions_mut().insert(Id(id.clone()));
+        id
+    }
+
+    pub(crate) fn echo(&self, response: &mut Response, id: &str) {
+        response.headers_mut().insert(self.header.clone(), id);
+    }
+}
+
+impl Layer for RequestId {
+    fn layer(&self, inner: BoxService) -> BoxService {
+        let request_id = self.clone();
+        from_fn(move |mut request: Request, next: Next| {
+            let request_id = request_id.clone();
+            async move {
+                let id = request_id.assign(&mut request);
+                let mut response = next.run(request).await;
+                request_id.echo(&mut response, &id);
+                response
+            }
+        })
+        .layer(inner)
+    }
+}
+
+// what ends up in log lines, so no spaces, quotes or control characters
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_LEN
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_.:/+=@".contains(&b))
+}
+
+// a version 4 UUID. Randomly keyed hashers of a counter and the clock are
+// plenty for correlating logs, not meant to be unguessable
+fn generate() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+
+    let half = |salt: u64| {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(count);
+        hasher.write_u128(nanos);
+        hasher.write_u64(salt);
+        hasher.finish()
+    };
+    let high = (half(0) & !0xf000) | 0x4000;
+    let low = (half(1) & !(0xc << 60)) | (0x8 << 60);
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        high >> 32,
+        (high >> 16) & 0xffff,
+        high & 0xffff,
+        low >> 48,
+        low & 0xffff_ffff_ffff
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::client::Method;
+    use crate::IntoResponse;
+
+    fn request(headers: &[(&str, &str)]) -> Request {
+        Request::with_headers(Method::GET, "/", headers)
+    }
+
+    fn is_uuid_v4(id: &str) -> bool {
+        let groups: Vec<&str> = id.split('-').collect();
+        let lengths: Vec<usize> = groups.iter().map(|group| group.len()).collect();
+        lengths == [8, 4, 4, 4, 12]
+            && id
+                .bytes()
+                .all(|b| b == b'-' || b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+            && groups[2].starts_with('4')
+            && groups[3].starts_with(['8', '9', 'a', 'b'])
+    }
+
+    #[test]
+    fn only_log_safe_ids_are_valid() {
+        assert!(is_valid("abc-123_x.y:z/+=@"));
+        assert!(is_valid(&"a".repeat(MAX_LEN)));
+        assert!(!is_valid(""));
+        assert!(!is_valid("two words"));
+        assert!(!is_valid("say \"hi\""));
+        assert!(!is_valid("line\nbreak"));
+        assert!(!is_valid(&"a".repeat(MAX_LEN + 1)));
+    }
+
+    #[test]
+    fn generated_ids_are_version_4_uuids() {
+        let ids: Vec<String> = (0..64).map(|_| generate()).collect();
+        for id in &ids {
+            assert!(is_uuid_v4(id), "{}", id);
+        }
+        let mut unique = ids.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), ids.len());
+    }
+
+    #[test]
+    fn valid_incoming_ids_are_kept() {
+        let request_id = RequestId::new();
+        let mut request = request(&[("X-Request-Id", "abc-123")]);
+        assert_eq!(request_id.assign(&mut request), "abc-123");
+        assert_eq!(request.request_id(), Some("abc-123"));
+
+        let mut response = "ok".into_response();
+        request_id.echo(&mut response, "abc-123");
+        assert_eq!(response.headers().get("X-Request-Id"), Some("abc-123"));
+    }
+
+    #[test]
+    fn unusable_incoming_ids_are_replaced() {
+        let request_id = RequestId::new();
+        let mut spaced = request(&[("X-Request-Id", "has spaces")]);
+        let id = request_id.assign(&mut spaced);
+        assert!(is_uuid_v4(&id), "{}", id);
+        // handlers further down see the replacement in the header too
+        assert_eq!(spaced.get_header("X-Request-Id"), Some(id.as_str()));
+
+        let strict = RequestId::new().accept_incoming(false);
+        let mut trusted = request(&[("X-Request-Id", "abc-123")]);
+        let id = strict.assign(&mut trusted);
+        assert!(is_uuid_v4(&id), "{}", id);
+    }
+
+    #[test]
+    fn custom_headers_and_generators_are_echoed() {
+        let request_id = RequestId::new()
+            .header("X-Correlation-Id")
+            .generator(|| "fixed".to_string());
+        let mut request = request(&[("X-Request-Id", "ignored")]);
+        let id = request_id.assign(&mut request);
+        assert_eq!(id, "fixed");
+        assert_eq!(request.get_header("X-Correlation-Id"), Some("fixed"));
+        assert_eq!(request.get_header("X-Request-Id"), Some("ignored"));
+
+        let mut response = "ok".into_response();
+        request_id.echo(&mut response, &id);
+        assert_eq!(response.headers().get("X-Correlation-Id"), Some("fixed"));
+        assert!(!response.headers().contains_key("X-Request-Id"));
+    }
+
+    #[test]
+    fn an_assigned_id_is_not_reassigned() {
+        let mut request = request(&[]);
+        let first = RequestId::new().assign(&mut request);
+        let again = RequestId::new()
+            .generator(|| "other".to_string())
+            .assign(&mut request);
+        assert_eq!(first, again);
+    }
+}
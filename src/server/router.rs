@@ -2,7 +2,27 @@ use std::{collections::HashMap, sync::Arc};
 
 use thiserror::Error;
 
-use crate::{client::client::Method, HandlerTypes};
+use super::compression::Compression;
+use super::conditional::Conditional;
+use super::middleware::{wrap, AwaitBody, BoxFuture, BoxService, Layer, Service};
+use super::panic::CatchPanic;
+use super::request_id::RequestId;
+use super::serve_dir::{ServeDir, ServeFile};
+use crate::{
+    client::client::{Method, Request},
+    into_response::Response,
+    status_code::StatusCode,
+    HandlerTypes, IntoResponse,
+};
+
+/// Pattern of the route a request matched, prefix of nested routers
+/// included. In the request's extensions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MatchedPath(pub String);
+
+/// Path parameters of the matched route, in the request's extensions.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PathParams(pub HashMap<String, String>);
 
 #[derive(Clone)]
 pub struct RouterService {
@@ -10,9 +30,67 @@ pub struct RouterService {
 }
 
 pub struct RouteMatch<'a> {
-    pub handler: &'a HandlerTypes,
+    /// the pattern the route was registered under
+    pub pattern: &'a str,
+    /// the handler with every layer applying to the route around it
+    pub service: &'a BoxService,
     pub params: HashMap<String, String>,
     pub methods: &'a Method,
+    /// whether the handler gets to see the body
+    pub reads_body: bool,
+    pub expect_check: Option<&'a ExpectCheck>,
+}
+
+/// Looks at the head of an `Expect: 100-continue` request and decides whether the
+/// client may go on sending the body. An error status is sent instead of
+/// `100 Continue` and the body is never read.
+pub type ExpectCheck = Box<dyn Fn(&Request) -> Result<(), StatusCode> + Send + Sync + 'static>;
+
+/// A handler together with its per route settings. Plain `HandlerTypes` turn
+/// into a `Route` on their own when passed to `RouterBuilder::route`.
+pub struct Route {
+    handler: HandlerTypes,
+    expect_check: Option<ExpectCheck>,
+    layers: Vec<Arc<dyn Layer>>,
+}
+
+// a route the way the router keeps it, the handler already wrapped in the
+// layers that apply to it
+struct Endpoint {
+    method: Method,
+    reads_body: bool,
+    expect_check: Option<ExpectCheck>,
+    service: BoxService,
+}
+
+impl Route {
+    pub fn new(handler: HandlerTypes) -> Self {
+        Self {
+            handler,
+            expect_check: None,
+            layers: Vec::new(),
+        }
+    }
+
+    /// middleware for this route only, runs inside the router's layers
+    pub fn layer(mut self, layer: impl Layer) -> Self {
+        self.layers.push(Arc::new(layer));
+        self
+    }
+
+    pub fn expect_continue<F>(mut self, check: F) -> Self
+    where
+        F: Fn(&Request) -> Result<(), StatusCode> + Send + Sync + 'static,
+    {
+        self.expect_check = Some(Box::new(check));
+        self
+    }
+}
+
+impl From<HandlerTypes> for Route {
+    fn from(handler: HandlerTypes) -> Self {
+        Route::new(handler)
+    }
 }
 
 pub enum MethodTypes {
@@ -27,26 +105,155 @@ pub enum RouterError {
     #[error("failed to find appropriate method handler")]
     MethodNotAllowed,
 }
+
+#[derive(Default)]
 pub struct RouterBuilder {
-    routes: Vec<(&'static str, HandlerTypes)>,
+    routes: Vec<(String, Endpoint)>,
+    layers: Vec<Arc<dyn Layer>>,
+    conditional: Option<Conditional>,
+    compression: Option<Compression>,
+    catch_panic: CatchPanic,
+    request_id: Option<RequestId>,
 }
 
 pub struct Router {
-    inner: Vec<(&'static str, HandlerTypes)>,
+    inner: Vec<(String, Endpoint)>,
+    // answers what no route matched, wrapped in the router's layers
+    not_found: BoxService,
+    method_not_allowed: BoxService,
+    catch_panic: CatchPanic,
+    request_id: Option<RequestId>,
 }
 
 impl RouterBuilder {
     pub fn new() -> Self {
-        Self { routes: Vec::new() }
+        Self::default()
+    }
+
+    pub fn route(mut self, path: &'static str, route: impl Into<Route>) -> Self {
+        self.push(path.to_string(), route.into());
+        self
     }
 
-    pub fn route(mut self, path: &'static str, handler: HandlerTypes) -> Self {
-        self.routes.push((path, handler));
+    /// Mounts the routes of `router` below `prefix`, with the layers they
+    /// already have. Only this router's fallback and panic handling apply.
+    pub fn nest(mut self, prefix: &'static str, router: Router) -> Self {
+        let prefix = prefix.trim_end_matches('/');
+        for (pattern, endpoint) in router.inner {
+            self.routes
+                .push((format!("{}{}", prefix, pattern), endpoint));
+        }
+        self
+    }
+
+    /// Middleware around every route of the router, including nested ones, and
+    /// around the `404` and `405` answers.
+    pub fn layer(mut self, layer: impl Layer) -> Self {
+        self.layers.push(Arc::new(layer));
+        self
+    }
+
+    fn push(&mut self, pattern: String, route: Route) {
+        let method = route.handler.get_method().clone();
+        let reads_body = route.handler.reads_body();
+        let handler: BoxService = if reads_body {
+            Arc::new(AwaitBody(Arc::new(route.handler)))
+        } else {
+            Arc::new(route.handler)
+        };
+        let endpoint = Endpoint {
+            method,
+            reads_body,
+            expect_check: route.expect_check,
+            service: wrap(handler, &route.layers),
+        };
+        self.routes.push((pattern, endpoint));
+    }
+
+    /// Serves the files below the directory of `dir` under `prefix`, for `GET`
+    /// and `HEAD` requests.
+    pub fn serve_dir(mut self, prefix: &'static str, dir: ServeDir) -> Self {
+        let pattern = format!("{}/{{*path}}", prefix.trim_end_matches('/'));
+        // the catch all parameter still works once the router is nested
+        let handler = move |request: Request| {
+            let rest = request.params().get("path").cloned().unwrap_or_default();
+            dir.serve(&request, &rest)
+        };
+        self.push(pattern, HandlerTypes::full(handler, Method::GET).into());
+        self
+    }
+
+    /// Serves a single file under `path`, for `GET` and `HEAD` requests.
+    pub fn serve_file(mut self, path: &'static str, file: ServeFile) -> Self {
+        let handler = move |request: Request| file.serve(&request);
+        self.push(
+            path.to_string(),
+            HandlerTypes::full(handler, Method::GET).into(),
+        );
+        self
+    }
+
+    /// Answers conditional `GET` and `HEAD` requests to every route with
+    /// `304` or `412` where the response's validators call for it. Handlers
+    /// of unsafe methods use `Conditional::check` themselves.
+    pub fn conditional(mut self, conditional: Conditional) -> Self {
+        self.conditional = Some(conditional);
+        self
+    }
+
+    /// Compresses the responses of every route for clients accepting it.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// How panicking handlers are answered and reported, a plain `500` by
+    /// default.
+    pub fn catch_panic(mut self, catch_panic: CatchPanic) -> Self {
+        self.catch_panic = catch_panic;
+        self
+    }
+
+    /// Gives every request an id as soon as it is parsed, before panic
+    /// handling, logging and any layer see it, and echoes it in the response.
+    pub fn request_id(mut self, request_id: RequestId) -> Self {
+        self.request_id = Some(request_id);
         self
     }
 
     pub fn build(self) -> Router {
-        Router { inner: self.routes }
+        // conditional requests are evaluated on the uncompressed response,
+        // both run inside the router's own layers
+        let mut layers = self.layers;
+        if let Some(compression) = self.compression {
+            layers.push(Arc::new(compression));
+        }
+        if let Some(conditional) = self.conditional {
+            layers.push(Arc::new(conditional));
+        }
+
+        let mut inner = self.routes;
+        for (_, endpoint) in &mut inner {
+            endpoint.service = wrap(endpoint.service.clone(), &layers);
+        }
+        let fallback = |status| wrap(Arc::new(StatusService(status)), &layers);
+        Router {
+            inner,
+            not_found: fallback(StatusCode::NOT_FOUND),
+            method_not_allowed: fallback(StatusCode::METHOD_NOT_ALLOWED),
+            catch_panic: self.catch_panic,
+            request_id: self.request_id,
+        }
+    }
+}
+
+// answers every request with a bare status, what the router falls back on
+struct StatusService(StatusCode);
+
+impl Service for StatusService {
+    fn call(&self, _: Request) -> BoxFuture<Response> {
+        let response = (self.0, ()).into_response();
+        Box::pin(async move { response })
     }
 }
 
@@ -60,25 +267,38 @@ impl Router {
             router: Arc::new(self),
         }
     }
+
+    /// the service answering requests no route matched
+    pub(crate) fn fallback(&self, error: &RouterError) -> &BoxService {
+        match error {
+            RouterError::PathNotFound => &self.not_found,
+            RouterError::MethodNotAllowed => &self.method_not_allowed,
+        }
+    }
+
+    pub(crate) fn catch_panic(&self) -> &CatchPanic {
+        &self.catch_panic
+    }
+
+    pub(crate) fn request_id(&self) -> Option<&RequestId> {
+        self.request_id.as_ref()
+    }
 }
 
 impl Router {
-    pub fn matches(&self, path: &str, required_method: Method) -> Result<RouteMatch, RouterError> {
+    pub fn matches(
+        &self,
+        path: &str,
+        required_method: Method,
+    ) -> Result<RouteMatch<'_>, RouterError> {
+        let path = path.split_once('?').map_or(path, |(path, _)| path);
         // First, try to find a matching route with the correct path and method.
-        let result = self.inner.iter().find_map(|(pattern, handler)| {
-            let path_pattern = PatternPath::from_path(pattern);
-            if path_pattern.matches(path) {
-                if *handler.get_method() == required_method {
-                    // Found a match with the required method.
-                    return Some(RouteMatch {
-                        handler,
-                        params: path_pattern.extract_params(path),
-                        methods: handler.get_method(),
-                    });
-                }
-            }
-            None
-        });
+        let mut result = self.find(path, &required_method);
+        // HEAD is answered by the GET handler unless there is a dedicated one,
+        // the body gets dropped when the response is written
+        if result.is_none() && required_method == Method::HEAD {
+            result = self.find(path, &Method::GET);
+        }
 
         result.ok_or_else(|| {
             if self
@@ -92,6 +312,24 @@ impl Router {
             }
         })
     }
+
+    fn find(&self, path: &str, method: &Method) -> Option<RouteMatch<'_>> {
+        self.inner.iter().find_map(|(pattern, endpoint)| {
+            let path_pattern = PatternPath::from_path(pattern);
+            if path_pattern.matches(path) && &endpoint.method == method {
+                // Found a match with the required method.
+                return Some(RouteMatch {
+                    pattern,
+                    service: &endpoint.service,
+                    params: path_pattern.extract_params(path),
+                    methods: &endpoint.method,
+                    reads_body: endpoint.reads_body,
+                    expect_check: endpoint.expect_check.as_ref(),
+                });
+            }
+            None
+        })
+    }
 }
 
 #[derive(Debug)]
@@ -103,6 +341,8 @@ pub struct PatternPath {
 enum PathSegment {
     Static(String),
     Parameter(String),
+    // `{*name}`, swallows the rest of the path, nothing included
+    CatchAll(String),
 }
 
 impl PatternPath {
@@ -111,7 +351,9 @@ impl PatternPath {
             .split("/")
             .filter(|s| !s.is_empty())
             .map(|segment| {
-                if segment.starts_with("{") && segment.ends_with("}") {
+                if segment.starts_with("{*") && segment.ends_with("}") {
+                    PathSegment::CatchAll(segment[2..segment.len() - 1].to_string())
+                } else if segment.starts_with("{") && segment.ends_with("}") {
                     PathSegment::Parameter(segment[1..segment.len() - 1].to_string())
                 } else {
                     //normal path
@@ -124,7 +366,12 @@ impl PatternPath {
 
     fn matches(&self, path: &str) -> bool {
         let path_segments: Vec<_> = path.split("/").filter(|s| !s.is_empty()).collect();
-        if path_segments.len() != self.segments.len() {
+        let catch_all = matches!(self.segments.last(), Some(PathSegment::CatchAll(_)));
+        if catch_all {
+            if path_segments.len() < self.segments.len() - 1 {
+                return false;
+            }
+        } else if path_segments.len() != self.segments.len() {
             return false;
         }
 
@@ -133,7 +380,7 @@ impl PatternPath {
             .zip(path_segments)
             .all(|(pattern, segment)| match pattern {
                 PathSegment::Static(s) => s == segment,
-                PathSegment::Parameter(_) => true,
+                PathSegment::Parameter(_) | PathSegment::CatchAll(_) => true,
             })
     }
 
@@ -141,11 +388,111 @@ impl PatternPath {
         let mut params = HashMap::new();
         let path_segments: Vec<_> = path.split("/").filter(|s| !s.is_empty()).collect();
 
-        for (pattern, path_seg) in self.segments.iter().zip(path_segments) {
-            if let PathSegment::Parameter(name) = pattern {
-                params.insert(name.to_string(), path_seg.to_string());
+        for (i, (pattern, path_seg)) in self.segments.iter().zip(&path_segments).enumerate() {
+            match pattern {
+                PathSegment::Parameter(name) => {
+                    params.insert(name.to_string(), path_seg.to_string());
+                }
+                PathSegment::CatchAll(name) => {
+                    params.insert(name.to_string(), path_segments[i..].join("/"));
+                }
+                PathSegment::Static(_) => {}
             }
         }
         params
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::middleware::{from_fn, FromFn, Next};
+    use std::sync::Mutex;
+
+    type Calls = Arc<Mutex<Vec<String>>>;
+
+    // notes when the request passes on the way in and the response on the way out
+    fn record(calls: &Calls, name: &'static str) -> FromFn {
+        let calls = calls.clone();
+        from_fn(move |request: Request, next: Next| {
+            let calls = calls.clone();
+            async move {
+                calls.lock().unwrap().push(format!("{}>", name));
+                let response = next.run(request).await;
+                calls.lock().unwrap().push(format!("<{}", name));
+                response
+            }
+        })
+    }
+
+    fn app(calls: &Calls) -> Router {
+        let handler_calls = calls.clone();
+        let item = HandlerTypes::params(
+            move |params: HashMap<String, String>| {
+                let id = params.get("id").cloned().unwrap_or_default();
+                handler_calls.lock().unwrap().push(format!("item {}", id));
+                id
+            },
+            Method::GET,
+        );
+        let nested = Router::builder()
+            .route(
+                "/item/{id}",
+                Route::new(item)
+                    .layer(record(calls, "route1"))
+                    .layer(record(calls, "route2")),
+            )
+            .layer(record(calls, "nested"))
+            .build();
+        Router::builder()
+            .nest("/api", nested)
+            .layer(record(calls, "outer1"))
+            .layer(record(calls, "outer2"))
+            .build()
+    }
+
+    // what the router answers `method path` with, as the connection would call it
+    async fn call(router: &Router, method: Method, path: &str) -> Response {
+        let mut request = Request::with_headers(method.clone(), path, &[]);
+        match router.matches(path, method) {
+            Ok(route_match) => {
+                request
+                    .extensions_mut()
+                    .insert(PathParams(route_match.params));
+                route_match.service.call(request).await
+            }
+            Err(error) => router.fallback(&error).call(request).await,
+        }
+    }
+
+    #[tokio::test]
+    async fn the_first_layer_is_the_outermost() {
+        let calls = Calls::default();
+        let router = app(&calls);
+
+        let response = call(&router, Method::GET, "/api/item/7").await;
+        assert_eq!(response.body().as_bytes(), Some(&b"7"[..]));
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                "outer1>", "outer2>", "nested>", "route1>", "route2>", "item 7", "<route2",
+                "<route1", "<nested", "<outer2", "<outer1",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn fallbacks_only_go_through_the_router_layers() {
+        let calls = Calls::default();
+        let router = app(&calls);
+
+        let missing = call(&router, Method::GET, "/api/nothing").await;
+        assert_eq!(missing.status_code(), StatusCode::NOT_FOUND);
+        let wrong_method = call(&router, Method::POST, "/api/item/7").await;
+        assert_eq!(wrong_method.status_code(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(
+            *calls.lock().unwrap(),
+            ["outer1>", "outer2>", "<outer2", "<outer1"].repeat(2)
+        );
+    }
+}
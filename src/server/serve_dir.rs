@@ -0,0 +1,567 @@
+use std::fmt::Write;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::compression::accepted_quality;
+use super::conditional::{precondition_response, Validators};
+use super::range::RangedBody;
+use crate::client::client::Request;
+use crate::header::{add_vary, HeaderMap};
+use crate::into_response::{Response, ResponseBuilder};
+use crate::status_code::StatusCode;
+use crate::IntoResponse;
+
+/// Serves the files below a directory, mount it with `RouterBuilder::serve_dir`.
+/// Requests for a directory get its index file, or a listing when enabled.
+#[derive(Clone, Debug)]
+pub struct ServeDir {
+    root: PathBuf,
+    index_file: Option<String>,
+    listing: bool,
+    precompressed: Precompressed,
+}
+
+/// Serves one file, mount it with `RouterBuilder::serve_file`.
+#[derive(Clone, Debug)]
+pub struct ServeFile {
+    path: PathBuf,
+    precompressed: Precompressed,
+}
+
+// which `.gz` / `.br` siblings may be sent in place of the requested file
+#[derive(Clone, Copy, Debug, Default)]
+struct Precompressed {
+    gzip: bool,
+    br: bool,
+}
+
+impl ServeDir {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            index_file: Some("index.html".to_string()),
+            listing: false,
+            precompressed: Precompressed::default(),
+        }
+    }
+
+    /// file served for directory requests, `None` to never serve one
+    pub fn index_file(mut self, name: Option<&str>) -> Self {
+        self.index_file = name.map(str::to_string);
+        self
+    }
+
+    /// render an HTML listing for directories without an index file
+    pub fn directory_listing(mut self, enabled: bool) -> Self {
+        self.listing = enabled;
+        self
+    }
+
+    /// send `file.gz` instead of `file` to clients accepting gzip
+    pub fn precompressed_gzip(mut self) -> Self {
+        self.precompressed.gzip = true;
+        self
+    }
+
+    /// send `file.br` instead of `file` to clients accepting brotli
+    pub fn precompressed_br(mut self) -> Self {
+        self.precompressed.br = true;
+        self
+    }
+
+    /// Answers `request` with whatever `rest`, the part of the path below the
+    /// mount point, points at.
+    pub fn serve(&self, request: &Request, rest: &str) -> Response {
+        // anything trying to climb out of the root looks like a missing file
+        let Some(relative) = sanitize(rest) else {
+            return (StatusCode::NOT_FOUND, ()).into_response();
+        };
+        // symlinks may point anywhere, what they resolve to has to be inside too
+        let root = match fs::canonicalize(&self.root) {
+            Ok(root) => root,
+            Err(e) => return io_error_response(e),
+        };
+        let path = match inside(&root, &root.join(relative)) {
+            Ok(path) => path,
+            Err(e) => return io_error_response(e),
+        };
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) => return io_error_response(e),
+        };
+        if !metadata.is_dir() {
+            return serve_path(request, &path, self.precompressed, Some(&root));
+        }
+
+        // relative links in the index only resolve against a trailing slash
+        let request_path = request_path(request);
+        if !request_path.ends_with('/') {
+            // `//host/dir` as a Location is a link to another site, one slash
+            // keeps it on ours
+            let target = format!("/{}/", request_path.trim_start_matches(['/', '\\']));
+            let location = match request.get_path().split_once('?') {
+                Some((_, query)) => format!("{}?{}", target, query),
+                None => target,
+            };
+            return ResponseBuilder::new()
+                .status_code(StatusCode::MOVED_PERMANENTLY)
+                .header(("Location", &location))
+                .build();
+        }
+
+        if let Some(index) = &self.index_file {
+            if let Ok(index) = inside(&root, &path.join(index)) {
+                if index.is_file() {
+                    return serve_path(request, &index, self.precompressed, Some(&root));
+                }
+            }
+        }
+        if self.listing {
+            return match render_listing(&path, request_path) {
+                Ok(html) => {
+                    let mut response = html.into_response();
+                    response
+                        .headers_mut()
+                        .insert("Content-Type", "text/html; charset=utf-8");
+                    response
+                }
+                Err(e) => io_error_response(e),
+            };
+        }
+        (StatusCode::NOT_FOUND, ()).into_response()
+    }
+}
+
+impl ServeFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            precompressed: Precompressed::default(),
+        }
+    }
+
+    /// send `file.gz` instead of `file` to clients accepting gzip
+    pub fn precompressed_gzip(mut self) -> Self {
+        self.precompressed.gzip = true;
+        self
+    }
+
+    /// send `file.br` instead of `file` to clients accepting brotli
+    pub fn precompressed_br(mut self) -> Self {
+        self.precompressed.br = true;
+        self
+    }
+
+    pub fn serve(&self, request: &Request) -> Response {
+        serve_path(request, &self.path, self.precompressed, None)
+    }
+}
+
+/// path of the request without the query string
+pub fn request_path(request: &Request) -> &str {
+    let path = request.get_path();
+    path.split_once('?').map_or(path, |(path, _)| path)
+}
+
+// `path` with every symlink resolved, as long as that is below `root`, which
+// has to be resolved already. Anything else looks like a missing file.
+fn inside(root: &Path, path: &Path) -> io::Result<PathBuf> {
+    let path = fs::canonicalize(path)?;
+    if path.starts_with(root) {
+        Ok(path)
+    } else {
+        Err(io::ErrorKind::NotFound.into())
+    }
+}
+
+// streams the file at `path`, or a precompressed sibling of it, with the
+// content type of the original. Siblings have to resolve to somewhere below
+// `root` as well when there is one.
+fn serve_path(
+    request: &Request,
+    path: &Path,
+    precompressed: Precompressed,
+    root: Option<&Path>,
+) -> Response {
+    let (file, encoding) = match precompressed.pick(request, path, root) {
+        Some((file, encoding)) => (file, Some(encoding)),
+        None => match File::open(path) {
+            Ok(file) => (file, None),
+            Err(e) => return io_error_response(e),
+        },
+    };
+    let metadata = match file.metadata() {
+        Ok(metadata) if metadata.is_file() => metadata,
+        Ok(_) => return (StatusCode::NOT_FOUND, ()).into_response(),
+        Err(e) => return io_error_response(e),
+    };
+
+    // the sibling's own metadata tags it, so each encoding gets its own ETag
+    let validators = Validators::from_metadata(&metadata);
+    let mut response = match validators.evaluate(request.get_method(), &request.headers) {
+        Some(status) => {
+            let mut headers = HeaderMap::new();
+            if let Some(etag) = &validators.etag {
+                headers.insert("ETag", etag.as_str());
+            }
+            if let Some(modified) = validators.last_modified {
+                headers.insert("Last-Modified", httpdate::fmt_http_date(modified));
+            }
+            // what the 200 would have been, for compression to judge
+            headers.insert("Content-Type", content_type_for(path));
+            headers.insert("Content-Length", metadata.len().to_string());
+            precondition_response(status, &headers)
+        }
+        None => {
+            // ranges apply to what is sent, the compressed bytes for a sibling
+            let mut body = RangedBody::new(tokio::fs::File::from_std(file), metadata.len())
+                .content_type(content_type_for(path));
+            if let Some(etag) = &validators.etag {
+                body = body.etag(etag);
+            }
+            if let Some(modified) = validators.last_modified {
+                body = body.last_modified(modified);
+            }
+            body.respond(request)
+        }
+    };
+    if let Some(encoding) = encoding {
+        response.headers_mut().insert("Content-Encoding", encoding);
+    }
+    if precompressed.gzip || precompressed.br {
+        // caches have to keep the variants apart
+        add_vary(response.headers_mut(), "Accept-Encoding");
+    }
+    response
+}
+
+impl Precompressed {
+    // the best precompressed sibling the client accepts, brotli wins ties
+    fn pick(
+        &self,
+        request: &Request,
+        path: &Path,
+        root: Option<&Path>,
+    ) -> Option<(File, &'static str)> {
+        let accept = request.get_header("Accept-Encoding")?;
+        let mut candidates = Vec::new();
+        if self.br {
+            candidates.push(("br", "br"));
+        }
+        if self.gzip {
+            candidates.push(("gzip", "gz"));
+        }
+
+        let mut best: Option<(f32, &'static str, &'static str)> = None;
+        for (encoding, extension) in candidates {
+            let q = accepted_quality(accept, encoding);
+            if q > 0.0 && best.map_or(true, |(best_q, _, _)| q > best_q) {
+                best = Some((q, encoding, extension));
+            }
+        }
+        let (_, encoding, extension) = best?;
+
+        let mut sibling = path.as_os_str().to_owned();
+        sibling.push(".");
+        sibling.push(extension);
+        let mut sibling = PathBuf::from(sibling);
+        if let Some(root) = root {
+            sibling = inside(root, &sibling).ok()?;
+        }
+        let file = File::open(sibling).ok()?;
+        Some((file, encoding))
+    }
+}
+
+// Turns the requested path into a relative file system path. Percent escapes
+// are decoded per segment and `None` comes back for anything that could leave
+// the root: `..`, encoded separators, drive letters or NUL bytes.
+fn sanitize(rest: &str) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for segment in rest.split('/') {
+        let segment = percent_decode(segment)?;
+        match segment.as_str() {
+            "" | "." => continue,
+            ".." => return None,
+            s if s.contains(['/', '\\', ':', '\0']) => return None,
+            s => path.push(s),
+        }
+    }
+    Some(path)
+}
+
+pub(crate) fn percent_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = input.get(i + 1..i + 3)?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+pub(crate) fn percent_encode(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for byte in input.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(byte as char)
+            }
+            _ => write!(out, "%{:02X}", byte).unwrap(),
+        }
+    }
+    out
+}
+
+fn html_escape(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn render_listing(dir: &Path, request_path: &str) -> io::Result<String> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let is_dir = entry.file_type()?.is_dir();
+        entries.push((name, is_dir));
+    }
+    // directories first, then alphabetical
+    entries.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    let title = html_escape(request_path);
+    let mut html = String::new();
+    write!(
+        html,
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {0}</title></head>\n<body>\n<h1>Index of {0}</h1>\n<ul>\n",
+        title
+    )
+    .unwrap();
+    if request_path != "/" {
+        html.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for (name, is_dir) in entries {
+        let slash = if is_dir { "/" } else { "" };
+        writeln!(
+            html,
+            "<li><a href=\"{}{}\">{}{}</a></li>",
+            percent_encode(&name),
+            slash,
+            html_escape(&name),
+            slash
+        )
+        .unwrap();
+    }
+    html.push_str("</ul>\n</body>\n</html>\n");
+    Ok(html)
+}
+
+fn io_error_response(e: io::Error) -> Response {
+    let status = match e.kind() {
+        io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, ()).into_response()
+}
+
+/// Content type for a file, guessed from its extension.
+pub(crate) fn content_type_for(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt" | "log") => "text/plain; charset=utf-8",
+        Some("csv") => "text/csv; charset=utf-8",
+        Some("md") => "text/markdown; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("wasm") => "application/wasm",
+        Some("pdf") => "application/pdf",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("avif") => "image/avif",
+        Some("svg") => "image/svg+xml",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        Some("otf") => "font/otf",
+        Some("mp3") => "audio/mpeg",
+        Some("ogg") => "audio/ogg",
+        Some("wav") => "audio/wav",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        Some("zip") => "application/zip",
+        Some("gz") => "application/gzip",
+        Some("tar") => "application/x-tar",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::client::Method;
+
+    // a directory below the system's temporary one, gone with the guard
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("skibidi-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        fn path(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // a fresh directory with `evil.example/dir/` and `page.txt` (plus a
+    // gzipped copy) in it
+    fn root(name: &str) -> TempDir {
+        let root = TempDir::new(name);
+        fs::create_dir_all(root.path().join("evil.example/dir")).unwrap();
+        fs::write(root.path().join("page.txt"), "plain").unwrap();
+        fs::write(root.path().join("page.txt.gz"), "pretend gzip").unwrap();
+        root
+    }
+
+    fn get(path: &str, headers: &[(&str, &str)]) -> Request {
+        Request::with_headers(Method::GET, path, headers)
+    }
+
+    #[test]
+    fn directories_redirect_to_a_trailing_slash() {
+        let root = root("redirect");
+        let dir = ServeDir::new(root.path());
+        let response = dir.serve(&get("/evil.example/dir?sort=name", &[]), "evil.example/dir");
+        assert_eq!(response.status_code(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(
+            response.headers().get("Location"),
+            Some("/evil.example/dir/?sort=name")
+        );
+    }
+
+    #[test]
+    fn redirects_never_leave_the_site() {
+        let root = root("open-redirect");
+        let dir = ServeDir::new(root.path());
+        for path in [
+            "//evil.example/dir",
+            "///evil.example/dir",
+            "/\\evil.example/dir",
+        ] {
+            let response = dir.serve(&get(path, &[]), "evil.example/dir");
+            assert_eq!(
+                response.headers().get("Location"),
+                Some("/evil.example/dir/"),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn escaping_the_root_is_not_found() {
+        let root = root("escape");
+        let dir = ServeDir::new(root.path());
+        for rest in [
+            "../etc/passwd",
+            "%2e%2e/etc/passwd",
+            "a%2f..%2f..",
+            "c:/windows",
+        ] {
+            let response = dir.serve(&get("/", &[]), rest);
+            assert_eq!(response.status_code(), StatusCode::NOT_FOUND, "{}", rest);
+        }
+    }
+
+    #[test]
+    fn precompressed_siblings_add_to_vary() {
+        let root = root("precompressed");
+        let dir = ServeDir::new(root.path()).precompressed_gzip();
+        let response = dir.serve(
+            &get("/page.txt", &[("Accept-Encoding", "gzip")]),
+            "page.txt",
+        );
+        assert_eq!(response.headers().get("Content-Encoding"), Some("gzip"));
+        assert_eq!(
+            response.headers().get("Content-Type"),
+            Some("text/plain; charset=utf-8")
+        );
+        assert_eq!(response.headers().get("Content-Length"), Some("12"));
+        let vary: Vec<&str> = response.headers().get_all("Vary").collect();
+        assert_eq!(vary, ["Accept-Encoding"]);
+
+        let response = dir.serve(&get("/page.txt", &[]), "page.txt");
+        assert_eq!(response.headers().get("Content-Encoding"), None);
+        assert_eq!(response.headers().get("Content-Length"), Some("5"));
+        assert!(response.headers().contains_token("Vary", "Accept-Encoding"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_out_of_the_root_are_not_found() {
+        use std::os::unix::fs::symlink;
+
+        let root = root("symlinks");
+        let outside = TempDir::new("symlinks-outside");
+        fs::write(outside.path().join("secret.txt"), "secret").unwrap();
+        let link = |target: &Path, name: &str| symlink(target, root.path().join(name)).unwrap();
+        link(&outside.path().join("secret.txt"), "secret.txt");
+        link(outside.path(), "elsewhere");
+        link(&root.path().join("page.txt"), "alias.txt");
+        fs::write(root.path().join("other.txt"), "other").unwrap();
+        link(&outside.path().join("secret.txt"), "other.txt.gz");
+
+        let dir = ServeDir::new(root.path()).precompressed_gzip();
+        for rest in ["secret.txt", "elsewhere/secret.txt", "elsewhere/"] {
+            let response = dir.serve(&get("/", &[]), rest);
+            assert_eq!(response.status_code(), StatusCode::NOT_FOUND, "{}", rest);
+        }
+        let alias = dir.serve(&get("/alias.txt", &[]), "alias.txt");
+        assert_eq!(alias.status_code(), StatusCode::OK);
+        // the sibling leads outside, the plain file is sent instead
+        let other = dir.serve(
+            &get("/other.txt", &[("Accept-Encoding", "gzip")]),
+            "other.txt",
+        );
+        assert_eq!(other.headers().get("Content-Encoding"), None);
+        assert_eq!(other.headers().get("Content-Length"), Some("5"));
+    }
+}
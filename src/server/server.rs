@@ -1,6 +1,10 @@
 use thiserror::Error;
 
-use super::{handle_connection::handle_connection, router::RouterService};
+use std::sync::Arc;
+
+use super::{
+    config::ServerConfig, handle_connection::handle_connection, router::RouterService, trace,
+};
 
 #[derive(Error, Debug)]
 pub enum ServerError {
@@ -11,11 +15,21 @@ pub enum ServerError {
 pub struct Server {
     listener: tokio::net::TcpListener,
     router: RouterService,
+    config: Arc<ServerConfig>,
 }
 
 impl Server {
     pub fn new(listener: tokio::net::TcpListener, router: RouterService) -> Self {
-        Self { listener, router }
+        Self {
+            listener,
+            router,
+            config: Arc::new(ServerConfig::default()),
+        }
+    }
+
+    pub fn config(mut self, config: ServerConfig) -> Self {
+        self.config = Arc::new(config);
+        self
     }
 
     // pub fn serve_blocking(&self) -> Result<(), ServerError> {
@@ -31,9 +45,14 @@ impl Server {
     pub async fn serve_async(&self, listener: &tokio::net::TcpListener) -> Result<(), ServerError> {
         loop {
             match listener.accept().await {
-                Ok((stream, _)) => {
+                Ok((stream, peer)) => {
                     let router = self.router.clone();
-                    tokio::spawn(handle_connection(stream, router));
+                    let config = self.config.clone();
+                    let connection = handle_connection(stream, router, config);
+                    tokio::spawn(trace::instrument(
+                        connection,
+                        &trace::connection(Some(peer)),
+                    ));
                 }
                 Err(_) => return Err(ServerError::ServerErr),
             };
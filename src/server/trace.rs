@@ -0,0 +1,125 @@
+// Spans and events for `tracing` subscribers. Without the `tracing` feature
+// everything in here is an empty function the compiler throws away.
+#![cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+
+use std::future::Future;
+use std::net::SocketAddr;
+
+use crate::client::client::Request;
+use crate::client::parse::ParseError;
+use crate::into_response::HandlerError;
+use crate::status_code::StatusCode;
+
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::Span;
+
+/// stand-in for `tracing::Span` when the feature is off
+#[cfg(not(feature = "tracing"))]
+#[derive(Clone)]
+pub(crate) struct Span;
+
+#[cfg(feature = "tracing")]
+pub(crate) fn instrument<F: Future>(
+    future: F,
+    span: &Span,
+) -> tracing::instrument::Instrumented<F> {
+    tracing::Instrument::instrument(future, span.clone())
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn instrument<F: Future>(future: F, span: &Span) -> F {
+    future
+}
+
+/// `connection` span, parent of the request spans of that connection
+pub(crate) fn connection(peer: Option<SocketAddr>) -> Span {
+    #[cfg(feature = "tracing")]
+    return match peer {
+        Some(peer) => tracing::info_span!("connection", peer = %peer),
+        None => tracing::info_span!("connection", peer = tracing::field::Empty),
+    };
+    #[cfg(not(feature = "tracing"))]
+    Span
+}
+
+/// `request` span, the route and status are filled in once they are known.
+/// Carries the peer itself for subscribers that don't show parent fields.
+pub(crate) fn request(request: &Request, peer: Option<SocketAddr>) -> Span {
+    #[cfg(feature = "tracing")]
+    {
+        let span = tracing::info_span!(
+            "request",
+            peer = tracing::field::Empty,
+            method = %request.get_method(),
+            path = request.get_path(),
+            request_id = tracing::field::Empty,
+            route = tracing::field::Empty,
+            status = tracing::field::Empty,
+        );
+        if let Some(peer) = peer {
+            span.record("peer", tracing::field::display(peer));
+        }
+        if let Some(id) = request.request_id() {
+            span.record("request_id", id);
+        }
+        span.in_scope(|| tracing::debug!(version = request.get_version(), "request parsed"));
+        span
+    }
+    #[cfg(not(feature = "tracing"))]
+    Span
+}
+
+pub(crate) fn parse_failed(error: &ParseError) {
+    #[cfg(feature = "tracing")]
+    match error {
+        ParseError::CloseConn | ParseError::Idle => tracing::debug!(%error, "connection closed"),
+        _ => tracing::warn!(%error, "request could not be parsed"),
+    }
+}
+
+/// records the matched route on the current request span, `None` for a 404 or 405
+pub(crate) fn routed(pattern: Option<&str>) {
+    #[cfg(feature = "tracing")]
+    match pattern {
+        Some(pattern) => {
+            tracing::Span::current().record("route", pattern);
+            tracing::debug!(route = pattern, "route matched");
+        }
+        None => tracing::debug!("no route matched"),
+    }
+}
+
+pub(crate) fn handled(span: &Span, status: StatusCode) {
+    #[cfg(feature = "tracing")]
+    {
+        span.record("status", status.as_u16());
+        span.in_scope(|| tracing::debug!(status = status.as_u16(), "handler finished"));
+    }
+}
+
+pub(crate) fn handler_error(span: &Span, error: &HandlerError) {
+    #[cfg(feature = "tracing")]
+    span.in_scope(|| {
+        let status = error.status_code().as_u16();
+        let chain = error.chain();
+        if error.status_code().is_server_error() {
+            tracing::error!(status, error = %chain, "handler failed");
+        } else {
+            tracing::warn!(status, error = %chain, "handler failed");
+        }
+    });
+}
+
+pub(crate) fn panicked(route: &str, message: &str) {
+    #[cfg(feature = "tracing")]
+    tracing::error!(route, message, "handler panicked");
+}
+
+/// the response is out, or failed to go out after `bytes` body bytes
+pub(crate) fn written(span: &Span, bytes: u64, error: Option<&std::io::Error>) {
+    #[cfg(feature = "tracing")]
+    span.in_scope(|| match error {
+        None => tracing::debug!(bytes, "response written"),
+        Some(error) => tracing::warn!(bytes, %error, "writing the response failed"),
+    });
+}
@@ -0,0 +1,817 @@
+use std::fs;
+use std::io::{self, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::{Bytes, BytesMut};
+use futures_util::stream;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use super::compression::is_compressed_type;
+use super::serve_dir::{content_type_for, percent_encode};
+use crate::body::{Body, BoxError};
+use crate::into_response::{Response, ResponseBuilder};
+use crate::status_code::StatusCode;
+use crate::IntoResponse;
+
+// size of the reads from the entries
+const READ_CHUNK: usize = 64 * 1024;
+
+// the headers store name lengths in 16 bits
+const MAX_NAME_LEN: usize = u16::MAX as usize;
+
+// entries at least this big get zip64 sizes, with some room left for deflate
+// making incompressible data a little larger
+const ZIP64_THRESHOLD: u64 = 0xFFFF_0000;
+
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const DATA_DESCRIPTOR: u32 = 0x0807_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const ZIP64_END: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR: u32 = 0x0706_4b50;
+const END: u32 = 0x0605_4b50;
+
+// sizes follow in a data descriptor, names are UTF-8
+const FLAGS: u16 = 0x0008 | 0x0800;
+// made by unix so the permission bits in the external attributes count
+const VERSION_MADE_BY: u16 = (3 << 8) | 45;
+
+/// How the entries of an archive are stored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ZipMethod {
+    Stored,
+    Deflate,
+}
+
+impl ZipMethod {
+    fn code(self) -> u16 {
+        match self {
+            ZipMethod::Stored => 0,
+            ZipMethod::Deflate => 8,
+        }
+    }
+}
+
+/// A ZIP archive put together while it is sent, entry by entry, with nothing
+/// of it written to disk. Sizes and checksums of an entry follow its data in a
+/// descriptor, so every entry is read exactly once. Entries beyond 4 GiB and
+/// archives beyond 65535 entries or 4 GiB get zip64 records.
+///
+/// ```ignore
+/// fn reports() -> Result<ZipDownload, std::io::Error> {
+///     ZipDownload::new("reports.zip").directory("reports", "./reports")
+/// }
+/// ```
+pub struct ZipDownload {
+    filename: String,
+    method: ZipMethod,
+    entries: Vec<ZipEntry>,
+}
+
+struct ZipEntry {
+    // path inside the archive, `/` separated
+    name: String,
+    source: Source,
+}
+
+enum Source {
+    File(PathBuf),
+    Bytes(Bytes),
+}
+
+impl ZipDownload {
+    /// `filename` is what the browser saves the archive as.
+    pub fn new(filename: &str) -> Self {
+        Self {
+            filename: filename.to_string(),
+            method: ZipMethod::Deflate,
+            entries: Vec::new(),
+        }
+    }
+
+    /// Method for the entries, deflate by default. Files of a type that is
+    /// compressed already are stored either way.
+    pub fn method(mut self, method: ZipMethod) -> Self {
+        self.method = method;
+        self
+    }
+
+    /// Adds the file at `path` as `name`, it is only opened once the archive
+    /// gets to it. Names with nothing left once cleaned up, like `..`, or
+    /// longer than 65535 bytes leave the entry out.
+    pub fn file(mut self, name: &str, path: impl Into<PathBuf>) -> Self {
+        if let Some(name) = entry_name(name) {
+            self.entries.push(ZipEntry {
+                name,
+                source: Source::File(path.into()),
+            });
+        }
+        self
+    }
+
+    /// Adds `data` as `name`, with the same rules for the name as `file`.
+    pub fn bytes(mut self, name: &str, data: impl Into<Bytes>) -> Self {
+        if let Some(name) = entry_name(name) {
+            self.entries.push(ZipEntry {
+                name,
+                source: Source::Bytes(data.into()),
+            });
+        }
+        self
+    }
+
+    /// Adds every file below `dir`, under `prefix` in the archive. Symlinked
+    /// directories are skipped so a link loop can't make the archive endless,
+    /// so are files whose name would be longer than 65535 bytes.
+    pub fn directory(mut self, prefix: &str, dir: impl AsRef<Path>) -> io::Result<Self> {
+        let mut files = Vec::new();
+        collect_files(dir.as_ref(), &clean_name(prefix), &mut files)?;
+        files.sort();
+        for (name, path) in files {
+            self.entries.push(ZipEntry {
+                name,
+                source: Source::File(path),
+            });
+        }
+        Ok(self)
+    }
+}
+
+impl IntoResponse for ZipDownload {
+    fn into_response(self) -> Response {
+        let disposition = content_disposition(&self.filename);
+        ResponseBuilder::new()
+            .status_code(StatusCode::OK)
+            .header(("Content-Type", "application/zip"))
+            .header(("Content-Disposition", &disposition))
+            .with_body(archive_body(self.entries, self.method))
+            .build()
+    }
+}
+
+// `attachment` with an ASCII fallback name for old clients and the real one
+// in `filename*`
+fn content_disposition(filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback,
+        percent_encode(filename)
+    )
+}
+
+// no leading slash, no `.` or `..` segments, forward slashes only, so the
+// archive can't unpack outside of where it is extracted
+fn clean_name(name: &str) -> String {
+    name.split(['/', '\\'])
+        .filter(|s| !s.is_empty() && *s != "." && *s != "..")
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// the cleaned up name of an entry, `None` when nothing is left of it or it
+// doesn't fit the headers
+fn entry_name(name: &str) -> Option<String> {
+    let name = clean_name(name);
+    (!name.is_empty() && name.len() <= MAX_NAME_LEN).then_some(name)
+}
+
+fn collect_files(dir: &Path, prefix: &str, out: &mut Vec<(String, PathBuf)>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let name = if prefix.is_empty() {
+            name
+        } else {
+            format!("{}/{}", prefix, name)
+        };
+        // deeper entries only get longer
+        if name.len() > MAX_NAME_LEN {
+            continue;
+        }
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect_files(&entry.path(), &name, out)?;
+        } else if file_type.is_file() || fs::metadata(entry.path()).is_ok_and(|m| m.is_file()) {
+            out.push((name, entry.path()));
+        }
+    }
+    Ok(())
+}
+
+// where the archive stands between two chunks of the body
+struct Writer {
+    entries: std::vec::IntoIter<ZipEntry>,
+    method: ZipMethod,
+    current: Option<Current>,
+    // bytes sent so far, the offset of the next local header
+    offset: u64,
+    central: Vec<u8>,
+    count: u64,
+    finished: bool,
+}
+
+// the entry whose data is being sent
+struct Current {
+    reader: Box<dyn AsyncRead + Send + Unpin>,
+    name: String,
+    method: ZipMethod,
+    encoder: Option<flate2::write::DeflateEncoder<Vec<u8>>>,
+    crc: crc32fast::Hasher,
+    uncompressed: u64,
+    compressed: u64,
+    header_offset: u64,
+    zip64: bool,
+    time: u16,
+    date: u16,
+    mode: u32,
+}
+
+fn archive_body(entries: Vec<ZipEntry>, method: ZipMethod) -> Body {
+    let writer = Writer {
+        entries: entries.into_iter(),
+        method,
+        current: None,
+        offset: 0,
+        central: Vec::new(),
+        count: 0,
+        finished: false,
+    };
+    // the writer is dropped after the first error so the stream ends there
+    let chunks = stream::unfold(Some(writer), |writer| async move {
+        let mut writer = writer?;
+        match writer.next_chunk().await {
+            Ok(Some(chunk)) => Some((Ok(chunk), Some(writer))),
+            Ok(None) => None,
+            Err(e) => Some((Err(BoxError::from(e)), None)),
+        }
+    });
+    Body::from_stream(chunks)
+}
+
+impl Writer {
+    async fn next_chunk(&mut self) -> io::Result<Option<Bytes>> {
+        loop {
+            if let Some(current) = &mut self.current {
+                let mut buf = BytesMut::with_capacity(READ_CHUNK);
+                if current.reader.read_buf(&mut buf).await? > 0 {
+                    let out = current.write(&buf)?;
+                    if out.is_empty() {
+                        continue;
+                    }
+                    self.offset += out.len() as u64;
+                    return Ok(Some(Bytes::from(out)));
+                }
+                let current = self.current.take().unwrap();
+                let out = self.finish_entry(current)?;
+                self.offset += out.len() as u64;
+                return Ok(Some(Bytes::from(out)));
+            }
+
+            if self.finished {
+                return Ok(None);
+            }
+            match self.entries.next() {
+                Some(entry) => {
+                    let (current, header) = self.start_entry(entry).await?;
+                    self.current = Some(current);
+                    self.offset += header.len() as u64;
+                    return Ok(Some(Bytes::from(header)));
+                }
+                None => {
+                    self.finished = true;
+                    return Ok(Some(Bytes::from(self.end_of_archive())));
+                }
+            }
+        }
+    }
+
+    async fn start_entry(&mut self, entry: ZipEntry) -> io::Result<(Current, Vec<u8>)> {
+        let content_type = content_type_for(Path::new(&entry.name));
+        let method =
+            if is_compressed_type(content_type) && content_type != "application/octet-stream" {
+                ZipMethod::Stored
+            } else {
+                self.method
+            };
+
+        let (reader, size, modified, mode): (Box<dyn AsyncRead + Send + Unpin>, _, _, _) =
+            match entry.source {
+                Source::File(path) => {
+                    let file = tokio::fs::File::open(&path).await?;
+                    let metadata = file.metadata().await?;
+                    let modified = metadata.modified().unwrap_or_else(|_| SystemTime::now());
+                    (
+                        Box::new(file),
+                        metadata.len(),
+                        modified,
+                        file_mode(&metadata),
+                    )
+                }
+                Source::Bytes(bytes) => {
+                    let size = bytes.len() as u64;
+                    (
+                        Box::new(io::Cursor::new(bytes)),
+                        size,
+                        SystemTime::now(),
+                        0o644,
+                    )
+                }
+            };
+        let (time, date) = dos_date_time(modified);
+        let zip64 = size >= ZIP64_THRESHOLD;
+        let name_len = u16::try_from(entry.name.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "entry name too long"))?;
+
+        let mut header = Vec::with_capacity(30 + entry.name.len() + 20);
+        put_u32(&mut header, LOCAL_HEADER);
+        put_u16(&mut header, if zip64 { 45 } else { 20 });
+        put_u16(&mut header, FLAGS);
+        put_u16(&mut header, method.code());
+        put_u16(&mut header, time);
+        put_u16(&mut header, date);
+        // crc and sizes come in the data descriptor
+        put_u32(&mut header, 0);
+        let size_field = if zip64 { u32::MAX } else { 0 };
+        put_u32(&mut header, size_field);
+        put_u32(&mut header, size_field);
+        put_u16(&mut header, name_len);
+        put_u16(&mut header, if zip64 { 20 } else { 0 });
+        header.extend_from_slice(entry.name.as_bytes());
+        if zip64 {
+            // announces 8 byte sizes in the descriptor
+            put_u16(&mut header, 0x0001);
+            put_u16(&mut header, 16);
+            put_u64(&mut header, 0);
+            put_u64(&mut header, 0);
+        }
+
+        let encoder = (method == ZipMethod::Deflate).then(|| {
+            flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default())
+        });
+        let current = Current {
+            reader,
+            name: entry.name,
+            method,
+            encoder,
+            crc: crc32fast::Hasher::new(),
+            uncompressed: 0,
+            compressed: 0,
+            header_offset: self.offset,
+            zip64,
+            time,
+            date,
+            mode,
+        };
+        Ok((current, header))
+    }
+
+    // the rest of the compressed data and the descriptor, and the entry's
+    // record for the central directory
+    fn finish_entry(&mut self, mut current: Current) -> io::Result<Vec<u8>> {
+        let mut out = match current.encoder.take() {
+            Some(encoder) => encoder.finish()?,
+            None => Vec::new(),
+        };
+        current.compressed += out.len() as u64;
+        if !current.zip64 && current.compressed.max(current.uncompressed) > u32::MAX as u64 {
+            // the file grew past what its header allowed for while we read it
+            return Err(io::Error::other(format!(
+                "{} outgrew its zip entry",
+                current.name
+            )));
+        }
+
+        let crc = current.crc.clone().finalize();
+        put_u32(&mut out, DATA_DESCRIPTOR);
+        put_u32(&mut out, crc);
+        if current.zip64 {
+            put_u64(&mut out, current.compressed);
+            put_u64(&mut out, current.uncompressed);
+        } else {
+            put_u32(&mut out, current.compressed as u32);
+            put_u32(&mut out, current.uncompressed as u32);
+        }
+
+        // the central directory needs zip64 once sizes or the offset don't fit
+        let zip64 = current.zip64 || current.header_offset > u32::MAX as u64;
+        let central = &mut self.central;
+        put_u32(central, CENTRAL_HEADER);
+        put_u16(central, VERSION_MADE_BY);
+        put_u16(central, if zip64 { 45 } else { 20 });
+        put_u16(central, FLAGS);
+        put_u16(central, current.method.code());
+        put_u16(central, current.time);
+        put_u16(central, current.date);
+        put_u32(central, crc);
+        if zip64 {
+            put_u32(central, u32::MAX);
+            put_u32(central, u32::MAX);
+        } else {
+            put_u32(central, current.compressed as u32);
+            put_u32(central, current.uncompressed as u32);
+        }
+        // checked against the limit when the entry started
+        put_u16(central, current.name.len() as u16);
+        put_u16(central, if zip64 { 28 } else { 0 });
+        // comment, disk number, internal attributes
+        put_u16(central, 0);
+        put_u16(central, 0);
+        put_u16(central, 0);
+        put_u32(central, (0o100000 | current.mode) << 16);
+        put_u32(
+            central,
+            if zip64 {
+                u32::MAX
+            } else {
+                current.header_offset as u32
+            },
+        );
+        central.extend_from_slice(current.name.as_bytes());
+        if zip64 {
+            put_u16(central, 0x0001);
+            put_u16(central, 24);
+            put_u64(central, current.uncompressed);
+            put_u64(central, current.compressed);
+            put_u64(central, current.header_offset);
+        }
+        self.count += 1;
+        Ok(out)
+    }
+
+    fn end_of_archive(&mut self) -> Vec<u8> {
+        let mut out = mem::take(&mut self.central);
+        let directory_offset = self.offset;
+        let directory_size = out.len() as u64;
+        let zip64 = self.count > 0xFFFF
+            || directory_offset > u32::MAX as u64
+            || directory_size > u32::MAX as u64;
+
+        if zip64 {
+            let record_offset = directory_offset + directory_size;
+            put_u32(&mut out, ZIP64_END);
+            // size of the record after this field
+            put_u64(&mut out, 44);
+            put_u16(&mut out, VERSION_MADE_BY);
+            put_u16(&mut out, 45);
+            put_u32(&mut out, 0);
+            put_u32(&mut out, 0);
+            put_u64(&mut out, self.count);
+            put_u64(&mut out, self.count);
+            put_u64(&mut out, directory_size);
+            put_u64(&mut out, directory_offset);
+
+            put_u32(&mut out, ZIP64_LOCATOR);
+            put_u32(&mut out, 0);
+            put_u64(&mut out, record_offset);
+            put_u32(&mut out, 1);
+        }
+
+        let count = self.count.min(0xFFFF) as u16;
+        put_u32(&mut out, END);
+        put_u16(&mut out, 0);
+        put_u16(&mut out, 0);
+        put_u16(&mut out, count);
+        put_u16(&mut out, count);
+        put_u32(&mut out, directory_size.min(u32::MAX as u64) as u32);
+        put_u32(&mut out, directory_offset.min(u32::MAX as u64) as u32);
+        put_u16(&mut out, 0);
+        out
+    }
+}
+
+impl Current {
+    // checksums and compresses a chunk of the entry, what the encoder let go of
+    // comes back
+    fn write(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        self.crc.update(chunk);
+        self.uncompressed += chunk.len() as u64;
+        let out = match &mut self.encoder {
+            Some(encoder) => {
+                encoder.write_all(chunk)?;
+                mem::take(encoder.get_mut())
+            }
+            None => chunk.to_vec(),
+        };
+        self.compressed += out.len() as u64;
+        Ok(out)
+    }
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &std::fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o777
+}
+
+#[cfg(not(unix))]
+fn file_mode(metadata: &std::fs::Metadata) -> u32 {
+    if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    }
+}
+
+// MS-DOS time and date in UTC, two second resolution, nothing before 1980
+fn dos_date_time(time: SystemTime) -> (u16, u16) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    if year < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let of_day = secs % 86400;
+    let time = ((of_day / 3600) << 11) | ((of_day % 3600 / 60) << 5) | (of_day % 60 / 2);
+    let date = (((year - 1980).min(127) as u64) << 9) | ((month as u64) << 5) | day as u64;
+    (time as u16, date as u16)
+}
+
+// days since 1970-01-01 to (year, month, day), Howard Hinnant's algorithm
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+    use std::io::Read;
+
+    fn u16_at(data: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(data[at..at + 2].try_into().unwrap())
+    }
+
+    fn u32_at(data: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+    }
+
+    fn u64_at(data: &[u8], at: usize) -> u64 {
+        u64::from_le_bytes(data[at..at + 8].try_into().unwrap())
+    }
+
+    async fn archive(download: ZipDownload) -> Vec<u8> {
+        let Body::Stream(mut stream) = download.into_response().into_body() else {
+            panic!("archives are streamed");
+        };
+        let mut out = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            out.extend_from_slice(&chunk.unwrap());
+        }
+        out
+    }
+
+    struct Entry {
+        name: String,
+        method: u16,
+        crc: u32,
+        compressed: u64,
+        uncompressed: u64,
+        offset: u64,
+    }
+
+    // reads the central directory the way an unzip tool does, from the end
+    fn central_directory(data: &[u8]) -> Vec<Entry> {
+        let end = data.len() - 22;
+        assert_eq!(u32_at(data, end), END);
+        let count = u16_at(data, end + 10) as usize;
+        let size = u32_at(data, end + 12) as usize;
+        let offset = u32_at(data, end + 16) as usize;
+        assert_eq!(
+            offset + size,
+            end,
+            "directory ends where the end record starts"
+        );
+
+        let mut entries = Vec::new();
+        let mut at = offset;
+        for _ in 0..count {
+            assert_eq!(u32_at(data, at), CENTRAL_HEADER);
+            let name_len = u16_at(data, at + 28) as usize;
+            let extra_len = u16_at(data, at + 30) as usize;
+            entries.push(Entry {
+                name: String::from_utf8(data[at + 46..at + 46 + name_len].to_vec()).unwrap(),
+                method: u16_at(data, at + 10),
+                crc: u32_at(data, at + 16),
+                compressed: u32_at(data, at + 20) as u64,
+                uncompressed: u32_at(data, at + 24) as u64,
+                offset: u32_at(data, at + 42) as u64,
+            });
+            at += 46 + name_len + extra_len;
+        }
+        assert_eq!(at, offset + size);
+        entries
+    }
+
+    // the data of an entry, checked against its local header and descriptor
+    fn entry_data<'a>(data: &'a [u8], entry: &Entry) -> &'a [u8] {
+        let at = entry.offset as usize;
+        assert_eq!(u32_at(data, at), LOCAL_HEADER);
+        assert_eq!(u16_at(data, at + 6), FLAGS);
+        assert_eq!(u16_at(data, at + 8), entry.method);
+        let name_len = u16_at(data, at + 26) as usize;
+        let extra_len = u16_at(data, at + 28) as usize;
+        assert_eq!(&data[at + 30..at + 30 + name_len], entry.name.as_bytes());
+
+        let start = at + 30 + name_len + extra_len;
+        let end = start + entry.compressed as usize;
+        assert_eq!(u32_at(data, end), DATA_DESCRIPTOR);
+        assert_eq!(u32_at(data, end + 4), entry.crc);
+        assert_eq!(u32_at(data, end + 8) as u64, entry.compressed);
+        assert_eq!(u32_at(data, end + 12) as u64, entry.uncompressed);
+        &data[start..end]
+    }
+
+    #[tokio::test]
+    async fn stored_entries_round_trip() {
+        let first = b"hello zip".to_vec();
+        let second = vec![7u8; 1000];
+        let download = ZipDownload::new("test.zip")
+            .method(ZipMethod::Stored)
+            .bytes("a.txt", first.clone())
+            .bytes("/dir/b.bin", second.clone());
+        let data = archive(download).await;
+
+        let entries = central_directory(&data);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "a.txt");
+        assert_eq!(entries[1].name, "dir/b.bin");
+        assert_eq!(entries[0].offset, 0);
+        for (entry, expected) in entries.iter().zip([&first, &second]) {
+            assert_eq!(entry.method, 0);
+            assert_eq!(entry.crc, crc32fast::hash(expected));
+            assert_eq!(entry.uncompressed, expected.len() as u64);
+            assert_eq!(entry_data(&data, entry), &expected[..]);
+        }
+        // the second local header directly follows the first descriptor
+        let first_end = 30 + "a.txt".len() + first.len() + 16;
+        assert_eq!(entries[1].offset, first_end as u64);
+    }
+
+    #[tokio::test]
+    async fn deflated_entries_inflate_to_the_original() {
+        let text = "all work and no play ".repeat(200);
+        let data = archive(ZipDownload::new("t.zip").bytes("text.txt", text.clone())).await;
+
+        let entries = central_directory(&data);
+        let entry = &entries[0];
+        assert_eq!(entry.method, 8);
+        assert_eq!(entry.crc, crc32fast::hash(text.as_bytes()));
+        assert!(entry.compressed < entry.uncompressed);
+
+        let mut inflated = String::new();
+        flate2::read::DeflateDecoder::new(entry_data(&data, entry))
+            .read_to_string(&mut inflated)
+            .unwrap();
+        assert_eq!(inflated, text);
+    }
+
+    #[tokio::test]
+    async fn compressed_types_are_stored() {
+        let data = archive(ZipDownload::new("t.zip").bytes("photo.jpg", vec![1u8; 100])).await;
+        assert_eq!(central_directory(&data)[0].method, 0);
+    }
+
+    #[tokio::test]
+    async fn unusable_names_leave_the_entry_out() {
+        let longest = "n".repeat(MAX_NAME_LEN);
+        let download = ZipDownload::new("t.zip")
+            .method(ZipMethod::Stored)
+            .bytes("..", "up")
+            .bytes("./", "here")
+            .bytes(&"n".repeat(MAX_NAME_LEN + 1), "long")
+            .bytes(&longest, "fits")
+            .bytes("../../etc/passwd", "climb");
+        let data = archive(download).await;
+        let names: Vec<String> = central_directory(&data)
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(names, [longest, "etc/passwd".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn empty_archive_is_just_the_end_record() {
+        let data = archive(ZipDownload::new("t.zip")).await;
+        assert_eq!(data.len(), 22);
+        assert!(central_directory(&data).is_empty());
+    }
+
+    fn writer_at(offset: u64) -> Writer {
+        Writer {
+            entries: Vec::new().into_iter(),
+            method: ZipMethod::Stored,
+            current: None,
+            offset,
+            central: Vec::new(),
+            count: 0,
+            finished: false,
+        }
+    }
+
+    fn current(header_offset: u64, zip64: bool) -> Current {
+        let mut current = Current {
+            reader: Box::new(io::Cursor::new(Vec::new())),
+            name: "big".to_string(),
+            method: ZipMethod::Stored,
+            encoder: None,
+            crc: crc32fast::Hasher::new(),
+            uncompressed: 0,
+            compressed: 0,
+            header_offset,
+            zip64,
+            time: 0,
+            date: 0,
+            mode: 0o644,
+        };
+        current.write(b"abc").unwrap();
+        current
+    }
+
+    #[test]
+    fn offsets_past_4gib_get_zip64_records() {
+        let far = u32::MAX as u64 + 10;
+        let mut writer = writer_at(far + 100);
+        let descriptor = writer.finish_entry(current(far, false)).unwrap();
+        // sizes fit, the descriptor stays small
+        assert_eq!(descriptor.len(), 16);
+
+        // the zip64 extra carries sizes and offset together, all of them go there
+        let central = writer.central.clone();
+        assert_eq!(u32_at(&central, 20), u32::MAX);
+        assert_eq!(u32_at(&central, 42), u32::MAX);
+        assert_eq!(u16_at(&central, 30), 28);
+        let extra = 46 + "big".len();
+        assert_eq!(u16_at(&central, extra), 0x0001);
+        assert_eq!(u64_at(&central, extra + 4), 3);
+        assert_eq!(u64_at(&central, extra + 12), 3);
+        assert_eq!(u64_at(&central, extra + 20), far);
+
+        let end = writer.end_of_archive();
+        let directory_size = central.len() as u64;
+        let record = &end[central.len()..];
+        assert_eq!(u32_at(record, 0), ZIP64_END);
+        assert_eq!(u64_at(record, 24), 1);
+        assert_eq!(u64_at(record, 40), directory_size);
+        assert_eq!(u64_at(record, 48), far + 100);
+
+        let locator = &record[56..];
+        assert_eq!(u32_at(locator, 0), ZIP64_LOCATOR);
+        assert_eq!(u64_at(locator, 8), far + 100 + directory_size);
+
+        let classic = &locator[20..];
+        assert_eq!(classic.len(), 22);
+        assert_eq!(u32_at(classic, 0), END);
+        assert_eq!(u32_at(classic, 16), u32::MAX);
+    }
+
+    #[test]
+    fn big_entries_get_zip64_descriptors() {
+        let mut writer = writer_at(200);
+        let descriptor = writer.finish_entry(current(0, true)).unwrap();
+        assert_eq!(descriptor.len(), 24);
+        assert_eq!(u64_at(&descriptor, 8), 3);
+        assert_eq!(u64_at(&descriptor, 16), 3);
+        assert_eq!(u32_at(&writer.central, 20), u32::MAX);
+        assert_eq!(u32_at(&writer.central, 24), u32::MAX);
+    }
+
+    #[test]
+    fn dos_dates() {
+        let time = httpdate::parse_http_date("Tue, 29 Feb 2000 13:45:31 GMT").unwrap();
+        let (time, date) = dos_date_time(time);
+        assert_eq!(time, (13 << 11) | (45 << 5) | 15);
+        assert_eq!(date, (20 << 9) | (2 << 5) | 29);
+        assert_eq!(dos_date_time(UNIX_EPOCH), (0, (1 << 5) | 1));
+    }
+}
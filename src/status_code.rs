@@ -0,0 +1,286 @@
+use std::fmt;
+
+use thiserror::Error;
+
+/// HTTP status code. Every IANA registered code has a constant, anything else
+/// in the 100..=999 range can still be built with `from_u16`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct StatusCode(u16);
+
+#[derive(Error, Debug)]
+pub enum StatusCodeError {
+    #[error("status code {0} is outside of 100..=999")]
+    OutOfRange(u16),
+}
+
+impl StatusCode {
+    // 1xx informational
+    pub const CONTINUE: StatusCode = StatusCode(100);
+    pub const SWITCHING_PROTOCOLS: StatusCode = StatusCode(101);
+    pub const PROCESSING: StatusCode = StatusCode(102);
+    pub const EARLY_HINTS: StatusCode = StatusCode(103);
+
+    // 2xx success
+    pub const OK: StatusCode = StatusCode(200);
+    pub const CREATED: StatusCode = StatusCode(201);
+    pub const ACCEPTED: StatusCode = StatusCode(202);
+    pub const NON_AUTHORITATIVE_INFORMATION: StatusCode = StatusCode(203);
+    pub const NO_CONTENT: StatusCode = StatusCode(204);
+    pub const RESET_CONTENT: StatusCode = StatusCode(205);
+    pub const PARTIAL_CONTENT: StatusCode = StatusCode(206);
+    pub const MULTI_STATUS: StatusCode = StatusCode(207);
+    pub const ALREADY_REPORTED: StatusCode = StatusCode(208);
+    pub const IM_USED: StatusCode = StatusCode(226);
+
+    // 3xx redirection
+    pub const MULTIPLE_CHOICES: StatusCode = StatusCode(300);
+    pub const MOVED_PERMANENTLY: StatusCode = StatusCode(301);
+    pub const FOUND: StatusCode = StatusCode(302);
+    pub const SEE_OTHER: StatusCode = StatusCode(303);
+    pub const NOT_MODIFIED: StatusCode = StatusCode(304);
+    pub const USE_PROXY: StatusCode = StatusCode(305);
+    pub const TEMPORARY_REDIRECT: StatusCode = StatusCode(307);
+    pub const PERMANENT_REDIRECT: StatusCode = StatusCode(308);
+
+    // 4xx client errors
+    pub const BAD_REQUEST: StatusCode = StatusCode(400);
+    pub const UNAUTHORIZED: StatusCode = StatusCode(401);
+    pub const PAYMENT_REQUIRED: StatusCode = StatusCode(402);
+    pub const FORBIDDEN: StatusCode = StatusCode(403);
+    pub const NOT_FOUND: StatusCode = StatusCode(404);
+    pub const METHOD_NOT_ALLOWED: StatusCode = StatusCode(405);
+    pub const NOT_ACCEPTABLE: StatusCode = StatusCode(406);
+    pub const PROXY_AUTHENTICATION_REQUIRED: StatusCode = StatusCode(407);
+    pub const REQUEST_TIMEOUT: StatusCode = StatusCode(408);
+    pub const CONFLICT: StatusCode = StatusCode(409);
+    pub const GONE: StatusCode = StatusCode(410);
+    pub const LENGTH_REQUIRED: StatusCode = StatusCode(411);
+    pub const PRECONDITION_FAILED: StatusCode = StatusCode(412);
+    pub const CONTENT_TOO_LARGE: StatusCode = StatusCode(413);
+    pub const URI_TOO_LONG: StatusCode = StatusCode(414);
+    pub const UNSUPPORTED_MEDIA_TYPE: StatusCode = StatusCode(415);
+    pub const RANGE_NOT_SATISFIABLE: StatusCode = StatusCode(416);
+    pub const EXPECTATION_FAILED: StatusCode = StatusCode(417);
+    pub const MISDIRECTED_REQUEST: StatusCode = StatusCode(421);
+    pub const UNPROCESSABLE_CONTENT: StatusCode = StatusCode(422);
+    pub const LOCKED: StatusCode = StatusCode(423);
+    pub const FAILED_DEPENDENCY: StatusCode = StatusCode(424);
+    pub const TOO_EARLY: StatusCode = StatusCode(425);
+    pub const UPGRADE_REQUIRED: StatusCode = StatusCode(426);
+    pub const PRECONDITION_REQUIRED: StatusCode = StatusCode(428);
+    pub const TOO_MANY_REQUESTS: StatusCode = StatusCode(429);
+    pub const REQUEST_HEADER_FIELDS_TOO_LARGE: StatusCode = StatusCode(431);
+    pub const UNAVAILABLE_FOR_LEGAL_REASONS: StatusCode = StatusCode(451);
+
+    // 5xx server errors
+    pub const INTERNAL_SERVER_ERROR: StatusCode = StatusCode(500);
+    pub const NOT_IMPLEMENTED: StatusCode = StatusCode(501);
+    pub const BAD_GATEWAY: StatusCode = StatusCode(502);
+    pub const SERVICE_UNAVAILABLE: StatusCode = StatusCode(503);
+    pub const GATEWAY_TIMEOUT: StatusCode = StatusCode(504);
+    pub const HTTP_VERSION_NOT_SUPPORTED: StatusCode = StatusCode(505);
+    pub const VARIANT_ALSO_NEGOTIATES: StatusCode = StatusCode(506);
+    pub const INSUFFICIENT_STORAGE: StatusCode = StatusCode(507);
+    pub const LOOP_DETECTED: StatusCode = StatusCode(508);
+    pub const NOT_EXTENDED: StatusCode = StatusCode(510);
+    pub const NETWORK_AUTHENTICATION_REQUIRED: StatusCode = StatusCode(511);
+
+    // older names kept around for existing code
+    pub const ALL_OK: StatusCode = StatusCode::OK;
+    pub const PAYLOAD_TOO_LARGE: StatusCode = StatusCode::CONTENT_TOO_LARGE;
+    pub const UNPROCESSABLE_ENTITY: StatusCode = StatusCode::UNPROCESSABLE_CONTENT;
+
+    pub fn from_u16(code: u16) -> Result<Self, StatusCodeError> {
+        if (100..=999).contains(&code) {
+            Ok(StatusCode(code))
+        } else {
+            Err(StatusCodeError::OutOfRange(code))
+        }
+    }
+
+    pub const fn as_u16(&self) -> u16 {
+        self.0
+    }
+
+    /// reason phrase from the registry, `None` for codes that aren't registered
+    pub fn canonical_reason(&self) -> Option<&'static str> {
+        let reason = match self.0 {
+            100 => "Continue",
+            101 => "Switching Protocols",
+            102 => "Processing",
+            103 => "Early Hints",
+            200 => "OK",
+            201 => "Created",
+            202 => "Accepted",
+            203 => "Non-Authoritative Information",
+            204 => "No Content",
+            205 => "Reset Content",
+            206 => "Partial Content",
+            207 => "Multi-Status",
+            208 => "Already Reported",
+            226 => "IM Used",
+            300 => "Multiple Choices",
+            301 => "Moved Permanently",
+            302 => "Found",
+            303 => "See Other",
+            304 => "Not Modified",
+            305 => "Use Proxy",
+            307 => "Temporary Redirect",
+            308 => "Permanent Redirect",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            402 => "Payment Required",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            406 => "Not Acceptable",
+            407 => "Proxy Authentication Required",
+            408 => "Request Timeout",
+            409 => "Conflict",
+            410 => "Gone",
+            411 => "Length Required",
+            412 => "Precondition Failed",
+            413 => "Content Too Large",
+            414 => "URI Too Long",
+            415 => "Unsupported Media Type",
+            416 => "Range Not Satisfiable",
+            417 => "Expectation Failed",
+            421 => "Misdirected Request",
+            422 => "Unprocessable Content",
+            423 => "Locked",
+            424 => "Failed Dependency",
+            425 => "Too Early",
+            426 => "Upgrade Required",
+            428 => "Precondition Required",
+            429 => "Too Many Requests",
+            431 => "Request Header Fields Too Large",
+            451 => "Unavailable For Legal Reasons",
+            500 => "Internal Server Error",
+            501 => "Not Implemented",
+            502 => "Bad Gateway",
+            503 => "Service Unavailable",
+            504 => "Gateway Timeout",
+            505 => "HTTP Version Not Supported",
+            506 => "Variant Also Negotiates",
+            507 => "Insufficient Storage",
+            508 => "Loop Detected",
+            510 => "Not Extended",
+            511 => "Network Authentication Required",
+            _ => return None,
+        };
+        Some(reason)
+    }
+
+    pub fn is_informational(&self) -> bool {
+        (100..200).contains(&self.0)
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.0)
+    }
+
+    pub fn is_redirection(&self) -> bool {
+        (300..400).contains(&self.0)
+    }
+
+    pub fn is_client_error(&self) -> bool {
+        (400..500).contains(&self.0)
+    }
+
+    pub fn is_server_error(&self) -> bool {
+        (500..600).contains(&self.0)
+    }
+}
+
+impl Default for StatusCode {
+    fn default() -> Self {
+        StatusCode::OK
+    }
+}
+
+impl TryFrom<u16> for StatusCode {
+    type Error = StatusCodeError;
+
+    fn try_from(code: u16) -> Result<Self, Self::Error> {
+        StatusCode::from_u16(code)
+    }
+}
+
+impl From<StatusCode> for u16 {
+    fn from(status: StatusCode) -> Self {
+        status.0
+    }
+}
+
+impl PartialEq<u16> for StatusCode {
+    fn eq(&self, other: &u16) -> bool {
+        self.0 == *other
+    }
+}
+
+// "404 Not Found", unregistered codes are printed as the bare number
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.canonical_reason() {
+            Some(reason) => write!(f, "{} {}", self.0, reason),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+impl fmt::Debug for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(code: u16) -> StatusCode {
+        StatusCode::from_u16(code).unwrap()
+    }
+
+    #[test]
+    fn codes_outside_of_three_digits_are_refused() {
+        for code in [0, 99, 1000, u16::MAX] {
+            assert!(matches!(
+                StatusCode::from_u16(code),
+                Err(StatusCodeError::OutOfRange(c)) if c == code
+            ));
+        }
+        assert_eq!(status(100), StatusCode::CONTINUE);
+        assert_eq!(status(999).as_u16(), 999);
+        assert!(StatusCode::try_from(42).is_err());
+    }
+
+    #[test]
+    fn registered_codes_have_a_reason() {
+        assert_eq!(StatusCode::NOT_FOUND.canonical_reason(), Some("Not Found"));
+        assert_eq!(
+            status(422).canonical_reason(),
+            Some("Unprocessable Content")
+        );
+        assert_eq!(status(599).canonical_reason(), None);
+        assert_eq!(status(299).canonical_reason(), None);
+        assert_eq!(StatusCode::NOT_FOUND.to_string(), "404 Not Found");
+        assert_eq!(status(599).to_string(), "599");
+    }
+
+    #[test]
+    fn classes_switch_at_the_hundreds() {
+        assert!(status(199).is_informational() && !status(199).is_success());
+        assert!(status(200).is_success() && !status(200).is_informational());
+        assert!(status(299).is_success() && !status(300).is_success());
+        assert!(status(399).is_redirection() && !status(399).is_client_error());
+        assert!(status(400).is_client_error() && !status(400).is_redirection());
+        assert!(status(499).is_client_error() && !status(499).is_server_error());
+        assert!(status(500).is_server_error() && !status(500).is_client_error());
+        assert!(status(599).is_server_error());
+        // codes past the defined classes belong to none of them
+        let odd = status(600);
+        assert!(!odd.is_informational() && !odd.is_success() && !odd.is_redirection());
+        assert!(!odd.is_client_error() && !odd.is_server_error());
+    }
+}